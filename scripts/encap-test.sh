@@ -0,0 +1,75 @@
+#!/bin/bash
+# a node in vxlan or geneve mode and a linux interface of the same kind and vni,
+# reaching each other over tap0 and vx0. needs root, iproute2 and ping.
+#
+#   lx 10.0.0.1, vx0 192.168.100.1 -- td 10.0.0.2, tap0 192.168.100.2
+#
+# usage: scripts/encap-test.sh [--geneve] [path to tap-demo]
+set -e
+
+KIND=vxlan
+PORT=4789
+if [ "$1" = "--geneve" ]; then
+    KIND=geneve
+    PORT=6081
+    shift
+fi
+
+BIN=$(realpath "${1:-target/debug/tap-demo}")
+DIR=$(mktemp -d)
+NS="lx td"
+
+cleanup() {
+    for pid in $(jobs -p); do kill "$pid" 2>/dev/null || true; done
+    for ns in $NS; do ip netns del "$ns" 2>/dev/null || true; done
+    rm -rf "$DIR"
+}
+trap cleanup EXIT
+
+for ns in $NS; do
+    ip netns add "$ns"
+    ip -n "$ns" link set lo up
+done
+
+ip link add lx-eth netns lx type veth peer name td-eth netns td
+ip -n lx addr add 10.0.0.1/24 dev lx-eth
+ip -n td addr add 10.0.0.2/24 dev td-eth
+ip -n lx link set lx-eth up
+ip -n td link set td-eth up
+
+if [ "$KIND" = "geneve" ]; then
+    ip -n lx link add vx0 type geneve id 42 remote 10.0.0.2 dstport $PORT
+else
+    ip -n lx link add vx0 type vxlan id 42 remote 10.0.0.2 dstport $PORT dev lx-eth
+fi
+ip -n lx addr add 192.168.100.1/24 dev vx0
+ip -n lx link set vx0 up
+
+cat > "$DIR/td.toml" <<EOC
+data_port = $PORT
+encapsulation = "$KIND"
+vni = 42
+remotes = ["10.0.0.1"]
+EOC
+
+HOSTNAME=td ip netns exec td "$BIN" start \
+    --config "$DIR/td.toml" \
+    --identity "$DIR/td.identity" \
+    --state-file "$DIR/td.peers" \
+    --admin-socket "$DIR/td.sock" > "$DIR/td.log" 2>&1 &
+
+sleep 2
+ip -n td addr add 192.168.100.2/24 dev tap0
+
+for _ in $(seq 10); do
+    if ip netns exec td ping -c 1 -W 1 192.168.100.1 > /dev/null &&
+        ip netns exec lx ping -c 1 -W 1 192.168.100.2 > /dev/null; then
+        "$BIN" fdb list --admin-socket "$DIR/td.sock"
+        echo "ok"
+        exit 0
+    fi
+done
+
+cat "$DIR/td.log"
+echo "failed"
+exit 1
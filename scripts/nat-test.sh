@@ -0,0 +1,94 @@
+#!/bin/bash
+# two nodes behind their own masquerading router, only knowing rendezvous on the
+# "internet" between routers. needs root, iproute2, iptables and ping.
+#
+#   h1 10.0.1.2 -- r1 (masquerade) --+-- r2 (masquerade) -- h2 10.0.2.2
+#                  198.51.100.2      |   198.51.100.3
+#                                    rv 198.51.100.1
+#
+# usage: scripts/nat-test.sh [--relay] [path to tap-demo]
+# --relay drops traffic between r1 and r2, so punching fails and frames go through
+# relay of rendezvous
+set -e
+
+RELAY=0
+if [ "$1" = "--relay" ]; then
+    RELAY=1
+    shift
+fi
+
+BIN=$(realpath "${1:-target/debug/tap-demo}")
+DIR=$(mktemp -d)
+NS="rv r1 r2 h1 h2"
+
+cleanup() {
+    for pid in $(jobs -p); do kill "$pid" 2>/dev/null || true; done
+    for ns in $NS; do ip netns del "$ns" 2>/dev/null || true; done
+    rm -rf "$DIR"
+}
+trap cleanup EXIT
+
+for ns in $NS; do
+    ip netns add "$ns"
+    ip -n "$ns" link set lo up
+done
+
+# link <ns1> <if1> <addr1> <ns2> <if2> <addr2>
+link() {
+    ip link add "$2" netns "$1" type veth peer name "$5" netns "$4"
+    ip -n "$1" addr add "$3" dev "$2"
+    ip -n "$4" addr add "$6" dev "$5"
+    ip -n "$1" link set "$2" up
+    ip -n "$4" link set "$5" up
+}
+
+# "internet", a bridge in rv
+ip -n rv link add wan type bridge
+ip -n rv addr add 198.51.100.1/24 dev wan
+ip -n rv link set wan up
+
+for i in 1 2; do
+    ip link add rv-r$i netns rv type veth peer name r$i-wan netns r$i
+    ip -n rv link set rv-r$i master wan up
+    ip -n r$i addr add 198.51.100.$((i + 1))/24 dev r$i-wan
+    ip -n r$i link set r$i-wan up
+
+    link r$i r$i-lan 10.0.$i.1/24 h$i h$i-lan 10.0.$i.2/24
+    ip -n h$i route add default via 10.0.$i.1
+
+    ip netns exec r$i sysctl -qw net.ipv4.ip_forward=1
+    ip netns exec r$i iptables -t nat -A POSTROUTING -o r$i-wan -j MASQUERADE
+done
+
+if [ $RELAY = 1 ]; then
+    ip netns exec r1 iptables -A FORWARD -d 198.51.100.3 -j DROP
+    ip netns exec r2 iptables -A FORWARD -d 198.51.100.2 -j DROP
+fi
+
+ip netns exec rv "$BIN" rendezvous > "$DIR/rv.log" 2>&1 &
+
+for i in 1 2; do
+    HOSTNAME=h$i ip netns exec h$i "$BIN" start \
+        --rendezvous 198.51.100.1:9910 \
+        --identity "$DIR/h$i.identity" \
+        --state-file "$DIR/h$i.peers" \
+        --admin-socket "$DIR/h$i.sock" > "$DIR/h$i.log" 2>&1 &
+done
+
+sleep 3
+ip -n h1 addr add 192.168.100.1/24 dev tap0
+ip -n h2 addr add 192.168.100.2/24 dev tap0
+
+# handshake, or relay after punching timed out
+for _ in $(seq 30); do
+    if ip netns exec h1 ping -c 1 -W 1 192.168.100.2 > /dev/null; then
+        "$BIN" peers list --admin-socket "$DIR/h1.sock"
+        grep -h "punched\|relay to" "$DIR"/h*.log || true
+        echo "ok"
+        exit 0
+    fi
+done
+
+cat "$DIR"/*.log
+echo "failed"
+exit 1
@@ -0,0 +1,252 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use bincode::{deserialize, serialize};
+use log::{debug, error};
+
+use crate::app::AppState;
+use crate::discovery::{connect_peers, init_peer_hw_addr, scan_node};
+use crate::error::{AppResult, TapDemoError};
+use crate::msg::AdminMsg;
+
+pub(crate) const DEFAULT_ADMIN_SOCKET: &str = "/run/tap-demo.sock";
+
+/// max length of one admin frame
+const MAX_FRAME_LEN: usize = 1024 * 1024;
+
+/// write `msg` prefixed by its length, u32 big endian
+pub(crate) fn write_frame(stream: &mut UnixStream, msg: &AdminMsg) -> AppResult<()> {
+    let data = serialize(msg)?;
+
+    stream.write_all(&(data.len() as u32).to_be_bytes())?;
+    stream.write_all(&data)?;
+
+    Ok(())
+}
+
+pub(crate) fn read_frame(stream: &mut UnixStream) -> AppResult<AdminMsg> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(TapDemoError::AdminFrameTooLarge(len));
+    }
+
+    let mut data = vec![0; len];
+    stream.read_exact(&mut data)?;
+
+    Ok(deserialize(&data)?)
+}
+
+/// send one request to daemon listening on `path`, for `network` if given, used by cli
+pub(crate) fn admin_request(
+    path: &Path,
+    network: Option<&str>,
+    msg: AdminMsg,
+) -> AppResult<AdminMsg> {
+    let mut stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(Duration::from_secs(60)))?;
+
+    let msg = match network {
+        Some(network) => AdminMsg::Network(network.to_owned(), Box::new(msg)),
+        None => msg,
+    };
+
+    write_frame(&mut stream, &msg)?;
+
+    match read_frame(&mut stream)? {
+        AdminMsg::NoSuchNetwork(network) => Err(TapDemoError::NoSuchNetwork(network)),
+        reply => Ok(reply),
+    }
+}
+
+/// handle request on network it names, or on the first one
+fn route_request(states: &[Arc<AppState>], msg: AdminMsg) -> Option<AdminMsg> {
+    match msg {
+        AdminMsg::Network(network, msg) => {
+            match states.iter().find(|it| it.config().network == network) {
+                Some(state) => handle_request(state, *msg),
+                None => Some(AdminMsg::NoSuchNetwork(network)),
+            }
+        }
+        msg => handle_request(&states[0], msg),
+    }
+}
+
+fn handle_request(state: &Arc<AppState>, msg: AdminMsg) -> Option<AdminMsg> {
+    let reply = match msg {
+        AdminMsg::AddPeerRequest(mut peer) => {
+            let result = init_peer_hw_addr(state, &mut peer);
+
+            match result {
+                Ok(_) => AdminMsg::AddPeerReply(state.add_peer(peer)),
+                Err(e) => {
+                    error!("add peer {} failed, {}", peer.name, e);
+                    AdminMsg::AddPeerReply(false)
+                }
+            }
+        }
+        AdminMsg::ListPeerRequest => {
+            let peers = { state.peers.read().unwrap().clone() };
+
+            AdminMsg::ListPeerReply(peers)
+        }
+        AdminMsg::RemovePeerRequest { name, addr } => {
+            state.remove_peer(name, addr);
+
+            AdminMsg::RemovePeerReply(true)
+        }
+        AdminMsg::ScanNodeRequest => {
+            let peers = {
+                let state = Arc::clone(state);
+                scan_node(state)
+            };
+
+            match peers {
+                Ok(peers) => {
+                    let scanned: Vec<SocketAddr> = peers.iter().map(|it| it.ctl_addr).collect();
+
+                    state.add_peers(connect_peers(state, peers));
+
+                    // reply peers joined, including those known before
+                    let peers = state
+                        .peers
+                        .read()
+                        .unwrap()
+                        .iter()
+                        .filter(|it| scanned.contains(&it.ctl_addr))
+                        .cloned()
+                        .collect();
+
+                    AdminMsg::ScanNodeReply(peers)
+                }
+                Err(_) => AdminMsg::ScanNodeReply(Vec::new()),
+            }
+        }
+        AdminMsg::ListFdbRequest => {
+            let mut records = { state.fdb.read().unwrap().records() };
+
+            {
+                let peers = state.peers.read().unwrap();
+
+                for record in &mut records {
+                    record.peer = peers
+                        .iter()
+                        .find(|it| it.data_addr == record.data_addr)
+                        .map(|it| it.name.clone());
+                }
+            }
+
+            AdminMsg::ListFdbReply(records)
+        }
+        AdminMsg::StatsRequest => AdminMsg::StatsReply(state.stats.snapshot()),
+        AdminMsg::ListRoutesRequest => {
+            let peers = { state.peers.read().unwrap().clone() };
+
+            AdminMsg::ListRoutesReply(state.routing.records(&peers))
+        }
+        _ => return None,
+    };
+
+    Some(reply)
+}
+
+fn handle_client(states: &[Arc<AppState>], mut stream: UnixStream) -> AppResult<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+
+    loop {
+        let msg = match read_frame(&mut stream) {
+            Ok(msg) => msg,
+            // client closed
+            Err(TapDemoError::IOError(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Ok(())
+            }
+            Err(e) => return Err(e),
+        };
+
+        match route_request(states, msg) {
+            Some(reply) => write_frame(&mut stream, &reply)?,
+            None => return Err(TapDemoError::UnexpectedMsg),
+        }
+    }
+}
+
+fn bind(path: &Path) -> AppResult<UnixListener> {
+    // remove socket left by previous run, unless another daemon still listens on it
+    if UnixStream::connect(path).is_ok() {
+        return Err(TapDemoError::IOError(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            "admin socket in use",
+        )));
+    }
+
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+
+    // only owner and group may manage this node
+    fs::set_permissions(path, fs::Permissions::from_mode(0o660))?;
+
+    Ok(listener)
+}
+
+/// serve admin requests from cli on unix socket `admin_socket` of config, for all
+/// networks of daemon
+pub(crate) fn admin_thread(states: Vec<Arc<AppState>>) -> AppResult<JoinHandle<()>> {
+    debug!("admin_thread start");
+
+    let listener = bind(&states[0].config().admin_socket)?;
+
+    Ok(std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = handle_client(&states, stream) {
+                        debug!("admin client error, {}", e);
+                    }
+                }
+                Err(e) => error!("error accept admin client {:?}", e),
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{read_frame, write_frame, MAX_FRAME_LEN};
+    use crate::msg::AdminMsg;
+    use std::io::Write;
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn test_frame() {
+        let (mut a, mut b) = UnixStream::pair().unwrap();
+
+        write_frame(&mut a, &AdminMsg::ListPeerRequest).unwrap();
+        write_frame(&mut a, &AdminMsg::AddPeerReply(true)).unwrap();
+
+        assert!(matches!(read_frame(&mut b), Ok(AdminMsg::ListPeerRequest)));
+        assert!(matches!(
+            read_frame(&mut b),
+            Ok(AdminMsg::AddPeerReply(true))
+        ));
+
+        a.write_all(&(MAX_FRAME_LEN as u32 + 1).to_be_bytes())
+            .unwrap();
+        assert!(read_frame(&mut b).is_err());
+    }
+}
@@ -1,118 +1,317 @@
-use std::env;
 use std::fs::File;
-use std::io::Read;
-use std::net::{IpAddr, UdpSocket};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
 use std::sync::{Arc, RwLock};
+use std::thread::JoinHandle;
 use std::time::Duration;
 
 use clap::ArgMatches;
-use log::error;
-
-use crate::control::control_thread;
-use crate::discovery::{discovery_thread, heartbeats_thread, init_peers_hw_addr};
-use crate::dispatch::{dispatch_from_peers, DispatchRoutine};
+use log::{info, warn};
+
+use crate::admin::admin_thread;
+use crate::config::{Config, Encapsulation};
+use crate::control::{control_thread, ControlSocket};
+use crate::crypto::DataCipher;
+use crate::detector::heartbeats_thread;
+use crate::discovery::{bind_listener, discovery_thread, init_peers_hw_addr, listener_thread};
+use crate::dispatch::{
+    dispatch_from_peers, dispatch_from_tap, fdb_ageing_thread, DEFAULT_MTU, TUNNEL_OVERHEAD,
+};
 use crate::error::AppResult;
-use crate::eth::EthV2;
-use crate::peer::Peer;
-use crate::tap::{create_tap as inner_create_tap, TapInfo};
+use crate::fdb::{Fdb, FDB_AGEING_TIME};
+use crate::geneve::GENEVE_PORT;
+use crate::handshake::{derive_psk, HandshakeConfig};
+use crate::identity::{fingerprint, load_authorized_keys, Identity};
+use crate::ipam::Ipam;
+use crate::membership::{sync, Membership};
+use crate::peer::{Peer, PeerState};
+use crate::reload::{block_signals, signal_thread};
+use crate::rendezvous::Rendezvous;
+use crate::routing::Routing;
+use crate::stats::Stats;
+use crate::store::PeerStore;
+use crate::storm::StormGuard;
+use crate::tap::{create_tap as inner_create_tap, set_mtu, TapInfo};
+use crate::underlay::bind_socket;
+use crate::vxlan::VXLAN_PORT;
 
 pub(crate) struct AppState {
-    pub(crate) name: String,
+    config: RwLock<Arc<Config>>,
     pub(crate) hw_addr: [u8; 6],
     pub(crate) data_sock: UdpSocket,
+    pub(crate) ctl: ControlSocket,
     pub(crate) tap_dev: File,
     pub(crate) peers: RwLock<Vec<Peer>>,
+    pub(crate) fdb: RwLock<Fdb>,
+    pub(crate) cipher: DataCipher,
+    pub(crate) handshake: HandshakeConfig,
+    pub(crate) ipam: Option<Ipam>,
+    pub(crate) stats: Stats,
+    pub(crate) store: PeerStore,
+    pub(crate) membership: Membership,
+    pub(crate) rendezvous: Rendezvous,
+    pub(crate) routing: Routing,
+    pub(crate) storm: StormGuard,
 }
 
 impl AppState {
-    pub(crate) fn add_peer(&self, peer: Peer) {
-        let mut peers = self.peers.write().unwrap();
+    pub(crate) fn new(
+        config: Config,
+        tap_info: TapInfo,
+        data_sock: UdpSocket,
+        ctl: ControlSocket,
+        handshake: HandshakeConfig,
+        ipam: Option<Ipam>,
+        store: PeerStore,
+    ) -> AppState {
+        let cipher = DataCipher::new();
+
+        AppState {
+            config: RwLock::new(Arc::new(config)),
+            hw_addr: tap_info.hw_addr,
+            data_sock,
+            ctl,
+            tap_dev: tap_info.tap_dev,
+            peers: RwLock::new(Vec::new()),
+            fdb: RwLock::new(Fdb::new(FDB_AGEING_TIME)),
+            cipher,
+            handshake,
+            ipam,
+            stats: Stats::default(),
+            store,
+            membership: Membership::new(),
+            rendezvous: Rendezvous::new(),
+            routing: Routing::new(),
+            storm: StormGuard::new(),
+        }
+    }
+
+    /// current config, replaced as a whole on reload
+    pub(crate) fn config(&self) -> Arc<Config> {
+        self.config.read().unwrap().clone()
+    }
+
+    pub(crate) fn set_config(&self, config: Config) {
+        *self.config.write().unwrap() = Arc::new(config);
+    }
+
+    /// add or update peer, peer must have finished handshake, then exchange membership
+    /// with it
+    pub(crate) fn add_peer(&self, peer: Peer) -> bool {
+        self.add_peers(vec![peer])
+    }
+
+    pub(crate) fn add_peers(&self, new_peers: Vec<Peer>) -> bool {
+        let mut added = Vec::new();
+
+        {
+            let mut peers = self.peers.write().unwrap();
+
+            for peer in new_peers {
+                let ctl_addr = peer.ctl_addr;
+                let public_key = peer.public_key;
+
+                if self.insert_peer(&mut peers, peer) {
+                    added.push((ctl_addr, public_key));
+                }
+            }
+
+            if !added.is_empty() {
+                self.store.update(&peers, &[]);
+            }
+        }
+
+        for (ctl_addr, public_key) in &added {
+            self.membership.unignore(public_key);
+            sync(self, *ctl_addr);
+        }
+
+        !added.is_empty()
+    }
+
+    fn insert_peer(&self, peers: &mut Vec<Peer>, peer: Peer) -> bool {
+        if peer.public_key == [0; 32] || !self.cipher.has_session(&peer.data_addr) {
+            warn!("refuse to add {}, no handshake", peer.name);
+            return false;
+        }
+
         let p = peers.iter_mut().find(|it| it.ctl_addr.eq(&peer.ctl_addr));
 
         match p {
-            Some(mut p) => {
+            Some(p) => {
                 // update all except addr
                 p.name = peer.name;
-                p.hw_addr = peer.hw_addr
+                p.hw_addr = peer.hw_addr;
+                p.public_key = peer.public_key;
+                p.ip_addr = peer.ip_addr;
+                p.state = PeerState::Alive;
+                p.via = peer.via;
             }
             None => peers.push(peer),
         }
-    }
 
-    pub(crate) fn add_peers(&self, peers: Vec<Peer>) {
-        for peer in peers {
-            self.add_peer(peer);
-        }
+        true
     }
 
+    /// remove peer by admin, it's not connected again by gossip until added again
     pub(crate) fn remove_peer(&self, name: Option<String>, addr: Option<IpAddr>) {
-        let mut peers = self.peers.write().unwrap();
-
-        peers.retain(move |it| {
-            let name_eq = if name.is_some() {
-                name.as_ref().unwrap().eq(&it.name)
-            } else {
-                false
+        let removed = self.remove_peers_where(|it| {
+            let name_eq = match name {
+                Some(ref name) => name.eq(&it.name),
+                None => false,
             };
 
-            let addr_eq = if addr.is_some() {
-                addr.as_ref().unwrap().eq(&it.ctl_addr.ip())
-            } else {
-                false
+            let addr_eq = match addr {
+                Some(ref addr) => addr.eq(&it.ctl_addr.ip()),
+                None => false,
             };
 
-            !(name_eq | addr_eq)
+            name_eq | addr_eq
         });
+
+        self.membership.ignore(&removed);
+    }
+
+    /// remove peers matching `f`, with their sessions and learned macs, return those removed
+    pub(crate) fn remove_peers_where<F: Fn(&Peer) -> bool>(&self, f: F) -> Vec<Peer> {
+        let mut peers = self.peers.write().unwrap();
+        let mut fdb = self.fdb.write().unwrap();
+        let mut removed = Vec::new();
+
+        peers.retain(|it| {
+            if f(it) {
+                fdb.forget(&it.data_addr);
+                self.cipher.remove(&it.data_addr);
+                self.routing.link_down(&it.hw_addr);
+                removed.push(it.clone());
+            }
+
+            !f(it)
+        });
+
+        if !removed.is_empty() {
+            let addrs: Vec<SocketAddr> = removed.iter().map(|it| it.ctl_addr).collect();
+            self.store.update(&peers, &addrs);
+        }
+
+        removed
     }
 }
 
-fn create_tap() -> AppResult<TapInfo> {
-    // create tap
-    inner_create_tap("tap0")
+fn create_tap(config: &Config) -> AppResult<TapInfo> {
+    let tap_info = inner_create_tap(&config.tap_name)?;
+    set_mtu(&config.tap_name, config.mtu)?;
+
+    if config.mtu > DEFAULT_MTU {
+        info!(
+            "mtu {} needs underlay mtu of at least {}",
+            config.mtu,
+            config.mtu + TUNNEL_OVERHEAD
+        );
+    }
+
+    Ok(tap_info)
 }
 
-fn create_data_sock() -> AppResult<UdpSocket> {
-    let data_sock = UdpSocket::bind("0.0.0.0:9908")?;
+fn create_data_sock(config: &Config) -> AppResult<UdpSocket> {
+    let data_sock = bind_socket(config.data_port)?.into_udp_socket();
     data_sock.set_write_timeout(Some(Duration::from_secs(5)))?;
 
     Ok(data_sock)
 }
 
-fn parse_peers_str(peers_str: &str) -> AppResult<Vec<Peer>> {
-    let peers_str: Vec<&str> = peers_str.split(",").collect();
+/// pre-shared key from `key` or `key_file`
+fn load_psk(config: &Config) -> AppResult<Option<[u8; 32]>> {
+    if let Some(ref key) = config.key {
+        return Ok(Some(derive_psk(key.as_bytes())));
+    }
 
-    let peers: Vec<Peer> = peers_str
-        .into_iter()
-        .map(|it| {
-            let peer = it.parse();
+    if let Some(ref key_file) = config.key_file {
+        let key = std::fs::read(key_file)?;
+        let key = String::from_utf8_lossy(&key);
 
-            peer.unwrap()
-        })
-        .collect();
+        return Ok(Some(derive_psk(key.trim().as_bytes())));
+    }
 
-    Ok(peers)
+    Ok(None)
 }
 
-pub(crate) fn run(args: &ArgMatches) -> AppResult<()> {
-    let tap_info = create_tap()?;
-    let data_sock = create_data_sock()?;
-    let is_auto = args.is_present("auto");
+fn load_handshake_config(config: &Config) -> AppResult<HandshakeConfig> {
+    let identity = Identity::load_or_generate(&config.identity)?;
 
-    // init peers from args
-    let init_peers = match args.value_of("peers") {
-        Some(peers_str) => parse_peers_str(peers_str)?,
-        None => Vec::new(),
+    let authorized_keys = match config.authorized_keys {
+        Some(ref path) => Some(load_authorized_keys(path)?),
+        None => None,
     };
 
-    let state = Arc::new(AppState {
-        name: env::var("HOSTNAME")
-            .or_else(|_| env::var("HOST"))
-            .unwrap_or("peer-01".to_owned()),
-        data_sock,
-        tap_dev: tap_info.tap_dev,
-        hw_addr: tap_info.hw_addr,
-        peers: RwLock::new(init_peers),
-    });
+    let psk = load_psk(config)?;
+
+    if psk.is_none() && authorized_keys.is_none() {
+        warn!("no key or authorized keys given, overlay is open, any node can join");
+    }
+
+    info!("identity {}", fingerprint(&identity.public_key));
+
+    Ok(HandshakeConfig {
+        identity,
+        psk,
+        authorized_keys,
+    })
+}
+
+/// create tap and sockets of network, and start its threads except dispatch from tap
+fn start_network(config: Config) -> AppResult<Arc<AppState>> {
+    info!(
+        "network {}, id {}, on {}",
+        config.network, config.network_id, config.tap_name
+    );
+
+    let iana_port = match config.encapsulation {
+        Encapsulation::Native => None,
+        Encapsulation::Vxlan => Some(("vxlan", VXLAN_PORT)),
+        Encapsulation::Geneve => Some(("geneve", GENEVE_PORT)),
+    };
+
+    if let Some((encapsulation, port)) = iana_port {
+        warn!(
+            "{} frames are neither encrypted nor authenticated, use on trusted underlay only",
+            encapsulation
+        );
+
+        if config.data_port != port {
+            info!(
+                "{} on data port {}, not iana port {}, give it as dstport of linux {}",
+                encapsulation, config.data_port, port, encapsulation
+            );
+        }
+    }
+
+    let handshake = load_handshake_config(&config)?;
+    let tap_info = create_tap(&config)?;
+    let data_sock = create_data_sock(&config)?;
+    let ctl = ControlSocket::bind(&config)?;
+    let listener = bind_listener(&config)?;
+
+    let ipam = config
+        .subnet
+        .map(|subnet| Ipam::new(subnet, &config.tap_name));
+
+    // init peers from config and state file, added once handshake done
+    let store = PeerStore::load(&config.state_file, Duration::from_secs(config.peer_expire))?;
+    let mut init_peers = config.static_peers()?;
+    for peer in store.peers() {
+        if !init_peers.iter().any(|it| it.ctl_addr == peer.ctl_addr) {
+            init_peers.push(peer);
+        }
+    }
+    let is_auto = config.auto;
+
+    let state = Arc::new(AppState::new(
+        config, tap_info, data_sock, ctl, handshake, ipam, store,
+    ));
+
+    if let Some(ref ipam) = state.ipam {
+        ipam.assign(&state.hw_addr, &[])?;
+    }
 
     // heartbeats thread
     {
@@ -132,14 +331,22 @@ pub(crate) fn run(args: &ArgMatches) -> AppResult<()> {
         control_thread(state);
     }
 
+    // discovery listener, when not on control port
+    if let Some(listener) = listener {
+        let state = state.clone();
+        listener_thread(state, listener);
+    }
+
     // init peers hw addr
-    {
+    if !init_peers.is_empty() {
         let state = state.clone();
-        let is_empty = { state.peers.read().unwrap().is_empty() };
+        std::thread::spawn(move || init_peers_hw_addr(state, init_peers));
+    }
 
-        if !is_empty {
-            init_peers_hw_addr(state);
-        }
+    // fdb ageing thread
+    {
+        let state = state.clone();
+        fdb_ageing_thread(state);
     }
 
     // dispatch from peers
@@ -148,39 +355,42 @@ pub(crate) fn run(args: &ArgMatches) -> AppResult<()> {
         std::thread::spawn(move || dispatch_from_peers(state));
     }
 
-    let mut buff = vec![0; 1500];
-    let dispatch_routine = DispatchRoutine(state.clone());
-
-    loop {
-        let mut tap_dev = &state.tap_dev;
-
-        let size = tap_dev.read(&mut buff);
+    Ok(state)
+}
 
-        if size.is_err() {
-            continue;
-        }
+pub(crate) fn run(args: &ArgMatches<'static>) -> AppResult<()> {
+    let config = Config::load(args)?;
 
-        let mut dst_mac = [0; 6];
-        dst_mac.copy_from_slice(&buff[0..6]);
+    // before any thread is spawned, so signals only go to signal thread
+    block_signals()?;
 
-        let mut src_mac = [0; 6];
-        src_mac.copy_from_slice(&buff[6..12]);
+    let states = config
+        .networks()
+        .into_iter()
+        .map(start_network)
+        .collect::<AppResult<Vec<Arc<AppState>>>>()?;
 
-        let mut proto_type = [0; 2];
-        proto_type.copy_from_slice(&buff[12..14]);
+    // signal thread
+    {
+        let states = states.clone();
+        signal_thread(states, args.clone());
+    }
 
-        let eth = EthV2 {
-            dst_mac,
-            src_mac,
-            proto_type: u16::from_be_bytes(proto_type),
-            data: &buff,
-        };
+    // admin thread
+    {
+        let states = states.clone();
+        admin_thread(states)?;
+    }
 
-        let result = dispatch_routine.dispatch_to_peers(eth);
+    // dispatch from tap of each network
+    let taps: Vec<JoinHandle<()>> = states
+        .into_iter()
+        .map(|state| std::thread::spawn(move || dispatch_from_tap(state)))
+        .collect();
 
-        match result {
-            Err(e) => error!("error dispatch to peers, {:?}", e),
-            _ => {}
-        }
+    for tap in taps {
+        let _ = tap.join();
     }
+
+    Ok(())
 }
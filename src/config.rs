@@ -0,0 +1,647 @@
+use std::env;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+
+use clap::ArgMatches;
+use serde::Deserialize;
+
+use crate::admin::DEFAULT_ADMIN_SOCKET;
+use crate::discovery::discovery_port;
+use crate::dispatch::{DEFAULT_MTU, MAX_MTU, MIN_MTU};
+use crate::error::{AppResult, TapDemoError};
+use crate::identity::DEFAULT_IDENTITY_PATH;
+use crate::ipam::Subnet;
+use crate::peer::Peer;
+use crate::store::{DEFAULT_PEER_EXPIRE, DEFAULT_STATE_FILE};
+use crate::vxlan::MAX_VNI;
+
+/// longest interface name, without trailing nul
+const MAX_IF_NAME_LEN: usize = 15;
+
+/// cluster id is sent in every discovery reply
+const MAX_CLUSTER_ID_LEN: usize = 64;
+
+/// network name is part of state file name
+const MAX_NETWORK_NAME_LEN: usize = 32;
+
+/// node name is sent in gossip and to rendezvous, several in one message
+pub(crate) const MAX_NAME_LEN: usize = 64;
+
+/// name of the only network when no `[[networks]]` given
+pub(crate) const DEFAULT_NETWORK: &str = "default";
+
+/// static peer in config file
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct PeerConfig {
+    pub(crate) name: String,
+    /// control address, eg, `10.0.0.2:9909`
+    pub(crate) addr: String,
+}
+
+/// how frames are carried to peers
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Encapsulation {
+    /// sealed with session of peer, relayed if needed
+    Native,
+    /// plain vxlan, to interwork with linux `vxlan` interfaces
+    Vxlan,
+    /// plain geneve with metadata in options, to interwork with linux `geneve`
+    /// interfaces
+    Geneve,
+}
+
+/// virtual network in config file, options not given are the top level ones
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct NetworkConfig {
+    pub(crate) name: String,
+    /// carried in frames and handshake, the same on every node of the network
+    pub(crate) id: u32,
+    pub(crate) tap_name: Option<String>,
+    pub(crate) mtu: Option<usize>,
+    pub(crate) data_port: Option<u16>,
+    pub(crate) ctl_port: Option<u16>,
+    pub(crate) discovery_port: Option<u16>,
+    pub(crate) auto: Option<bool>,
+    pub(crate) subnet: Option<Subnet>,
+    pub(crate) key: Option<String>,
+    pub(crate) key_file: Option<PathBuf>,
+    pub(crate) identity: Option<PathBuf>,
+    pub(crate) authorized_keys: Option<PathBuf>,
+    /// state file of top level with network name appended if not given
+    pub(crate) state_file: Option<PathBuf>,
+    pub(crate) peers: Option<Vec<PeerConfig>>,
+    pub(crate) encapsulation: Option<Encapsulation>,
+    pub(crate) vni: Option<u32>,
+    pub(crate) remotes: Option<Vec<IpAddr>>,
+}
+
+/// settings of `start`, read from toml file given by `--config`, flags override file values
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    pub(crate) name: String,
+    pub(crate) tap_name: String,
+    pub(crate) mtu: usize,
+    pub(crate) data_port: u16,
+    pub(crate) ctl_port: u16,
+    pub(crate) multicast_group: Ipv4Addr,
+    /// ipv6 discovery group, of link-local scope
+    pub(crate) multicast_group_v6: Ipv6Addr,
+    /// interface discovery is sent out of and joined on, the one of route if not given
+    pub(crate) multicast_interface: Option<String>,
+    /// port discovery requests are sent to, `ctl_port` if not given
+    pub(crate) discovery_port: Option<u16>,
+    /// hops discovery requests go, 1 stays on the lan
+    pub(crate) multicast_ttl: u32,
+    /// nodes only discover nodes of the same cluster, so overlays share a lan
+    pub(crate) cluster_id: String,
+    /// seconds between probes of peers
+    pub(crate) heartbeat_interval: u64,
+    /// seconds to wait for pong, twice this for indirect probes
+    pub(crate) probe_timeout: u64,
+    /// seconds a peer stays suspect before declared dead
+    pub(crate) suspect_timeout: u64,
+    /// seconds between discovery rounds in auto mode
+    pub(crate) discovery_interval: u64,
+    pub(crate) auto: bool,
+    pub(crate) subnet: Option<Subnet>,
+    pub(crate) key: Option<String>,
+    pub(crate) key_file: Option<PathBuf>,
+    pub(crate) identity: PathBuf,
+    pub(crate) authorized_keys: Option<PathBuf>,
+    pub(crate) admin_socket: PathBuf,
+    /// peers learned at runtime are kept here across restarts
+    pub(crate) state_file: PathBuf,
+    /// seconds a peer is kept in state file after last seen
+    pub(crate) peer_expire: u64,
+    /// rendezvous to reach nodes behind nat, eg, `rv.example.com:9910`
+    pub(crate) rendezvous: Option<String>,
+    /// pass frames and control messages on between peers not reaching each other
+    pub(crate) relay: bool,
+    /// broadcast and multicast frames from tap a second, more are dropped as a storm,
+    /// 0 for no limit
+    pub(crate) broadcast_limit: u64,
+    pub(crate) peers: Vec<PeerConfig>,
+    /// name of network, from `[[networks]]`, or `default`
+    #[serde(skip)]
+    pub(crate) network: String,
+    /// carried in frames and handshake, nodes of different ids never pair
+    pub(crate) network_id: u32,
+    /// several networks on one daemon, each with its own tap, ports and peers
+    pub(crate) networks: Vec<NetworkConfig>,
+    pub(crate) encapsulation: Encapsulation,
+    /// vni of vxlan and geneve encapsulation
+    pub(crate) vni: u32,
+    /// vteps frames are flooded to on `data_port`, besides peers, eg, linux vxlan
+    /// interfaces
+    pub(crate) remotes: Vec<IpAddr>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            name: env::var("HOSTNAME")
+                .or_else(|_| env::var("HOST"))
+                .unwrap_or_else(|_| "peer-01".to_owned()),
+            tap_name: "tap0".to_owned(),
+            mtu: DEFAULT_MTU,
+            data_port: 9908,
+            ctl_port: 9909,
+            multicast_group: Ipv4Addr::new(224, 0, 0, 100),
+            multicast_group_v6: Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0x7464),
+            multicast_interface: None,
+            discovery_port: None,
+            multicast_ttl: 1,
+            cluster_id: "default".to_owned(),
+            heartbeat_interval: 10,
+            probe_timeout: 2,
+            suspect_timeout: 30,
+            discovery_interval: 60,
+            auto: false,
+            subnet: None,
+            key: None,
+            key_file: None,
+            identity: PathBuf::from(DEFAULT_IDENTITY_PATH),
+            authorized_keys: None,
+            admin_socket: PathBuf::from(DEFAULT_ADMIN_SOCKET),
+            state_file: PathBuf::from(DEFAULT_STATE_FILE),
+            peer_expire: DEFAULT_PEER_EXPIRE,
+            rendezvous: None,
+            relay: true,
+            broadcast_limit: 1000,
+            peers: Vec::new(),
+            network: DEFAULT_NETWORK.to_owned(),
+            network_id: 0,
+            networks: Vec::new(),
+            encapsulation: Encapsulation::Native,
+            vni: 1,
+            remotes: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// config file from `--config` if given, then overridden by flags of `start`
+    pub(crate) fn load(args: &ArgMatches) -> AppResult<Config> {
+        let mut config = match args.value_of("config") {
+            Some(path) => Config::from_file(Path::new(path))?,
+            None => Config::default(),
+        };
+
+        config.apply_args(args)?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub(crate) fn from_file(path: &Path) -> AppResult<Config> {
+        let content = fs::read_to_string(path).map_err(|e| {
+            TapDemoError::ConfigError(format!("error read {}, {}", path.display(), e))
+        })?;
+
+        Config::from_toml(&content)
+            .map_err(|e| TapDemoError::ConfigError(format!("{}, {}", path.display(), e)))
+    }
+
+    fn from_toml(content: &str) -> Result<Config, toml::de::Error> {
+        toml::from_str(content)
+    }
+
+    fn apply_args(&mut self, args: &ArgMatches) -> AppResult<()> {
+        if let Some(name) = args.value_of("name") {
+            self.name = name.to_owned();
+        }
+
+        if let Some(peers_str) = args.value_of("peers") {
+            self.peers = parse_peers_str(peers_str)?;
+        }
+
+        if args.is_present("auto") {
+            self.auto = true;
+        }
+
+        if let Some(key) = args.value_of("key") {
+            self.key = Some(key.to_owned());
+            self.key_file = None;
+        }
+
+        if let Some(key_file) = args.value_of("key file") {
+            self.key_file = Some(PathBuf::from(key_file));
+            self.key = None;
+        }
+
+        if let Some(subnet) = args.value_of("subnet") {
+            self.subnet = Some(subnet.parse()?);
+        }
+
+        if let Some(mtu) = args.value_of("mtu") {
+            self.mtu = mtu
+                .parse()
+                .map_err(|_| TapDemoError::MtuParseError(mtu.to_owned()))?;
+        }
+
+        if let Some(identity) = args.value_of("identity") {
+            self.identity = PathBuf::from(identity);
+        }
+
+        if let Some(authorized_keys) = args.value_of("authorized keys") {
+            self.authorized_keys = Some(PathBuf::from(authorized_keys));
+        }
+
+        if let Some(admin_socket) = args.value_of("admin socket") {
+            self.admin_socket = PathBuf::from(admin_socket);
+        }
+
+        if let Some(state_file) = args.value_of("state file") {
+            self.state_file = PathBuf::from(state_file);
+        }
+
+        if let Some(rendezvous) = args.value_of("rendezvous") {
+            self.rendezvous = Some(rendezvous.to_owned());
+        }
+
+        if args.is_present("no relay") {
+            self.relay = false;
+        }
+
+        if let Some(cluster_id) = args.value_of("cluster id") {
+            self.cluster_id = cluster_id.to_owned();
+        }
+
+        if let Some(interface) = args.value_of("multicast interface") {
+            self.multicast_interface = Some(interface.to_owned());
+        }
+
+        Ok(())
+    }
+
+    /// config of each network, top level options with those of network over them, or
+    /// top level one as `default` network if no `[[networks]]` given
+    pub(crate) fn networks(&self) -> Vec<Config> {
+        if self.networks.is_empty() {
+            return vec![self.clone()];
+        }
+
+        self.networks
+            .iter()
+            .map(|it| {
+                let mut config = Config {
+                    network: it.name.clone(),
+                    network_id: it.id,
+                    networks: Vec::new(),
+                    ..self.clone()
+                };
+
+                if let Some(ref tap_name) = it.tap_name {
+                    config.tap_name = tap_name.clone();
+                }
+                if let Some(mtu) = it.mtu {
+                    config.mtu = mtu;
+                }
+                if let Some(data_port) = it.data_port {
+                    config.data_port = data_port;
+                }
+                if let Some(ctl_port) = it.ctl_port {
+                    config.ctl_port = ctl_port;
+                }
+                if let Some(discovery_port) = it.discovery_port {
+                    config.discovery_port = Some(discovery_port);
+                }
+                if let Some(auto) = it.auto {
+                    config.auto = auto;
+                }
+                if let Some(subnet) = it.subnet {
+                    config.subnet = Some(subnet);
+                }
+                if let Some(ref key) = it.key {
+                    config.key = Some(key.clone());
+                    config.key_file = None;
+                }
+                if let Some(ref key_file) = it.key_file {
+                    config.key_file = Some(key_file.clone());
+                    config.key = None;
+                }
+                if let Some(ref identity) = it.identity {
+                    config.identity = identity.clone();
+                }
+                if let Some(ref authorized_keys) = it.authorized_keys {
+                    config.authorized_keys = Some(authorized_keys.clone());
+                }
+                if let Some(ref peers) = it.peers {
+                    config.peers = peers.clone();
+                }
+                if let Some(encapsulation) = it.encapsulation {
+                    config.encapsulation = encapsulation;
+                }
+                if let Some(vni) = it.vni {
+                    config.vni = vni;
+                }
+                if let Some(ref remotes) = it.remotes {
+                    config.remotes = remotes.clone();
+                }
+
+                config.state_file = match it.state_file {
+                    Some(ref state_file) => state_file.clone(),
+                    None => format!("{}.{}", self.state_file.display(), it.name).into(),
+                };
+
+                config
+            })
+            .collect()
+    }
+
+    /// each network is valid, and none shares a tap, port or id with another
+    fn validate_networks(&self) -> AppResult<()> {
+        let invalid = |msg: &str| Err(TapDemoError::ConfigError(msg.to_owned()));
+        let networks = self.networks();
+
+        for (i, config) in networks.iter().enumerate() {
+            let name = &config.network;
+            let name_valid = name.len() <= MAX_NETWORK_NAME_LEN
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+            if name.is_empty() || !name_valid {
+                return invalid("network name must be 1 to 32 letters, digits, - or _");
+            }
+
+            config
+                .validate()
+                .map_err(|e| TapDemoError::ConfigError(format!("network {}, {}", name, e)))?;
+
+            let ports = |it: &Config| {
+                let discovery_port = discovery_port(it);
+                vec![it.data_port, it.ctl_port, discovery_port]
+            };
+
+            for other in &networks[..i] {
+                if other.network == config.network || other.network_id == config.network_id {
+                    return invalid("networks must have distinct names and ids");
+                }
+
+                if other.tap_name == config.tap_name || other.state_file == config.state_file {
+                    return invalid("networks must have distinct tap_name and state_file");
+                }
+
+                if ports(other).iter().any(|it| ports(config).contains(it)) {
+                    return invalid(
+                        "networks must have distinct data, control and discovery ports",
+                    );
+                }
+
+                // rendezvous knows nodes by key
+                if config.rendezvous.is_some() && other.identity == config.identity {
+                    return invalid("networks using rendezvous must have distinct identity");
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate(&self) -> AppResult<()> {
+        let invalid = |msg: &str| Err(TapDemoError::ConfigError(msg.to_owned()));
+
+        if !self.networks.is_empty() {
+            return self.validate_networks();
+        }
+
+        if self.name.is_empty() || self.name.len() > MAX_NAME_LEN {
+            return invalid("name must be 1 to 64 bytes");
+        }
+
+        if self.tap_name.is_empty() || self.tap_name.len() > MAX_IF_NAME_LEN {
+            return invalid("tap_name must be 1 to 15 bytes");
+        }
+
+        if self.mtu < MIN_MTU || self.mtu > MAX_MTU {
+            return Err(TapDemoError::MtuParseError(self.mtu.to_string()));
+        }
+
+        if self.data_port == 0 || self.ctl_port == 0 {
+            return invalid("data_port and ctl_port must not be 0");
+        }
+
+        if self.data_port == self.ctl_port {
+            return invalid("data_port and ctl_port must differ");
+        }
+
+        if !self.multicast_group.is_multicast() || !self.multicast_group_v6.is_multicast() {
+            return invalid("multicast_group and multicast_group_v6 must be multicast addresses");
+        }
+
+        match self.multicast_interface {
+            Some(ref name) if name.is_empty() || name.len() > MAX_IF_NAME_LEN => {
+                return invalid("multicast_interface must be 1 to 15 bytes");
+            }
+            _ => {}
+        }
+
+        if self.discovery_port == Some(0) || self.discovery_port == Some(self.data_port) {
+            return invalid("discovery_port must not be 0 or data_port");
+        }
+
+        if self.multicast_ttl == 0 || self.multicast_ttl > 255 {
+            return invalid("multicast_ttl must be 1 to 255");
+        }
+
+        if self.cluster_id.is_empty() || self.cluster_id.len() > MAX_CLUSTER_ID_LEN {
+            return invalid("cluster_id must be 1 to 64 bytes");
+        }
+
+        if self.heartbeat_interval == 0 || self.discovery_interval == 0 {
+            return invalid("heartbeat_interval and discovery_interval must not be 0");
+        }
+
+        if self.probe_timeout == 0 {
+            return invalid("probe_timeout must not be 0");
+        }
+
+        if self.key.is_some() && self.key_file.is_some() {
+            return invalid("key and key_file can't be both given");
+        }
+
+        if self.vni > MAX_VNI {
+            return invalid("vni must be below 2^24");
+        }
+
+        if !self.remotes.is_empty() && self.encapsulation == Encapsulation::Native {
+            return invalid("remotes are only sent to with vxlan or geneve encapsulation");
+        }
+
+        self.static_peers()?;
+
+        Ok(())
+    }
+
+    /// peers to connect at start
+    pub(crate) fn static_peers(&self) -> AppResult<Vec<Peer>> {
+        self.peers
+            .iter()
+            .map(|it| {
+                format!("{}={}", it.name, it.addr)
+                    .parse()
+                    .map_err(|e| TapDemoError::ConfigError(format!("peer {}, {}", it.name, e)))
+            })
+            .collect()
+    }
+}
+
+/// admin socket used by cli, `--admin-socket` or the one in `--config`
+pub(crate) fn admin_socket(args: &ArgMatches) -> AppResult<PathBuf> {
+    if let Some(admin_socket) = args.value_of("admin socket") {
+        return Ok(PathBuf::from(admin_socket));
+    }
+
+    match args.value_of("config") {
+        Some(path) => Ok(Config::from_file(Path::new(path))?.admin_socket),
+        None => Ok(PathBuf::from(DEFAULT_ADMIN_SOCKET)),
+    }
+}
+
+/// peers from `--peers`, eg, `peer1=10.0.0.1:9909,peer2=10.0.0.2:9909`
+fn parse_peers_str(peers_str: &str) -> AppResult<Vec<PeerConfig>> {
+    peers_str
+        .split(',')
+        .map(|it| {
+            let pairs: Vec<&str> = it.split('=').collect();
+
+            if pairs.len() != 2 {
+                return Err(TapDemoError::PeerParseError);
+            }
+
+            Ok(PeerConfig {
+                name: pairs[0].to_owned(),
+                addr: pairs[1].to_owned(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_config() {
+        let config = Config::from_toml(
+            r#"
+            name = "node-1"
+            tap_name = "tap1"
+            ctl_port = 10909
+            data_port = 10908
+            subnet = "10.0.0.0/24"
+
+            [[peers]]
+            name = "node-2"
+            addr = "127.0.0.1:10909"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.name, "node-1");
+        assert_eq!(config.subnet, Some("10.0.0.0/24".parse().unwrap()));
+        assert_eq!(config.heartbeat_interval, 10);
+        assert!(config.validate().is_ok());
+
+        let peers = config.static_peers().unwrap();
+        assert_eq!(peers[0].ctl_addr, "127.0.0.1:10909".parse().unwrap());
+
+        // unknown key, bad subnet
+        assert!(Config::from_toml("ctl_prot = 1").is_err());
+        assert!(Config::from_toml(r#"subnet = "10.0.0.0/31""#).is_err());
+
+        for content in &[
+            "ctl_port = 9908",
+            r#"multicast_group = "10.0.0.1""#,
+            r#"multicast_group_v6 = "fe80::1""#,
+            "discovery_port = 9908",
+            "multicast_ttl = 256",
+            r#"cluster_id = """#,
+            "heartbeat_interval = 0",
+            "probe_timeout = 0",
+            "mtu = 10",
+            r#"tap_name = "a-very-long-tap-name""#,
+            "vni = 16777216",
+            r#"remotes = ["10.0.0.2"]"#,
+        ] {
+            assert!(Config::from_toml(content).unwrap().validate().is_err());
+        }
+
+        let long_name = format!("name = \"{}\"", "n".repeat(MAX_NAME_LEN + 1));
+        assert!(Config::from_toml(&long_name).unwrap().validate().is_err());
+
+        let vxlan =
+            Config::from_toml("encapsulation = \"vxlan\"\nvni = 42\nremotes = [\"10.0.0.2\"]");
+        assert!(vxlan.unwrap().validate().is_ok());
+        assert!(Config::from_toml(r#"encapsulation = "geneve""#).is_ok());
+        assert!(Config::from_toml(r#"encapsulation = "gre""#).is_err());
+    }
+
+    #[test]
+    fn test_networks() {
+        let config = Config::from_toml(
+            r#"
+            key = "shared"
+
+            [[networks]]
+            name = "red"
+            id = 1
+            tap_name = "tap-red"
+
+            [[networks]]
+            name = "blue"
+            id = 2
+            tap_name = "tap-blue"
+            data_port = 10908
+            ctl_port = 10909
+            key_file = "/etc/tap-demo/blue.key"
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+
+        let networks = config.networks();
+        assert_eq!(networks.len(), 2);
+        assert_eq!(networks[0].network, "red");
+        assert_eq!(networks[0].key.as_deref(), Some("shared"));
+        assert_eq!(networks[0].ctl_port, 9909);
+        assert_eq!(networks[1].network_id, 2);
+        assert_eq!(networks[1].key, None);
+        assert_ne!(networks[0].state_file, networks[1].state_file);
+
+        // top level alone is the default network
+        let config = Config::from_toml("network_id = 7").unwrap();
+        assert_eq!(config.networks()[0].network, DEFAULT_NETWORK);
+        assert_eq!(config.networks()[0].network_id, 7);
+
+        let red = "[[networks]]\nname = \"red\"\nid = 1\ntap_name = \"tap-red\"\n";
+        let blue = "[[networks]]\nname = \"blue\"\nid = 2\ntap_name = \"tap-blue\"\n";
+        let ports = "data_port = 10908\nctl_port = 10909\n";
+
+        for content in &[
+            // both on default ports
+            format!("{}{}", red, blue),
+            // same id, tap, ports as another network
+            format!("{}{}{}", red, blue.replace("id = 2", "id = 1"), ports),
+            format!("{}{}{}", red, blue.replace("tap-blue", "tap-red"), ports),
+            format!("{}{}data_port = 9908\nctl_port = 10909", red, blue),
+            // bad name, invalid option of a network
+            format!("{}{}{}", red.replace("red\"", "r/d\""), blue, ports),
+            format!("{}{}{}mtu = 10", red, blue, ports),
+        ] {
+            assert!(Config::from_toml(content).unwrap().validate().is_err());
+        }
+        assert!(Config::from_toml(&format!("{}{}{}", red, blue, ports))
+            .unwrap()
+            .validate()
+            .is_ok());
+
+        assert!(Config::from_toml("[[networks]]\nname = \"red\"").is_err());
+    }
+}
@@ -1,127 +1,508 @@
+use std::io;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
 use std::thread::JoinHandle;
+use std::time::Duration;
 
-use bincode::deserialize;
+use bincode::serialize;
 use log::{debug, error};
-use socket2::SockAddr;
+use socket2::Socket;
 
 use crate::app::AppState;
-use crate::discovery::new_socket;
+use crate::config::Config;
+use crate::crypto::HEADER_LEN;
+use crate::detector::{ping_req_workers, PingReq};
 use crate::discovery::send_msg;
-use crate::discovery::IPV4;
-use crate::discovery::{init_peer_hw_addr, scan_node};
+use crate::discovery::{discovery_port, discovery_reply, join_groups, new_socket};
+use crate::error::{AppResult, TapDemoError};
+use crate::handshake::Responder;
+use crate::ipam::resolve_conflict;
+use crate::membership::{merge, on_exchange_reply, on_exchange_request};
 use crate::msg::*;
+use crate::peer::{Peer, PeerState};
+use crate::rendezvous::{on_nodes, on_punch, on_relay_ready, rendezvous_addr};
+use crate::routing::{next_hop_to, on_routes, MAX_HOPS};
+use crate::stats::Stats;
+use crate::underlay::canonical;
 
-pub(crate) fn control_thread(state: Arc<AppState>) -> JoinHandle<()> {
-    debug!("control_thread start");
+/// how long requests to peers wait for reply
+pub(crate) const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
 
-    std::thread::spawn(move || {
-        let sock = new_socket().unwrap();
-        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 9909);
+/// authenticated along with sealed control messages, so they never pass as data frames
+const CONTROL_AAD: &[u8] = b"tap-demo control";
+
+/// request waiting for a reply from one of `from`
+struct Waiter {
+    id: u64,
+    from: Vec<SocketAddr>,
+    accept: fn(&ControlMsg) -> bool,
+    tx: Sender<(SocketAddr, ControlMsg)>,
+}
+
+/// control socket, shared by control thread and requests to peers
+///
+/// everything to peers is sent from it, so a peer behind nat is reached through the
+/// mapping it opened towards us. replies are received by control thread, and passed
+/// to the request waiting for them.
+pub(crate) struct ControlSocket {
+    sock: Socket,
+    waiters: Mutex<Vec<Waiter>>,
+    next_id: AtomicU64,
+}
+
+impl ControlSocket {
+    /// bind `ctl_port` of config on all interfaces, and join discovery groups if
+    /// discovery is on the same port
+    pub(crate) fn bind(config: &Config) -> io::Result<ControlSocket> {
+        let sock = new_socket(config.ctl_port)?;
+
+        if discovery_port(config) == config.ctl_port {
+            join_groups(&sock, config)?;
+        }
+
+        Ok(ControlSocket {
+            sock,
+            waiters: Mutex::new(Vec::new()),
+            next_id: AtomicU64::new(0),
+        })
+    }
+
+    #[cfg(test)]
+    pub(crate) fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.sock
+            .local_addr()?
+            .as_std()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not an inet socket"))
+    }
+
+    pub(crate) fn send(&self, msg: ControlMsg, addr: SocketAddr) -> io::Result<usize> {
+        self.send_via(msg, addr, None)
+    }
+
+    /// send `msg` to `addr`, passed on by peer at `via` if given
+    pub(crate) fn send_via(
+        &self,
+        msg: ControlMsg,
+        addr: SocketAddr,
+        via: Option<SocketAddr>,
+    ) -> io::Result<usize> {
+        let (msg, addr) = route(msg, addr, via);
+
+        send_msg(Msg { inner: msg }, &self.sock, &addr.into())
+    }
 
-        match *IPV4 {
-            IpAddr::V4(ref ipv4) => {
-                sock.join_multicast_v4(ipv4, &Ipv4Addr::new(0, 0, 0, 0))
-                    .unwrap();
+    /// send `msg` to each of `to`, and wait `timeout` for a reply accepted by `accept`
+    /// from any of them
+    pub(crate) fn request(
+        &self,
+        msg: ControlMsg,
+        to: &[SocketAddr],
+        accept: fn(&ControlMsg) -> bool,
+        timeout: Duration,
+    ) -> AppResult<ControlMsg> {
+        let (_, reply) = self.request_via(msg, to, None, accept, timeout)?;
+
+        Ok(reply)
+    }
+
+    /// as `request`, passed on by peer at `via` if given, return the reply with the
+    /// one sent it
+    pub(crate) fn request_via(
+        &self,
+        msg: ControlMsg,
+        to: &[SocketAddr],
+        via: Option<SocketAddr>,
+        accept: fn(&ControlMsg) -> bool,
+        timeout: Duration,
+    ) -> AppResult<(SocketAddr, ControlMsg)> {
+        let (tx, rx) = mpsc::channel();
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+
+        // before sending, so a quick reply is not missed
+        self.waiters.lock().unwrap().push(Waiter {
+            id,
+            from: to.to_vec(),
+            accept,
+            tx,
+        });
+
+        let sent: AppResult<()> = to.iter().try_for_each(|addr| {
+            let (msg, addr) = route(msg.clone(), *addr, via);
+            let data = serialize(&Msg { inner: msg })?;
+            self.sock.send_to(&data, &addr.into())?;
+
+            Ok(())
+        });
+
+        let reply = sent.and_then(|_| rx.recv_timeout(timeout).map_err(|_| TapDemoError::NoReply));
+
+        self.waiters.lock().unwrap().retain(|it| it.id != id);
+
+        reply
+    }
+
+    /// pass reply from `src` to the request waiting for it, `msg` is given back if
+    /// none is
+    fn deliver(&self, src: SocketAddr, msg: ControlMsg) -> Option<ControlMsg> {
+        let mut waiters = self.waiters.lock().unwrap();
+
+        let index = waiters
+            .iter()
+            .position(|it| it.from.contains(&src) && (it.accept)(&msg));
+
+        match index {
+            Some(index) => {
+                // one reply per request, the next one goes to another request
+                let waiter = waiters.swap_remove(index);
+                let _ = waiter.tx.send((src, msg));
+
+                None
             }
-            IpAddr::V6(_) => unreachable!(),
+            None => Some(msg),
         }
+    }
+}
 
-        sock.bind(&SockAddr::from(addr)).unwrap();
+/// `msg` wrapped for peer at `via` to pass on to `addr`, and where to send it
+fn route(msg: ControlMsg, addr: SocketAddr, via: Option<SocketAddr>) -> (ControlMsg, SocketAddr) {
+    match via {
+        Some(via) => (
+            ControlMsg::Forward {
+                to: addr,
+                from: SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+                ttl: MAX_HOPS,
+                msg: Box::new(msg),
+            },
+            via,
+        ),
+        None => (msg, addr),
+    }
+}
 
-        let mut buff = vec![0; 512];
+/// send `msg` to `peer` sealed with session towards it, so only that peer opens it and
+/// knows it's from us, passed on by its relay if any
+pub(crate) fn send_sealed(state: &AppState, msg: &ControlMsg, peer: &Peer) -> AppResult<()> {
+    let data = serialize(msg)?;
+    let mut sealed = CONTROL_AAD.to_vec();
+
+    state
+        .cipher
+        .seal_control(&peer.data_addr, &data, &mut sealed)
+        .ok_or(TapDemoError::NoSession)?;
+
+    let msg = ControlMsg::Sealed(sealed.split_off(CONTROL_AAD.len()));
+    state.ctl.send_via(msg, peer.ctl_addr, peer.via)?;
+
+    Ok(())
+}
+
+/// open msg sealed by peer at ctl addr `src`, return it with that peer
+fn open_sealed(state: &AppState, src: SocketAddr, data: &[u8]) -> AppResult<(Peer, ControlMsg)> {
+    let mut sealed = [CONTROL_AAD, data].concat();
+
+    let (size, data_addr) = state
+        .cipher
+        .open(&mut sealed, CONTROL_AAD.len())
+        .map_err(|_| TapDemoError::PeerNotAuthorized)?;
+
+    // session and address must both be of the same peer
+    let peer = {
+        let peers = state.peers.read().unwrap();
+        peers
+            .iter()
+            .find(|it| it.data_addr == data_addr && it.ctl_addr == src)
+            .cloned()
+            .ok_or(TapDemoError::PeerNotAuthorized)?
+    };
+
+    let start = CONTROL_AAD.len() + HEADER_LEN;
+    let msg: ControlMsg = decode(&sealed[start..start + size])?;
+
+    if is_forwarding(&msg) || matches!(msg, ControlMsg::Sealed(_)) {
+        return Err(TapDemoError::UnexpectedMsg);
+    }
+
+    Ok((peer, msg))
+}
+
+fn is_forwarding(msg: &ControlMsg) -> bool {
+    matches!(
+        msg,
+        ControlMsg::Forward { .. } | ControlMsg::Forwarded { .. }
+    )
+}
+
+/// whether `msg` is passed on by peer at `src`, the last relay on the path
+fn is_forwarded_by_peer(state: &AppState, src: SocketAddr, msg: &ControlMsg) -> bool {
+    if is_forwarding(msg) {
+        return false;
+    }
+
+    let peers = state.peers.read().unwrap();
+    peers
+        .iter()
+        .any(|it| it.ctl_addr == src && it.via.is_none())
+}
+
+/// pass `msg` from peer at `src` on towards node at `to`, to the node itself if a peer
+/// reached directly, else to the next hop of its route, if this node relays
+fn forward(
+    state: &AppState,
+    src: SocketAddr,
+    to: SocketAddr,
+    from: SocketAddr,
+    ttl: u8,
+    msg: ControlMsg,
+) -> bool {
+    if !state.config().relay || is_forwarding(&msg) {
+        return false;
+    }
+
+    let next_hop = {
+        let peers = state.peers.read().unwrap();
+        let known = peers
+            .iter()
+            .any(|it| it.ctl_addr == src && it.via.is_none() && it.state != PeerState::Dead);
+
+        if !known {
+            return false;
+        }
+
+        next_hop_to(state, &peers, to)
+    };
+
+    // filled by the first relay only, later ones trust the relay before them
+    let from = if from.ip().is_unspecified() {
+        src
+    } else {
+        from
+    };
+
+    match next_hop {
+        Some(next_hop) if next_hop == to => {
+            let msg = ControlMsg::Forwarded {
+                from,
+                msg: Box::new(msg),
+            };
+
+            state.ctl.send(msg, to).is_ok()
+        }
+        Some(next_hop) if next_hop != src && ttl > 1 => {
+            let msg = ControlMsg::Forward {
+                to,
+                from,
+                ttl: ttl - 1,
+                msg: Box::new(msg),
+            };
+
+            state.ctl.send(msg, next_hop).is_ok()
+        }
+        _ => false,
+    }
+}
+
+pub(crate) fn control_thread(state: Arc<AppState>) -> JoinHandle<()> {
+    debug!("control_thread start");
+
+    let ping_reqs = ping_req_workers(&state);
+
+    std::thread::spawn(move || {
+        let mut buff = vec![0; MAX_MSG_LEN];
+        let mut responder = Responder::new();
 
         loop {
-            let size_and_addr = sock.recv_from(&mut buff);
+            let size_and_addr = state.ctl.sock.recv_from(&mut buff);
 
             match size_and_addr {
-                Ok((_size, src_addr)) => {
-                    let msg = deserialize(&buff);
-                    let msg: Msg = msg.unwrap();
+                Ok((size, src_addr)) => {
+                    let src = match src_addr.as_std() {
+                        Some(src) => canonical(src),
+                        None => continue,
+                    };
 
-                    match msg.inner {
-                        ControlMsg::DiscoveryRequest => {
-                            let msg_reply = Msg {
-                                inner: ControlMsg::DiscoveryReply(MsgDiscoveryReply {
-                                    name: state.name.clone(),
-                                    hw_addr: state.hw_addr,
-                                }),
-                            };
+                    let msg: Msg = match decode(&buff[..size]) {
+                        Ok(msg) => msg,
+                        Err(e) => {
+                            Stats::incr(&state.stats.ctl_malformed);
+                            debug!("drop msg from {}, {}", src, e);
+                            continue;
+                        }
+                    };
+
+                    // passed on by a peer, handled as sent by the one it's from, and
+                    // replied through the peer
+                    let (src, via, msg) = match msg.inner {
+                        ControlMsg::Forwarded { from, msg } => {
+                            if !is_forwarded_by_peer(&state, src, &msg) {
+                                Stats::incr(&state.stats.ctl_unexpected);
+                                debug!("drop msg forwarded by {} from {}", src, from);
+                                continue;
+                            }
+
+                            (from, Some(src), *msg)
+                        }
+                        msg => (src, None, msg),
+                    };
+
+                    // sealed by a peer, which alone may change what we know of it
+                    let (sender, msg) = match msg {
+                        ControlMsg::Sealed(data) => match open_sealed(&state, src, &data) {
+                            Ok((peer, msg)) => (Some(peer), msg),
+                            Err(e) => {
+                                Stats::incr(&state.stats.ctl_unexpected);
+                                debug!("drop sealed msg from {}, {}", src, e);
+                                continue;
+                            }
+                        },
+                        msg => (None, msg),
+                    };
+
+                    let msg = match state.ctl.deliver(src, msg) {
+                        Some(msg) => msg,
+                        None => continue,
+                    };
 
-                            let _ = send_msg(msg_reply, &sock, &src_addr);
+                    match msg {
+                        ControlMsg::DiscoveryRequest => {
+                            let _ = state.ctl.send_via(discovery_reply(&state), src, via);
                         }
                         ControlMsg::HwAddrRequest => {
-                            let msg_reply = Msg {
-                                inner: ControlMsg::HwAddrReply(state.hw_addr),
-                            };
+                            let msg_reply = ControlMsg::HwAddrReply(state.hw_addr);
 
-                            let _ = send_msg(msg_reply, &sock, &src_addr);
+                            let _ = state.ctl.send_via(msg_reply, src, via);
                         }
                         ControlMsg::Ping => {
-                            let msg_reply = Msg {
-                                inner: ControlMsg::Pong,
-                            };
+                            let _ = state.ctl.send_via(ControlMsg::Pong, src, via);
+                        }
+                        ControlMsg::HandshakeInit(data) => {
+                            match responder.on_init(&state, src, &data) {
+                                Ok(reply) => {
+                                    let msg_reply = ControlMsg::HandshakeResponse(reply);
 
-                            let _ = send_msg(msg_reply, &sock, &src_addr);
+                                    let _ = state.ctl.send_via(msg_reply, src, via);
+                                }
+                                Err(e) => debug!("handshake from {} failed, {}", src, e),
+                            }
                         }
-                        ControlMsg::AddPeerRequest(mut peer) => {
-                            let result = init_peer_hw_addr(&mut peer);
+                        ControlMsg::HandshakeFinish(data) => {
+                            let result = responder.on_finish(&state, src, via, &data);
+
+                            if let Err(ref e) = result {
+                                debug!("handshake from {} failed, {}", src, e);
+                            }
+
+                            let msg_reply = ControlMsg::HandshakeComplete(result.is_ok());
 
-                            let msg_reply = match result {
-                                Ok(_) => {
-                                    state.add_peer(peer);
-                                    Msg {
-                                        inner: ControlMsg::AddPeerReply(true),
-                                    }
+                            let _ = state.ctl.send_via(msg_reply, src, via);
+                        }
+                        ControlMsg::AddrAnnounce { hw_addr, ip_addr } => {
+                            // only the peer itself may move its address
+                            let mut sender = match sender {
+                                Some(sender) if sender.hw_addr == hw_addr => sender,
+                                _ => {
+                                    Stats::incr(&state.stats.ctl_unexpected);
+                                    debug!("drop addr announce of {:x?} from {}", hw_addr, src);
+                                    continue;
                                 }
-                                Err(_) => Msg {
-                                    inner: ControlMsg::AddPeerReply(false),
-                                },
                             };
+                            sender.ip_addr = Some(ip_addr);
+
+                            state
+                                .peers
+                                .write()
+                                .unwrap()
+                                .iter_mut()
+                                .filter(|it| it.data_addr == sender.data_addr)
+                                .for_each(|it| it.ip_addr = sender.ip_addr);
 
-                            let _ = send_msg(msg_reply, &sock, &src_addr);
+                            resolve_conflict(&state, &sender);
                         }
-                        ControlMsg::ListPeerRequest => {
-                            let peers = { state.peers.read().unwrap().clone() };
+                        ControlMsg::PingReq(target) => {
+                            // only on behalf of peers, and only towards peers
+                            let known = {
+                                let peers = state.peers.read().unwrap();
 
-                            let msg_reply = Msg {
-                                inner: ControlMsg::ListPeerReply(peers),
+                                peers.iter().any(|it| it.ctl_addr == src)
+                                    && peers.iter().any(|it| it.ctl_addr == target)
                             };
 
-                            let _ = send_msg(msg_reply, &sock, &src_addr);
-                        }
-                        ControlMsg::RemovePeerRequest { name, addr } => {
-                            state.remove_peer(name, addr);
+                            if !known {
+                                Stats::incr(&state.stats.ctl_unexpected);
+                                debug!("drop ping request for {} from {}", target, src);
+                                continue;
+                            }
 
-                            let msg_reply = Msg {
-                                inner: ControlMsg::RemovePeerReply(true),
-                            };
+                            let req = PingReq { target, src, via };
 
-                            let _ = send_msg(msg_reply, &sock, &src_addr);
+                            if ping_reqs.try_send(req).is_err() {
+                                debug!("drop ping request for {} from {}, busy", target, src);
+                            }
                         }
-                        ControlMsg::ScanNodeRequest => {
-                            let peers = {
-                                let state = Arc::clone(&state);
-                                scan_node(state)
+                        ControlMsg::Gossip(members) => match sender {
+                            Some(sender) => merge(&state, sender.ctl_addr, members),
+                            None => {
+                                Stats::incr(&state.stats.ctl_unexpected);
+                                debug!("drop gossip from {}", src);
+                            }
+                        },
+                        ControlMsg::PeerExchangeRequest => {
+                            let known = {
+                                let peers = state.peers.read().unwrap();
+                                peers.iter().any(|it| it.ctl_addr == src)
                             };
 
-                            let msg_reply = match peers {
-                                Ok(peers) => {
-                                    state.add_peers(peers.clone());
+                            if !known {
+                                Stats::incr(&state.stats.ctl_unexpected);
+                                debug!("drop peer exchange request from {}", src);
+                                continue;
+                            }
 
-                                    Msg {
-                                        inner: ControlMsg::ScanNodeReply(peers),
-                                    }
+                            on_exchange_request(&state, src);
+                        }
+                        ControlMsg::PeerExchangeReply(members) => {
+                            if sender.is_none() || !on_exchange_reply(&state, src, members) {
+                                Stats::incr(&state.stats.ctl_unexpected);
+                                debug!("drop unrequested peer exchange reply from {}", src);
+                            }
+                        }
+                        ControlMsg::Nodes(_) | ControlMsg::Punch(_) | ControlMsg::RelayReady(_)
+                            if Some(src) != rendezvous_addr(&state) =>
+                        {
+                            Stats::incr(&state.stats.ctl_unexpected);
+                            debug!("drop rendezvous msg from {}", src);
+                        }
+                        ControlMsg::Nodes(nodes) => on_nodes(&state, nodes),
+                        ControlMsg::Punch(node) => on_punch(&state, node),
+                        ControlMsg::RelayReady(node) => {
+                            on_relay_ready(&state, src, node);
+                        }
+                        ControlMsg::Forward { to, from, ttl, msg } => {
+                            if !forward(&state, src, to, from, ttl, *msg) {
+                                Stats::incr(&state.stats.ctl_unexpected);
+                                debug!("drop msg from {} to forward to {}", src, to);
+                            }
+                        }
+                        ControlMsg::Routes(adverts) => {
+                            let learned = match sender {
+                                Some(ref sender) if via.is_none() => {
+                                    on_routes(&state, sender, adverts)
                                 }
-                                Err(_) => Msg {
-                                    inner: ControlMsg::ScanNodeReply(Vec::new()),
-                                },
+                                _ => false,
                             };
 
-                            let _ = send_msg(msg_reply, &sock, &src_addr);
+                            if !learned {
+                                Stats::incr(&state.stats.ctl_unexpected);
+                                debug!("drop routes from {}", src);
+                            }
+                        }
+                        // answer to a request which gave up waiting
+                        ControlMsg::Pong => {}
+                        msg => {
+                            Stats::incr(&state.stats.ctl_unexpected);
+                            debug!("unexpected msg from {}, {:?}", src, msg);
                         }
-                        _ => unreachable!(),
                     }
                 }
                 Err(e) => match e.kind() {
@@ -134,3 +515,131 @@ pub(crate) fn control_thread(state: Arc<AppState>) -> JoinHandle<()> {
         }
     })
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::membership::{Member, MemberState};
+    use crate::test_util::{connect, control_node, fake_node};
+    use std::net::UdpSocket;
+    use std::time::Instant;
+
+    fn ctl_addr(state: &AppState) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], state.config().ctl_port))
+    }
+
+    fn ip_of(state: &AppState, name: &str) -> Option<Ipv4Addr> {
+        let peers = state.peers.read().unwrap();
+        peers.iter().find(|it| it.name == name).unwrap().ip_addr
+    }
+
+    #[test]
+    fn test_sealed() {
+        let (a, _a_wire) = control_node("a", [2, 0, 0, 0, 0, 1]);
+        let (b, _b_wire) = fake_node("b", [2, 0, 0, 0, 0, 2]);
+        let (c, _c_wire) = fake_node("c", [2, 0, 0, 0, 0, 3]);
+        connect(&a, &b, 1, 2);
+        connect(&b, &a, 2, 1);
+        a.peers.write().unwrap()[0].ctl_addr = ctl_addr(&b);
+        b.peers.write().unwrap()[0].ctl_addr = ctl_addr(&a);
+        let peer_a = b.peers.read().unwrap()[0].clone();
+
+        let announce = |hw_addr, last| ControlMsg::AddrAnnounce {
+            hw_addr,
+            ip_addr: Ipv4Addr::new(10, 0, 0, last),
+        };
+
+        // not sealed, by a stranger or even by the peer itself
+        c.ctl.send(announce(b.hw_addr, 9), ctl_addr(&a)).unwrap();
+        b.ctl.send(announce(b.hw_addr, 9), ctl_addr(&a)).unwrap();
+
+        // sealed by the peer, but for another node
+        send_sealed(&b, &announce(c.hw_addr, 9), &peer_a).unwrap();
+
+        // sealed by the peer, but sent from another address
+        let mut sealed = CONTROL_AAD.to_vec();
+        let data = serialize(&announce(b.hw_addr, 9)).unwrap();
+        b.cipher.seal_control(&peer_a.data_addr, &data, &mut sealed);
+        let msg = ControlMsg::Sealed(sealed.split_off(CONTROL_AAD.len()));
+        c.ctl.send(msg, ctl_addr(&a)).unwrap();
+
+        send_sealed(&b, &announce(b.hw_addr, 2), &peer_a).unwrap();
+
+        let start = Instant::now();
+        while ip_of(&a, "b").is_none() && start.elapsed() < Duration::from_secs(5) {
+            std::thread::sleep(Duration::from_millis(50));
+        }
+
+        assert_eq!(ip_of(&a, "b"), Some(Ipv4Addr::new(10, 0, 0, 2)));
+        assert_eq!(a.stats.snapshot().ctl_unexpected, 4);
+    }
+
+    #[test]
+    fn test_gossip() {
+        let (a, _a_wire) = control_node("a", [2, 0, 0, 0, 0, 1]);
+        let (b, _b_wire) = fake_node("b", [2, 0, 0, 0, 0, 2]);
+        let (c, _c_wire) = fake_node("c", [2, 0, 0, 0, 0, 3]);
+        connect(&a, &b, 1, 2);
+        connect(&b, &a, 2, 1);
+        a.peers.write().unwrap()[0].ctl_addr = ctl_addr(&b);
+        b.peers.write().unwrap()[0].ctl_addr = ctl_addr(&a);
+        let peer_a = b.peers.read().unwrap()[0].clone();
+
+        let left = ControlMsg::Gossip(vec![Member {
+            name: "b".to_owned(),
+            public_key: b.handshake.identity.public_key,
+            ctl_addr: ctl_addr(&b),
+            state: MemberState::Left,
+            incarnation: u64::MAX,
+        }]);
+
+        // b leaving, as told by others or not sealed
+        c.ctl.send(left.clone(), ctl_addr(&a)).unwrap();
+        b.ctl.send(left.clone(), ctl_addr(&a)).unwrap();
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(a.peers.read().unwrap().len(), 1);
+        assert_eq!(a.stats.snapshot().ctl_unexpected, 2);
+
+        send_sealed(&b, &left, &peer_a).unwrap();
+
+        let start = Instant::now();
+        while !a.peers.read().unwrap().is_empty() && start.elapsed() < Duration::from_secs(5) {
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        assert!(a.peers.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_ping_req() {
+        let (a, _a_wire) = control_node("a", [2, 0, 0, 0, 0, 1]);
+        let (b, _b_wire) = fake_node("b", [2, 0, 0, 0, 0, 2]);
+        let (c, _c_wire) = fake_node("c", [2, 0, 0, 0, 0, 3]);
+        connect(&a, &b, 1, 2);
+        a.peers.write().unwrap()[0].ctl_addr = ctl_addr(&b);
+
+        // another port on the host of a peer is not that peer
+        let req = ControlMsg::PingReq(ctl_addr(&b));
+        c.ctl.send(req.clone(), ctl_addr(&a)).unwrap();
+
+        // a burst of requests is left to workers, control thread goes on at once
+        for _ in 0..100 {
+            b.ctl.send(req.clone(), ctl_addr(&a)).unwrap();
+        }
+
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sock.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let ping = serialize(&Msg {
+            inner: ControlMsg::Ping,
+        })
+        .unwrap();
+        sock.send_to(&ping, ctl_addr(&a)).unwrap();
+
+        let mut buff = vec![0; MAX_MSG_LEN];
+        let size = sock.recv(&mut buff).unwrap();
+        assert!(matches!(
+            decode::<Msg>(&buff[..size]).unwrap().inner,
+            ControlMsg::Pong
+        ));
+        assert_eq!(a.stats.snapshot().ctl_unexpected, 1);
+    }
+}
@@ -0,0 +1,392 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce, Tag};
+
+/// receiver index (4 bytes) + counter (8 bytes)
+pub(crate) const HEADER_LEN: usize = 12;
+pub(crate) const TAG_LEN: usize = 16;
+
+/// bytes added to every sealed frame
+pub(crate) const OVERHEAD: usize = HEADER_LEN + TAG_LEN;
+
+const REPLAY_WINDOW: u64 = 64;
+
+/// counters of control messages, apart from data frames so neither pushes the other
+/// out of replay window
+const CONTROL_COUNTER: u64 = 1 << 63;
+
+#[derive(Debug)]
+pub(crate) enum OpenError {
+    Malformed,
+    UnknownSession,
+    AuthFailed,
+    Replayed,
+    /// of another network, eg, a peer configured with another network id
+    WrongNetwork,
+    /// packet header of another protocol
+    BadMagic,
+    /// packet header of another version
+    WrongVersion,
+    /// packet type unknown to this version
+    UnknownType,
+    /// geneve packet with critical option not understood
+    UnknownOption,
+    /// geneve packet whose sender option names another peer than the one sent it
+    WrongSender,
+    /// geneve packet which may go no more hops
+    TtlExpired,
+}
+
+/// sliding window over received counters, as in RFC 6479
+#[derive(Debug, Default)]
+pub(crate) struct ReplayWindow {
+    top: u64,
+    bitmap: u64,
+    initialized: bool,
+}
+
+impl ReplayWindow {
+    /// check `counter` and mark it as seen, false if it's a replay or too old
+    pub(crate) fn check_and_update(&mut self, counter: u64) -> bool {
+        if !self.initialized {
+            self.initialized = true;
+            self.top = counter;
+            self.bitmap = 1;
+            return true;
+        }
+
+        if counter > self.top {
+            let shift = counter - self.top;
+            self.bitmap = if shift >= REPLAY_WINDOW {
+                0
+            } else {
+                self.bitmap << shift
+            };
+            self.bitmap |= 1;
+            self.top = counter;
+
+            return true;
+        }
+
+        let offset = self.top - counter;
+        if offset >= REPLAY_WINDOW {
+            return false;
+        }
+
+        let mask = 1 << offset;
+        if self.bitmap & mask != 0 {
+            return false;
+        }
+
+        self.bitmap |= mask;
+
+        true
+    }
+}
+
+/// keys derived by handshake, `tx` for frames to remote, `rx` for frames from remote
+pub(crate) struct SessionKeys {
+    pub(crate) local_index: u32,
+    pub(crate) remote_index: u32,
+    pub(crate) tx: [u8; 32],
+    pub(crate) rx: [u8; 32],
+}
+
+struct Session {
+    data_addr: SocketAddr,
+    remote_index: u32,
+    established: Instant,
+    tx_cipher: ChaCha20Poly1305,
+    tx_counter: AtomicU64,
+    ctl_counter: AtomicU64,
+    rx_cipher: ChaCha20Poly1305,
+    window: Mutex<ReplayWindow>,
+    ctl_window: Mutex<ReplayWindow>,
+}
+
+/// seal and open data frames with chacha20-poly1305
+///
+/// sessions are established by handshake with each peer, and looked up by the local
+/// index carried in frame header on receive, by peer data address on send. bytes in
+/// front of the sealed frame, ie, packet header, are authenticated along with it.
+pub(crate) struct DataCipher {
+    sessions: RwLock<HashMap<u32, Arc<Session>>>,
+}
+
+impl DataCipher {
+    pub(crate) fn new() -> DataCipher {
+        DataCipher {
+            sessions: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// pick an unused local index for a new session
+    pub(crate) fn alloc_index(&self) -> u32 {
+        let sessions = self.sessions.read().unwrap();
+
+        loop {
+            let index = rand::random();
+
+            if !sessions.contains_key(&index) {
+                return index;
+            }
+        }
+    }
+
+    /// install session with peer at `data_addr`, the previous session is kept for
+    /// frames still in flight, older ones are dropped
+    pub(crate) fn install(&self, data_addr: SocketAddr, keys: SessionKeys) {
+        let mut sessions = self.sessions.write().unwrap();
+
+        let mut existing: Vec<(u32, Instant)> = sessions
+            .iter()
+            .filter(|(_, it)| it.data_addr == data_addr)
+            .map(|(index, it)| (*index, it.established))
+            .collect();
+        existing.sort_by_key(|(_, established)| *established);
+
+        for (index, _) in existing.iter().rev().skip(1) {
+            sessions.remove(index);
+        }
+
+        sessions.insert(
+            keys.local_index,
+            Arc::new(Session {
+                data_addr,
+                remote_index: keys.remote_index,
+                established: Instant::now(),
+                tx_cipher: ChaCha20Poly1305::new(Key::from_slice(&keys.tx)),
+                tx_counter: AtomicU64::new(0),
+                ctl_counter: AtomicU64::new(CONTROL_COUNTER),
+                rx_cipher: ChaCha20Poly1305::new(Key::from_slice(&keys.rx)),
+                window: Mutex::new(ReplayWindow::default()),
+                ctl_window: Mutex::new(ReplayWindow::default()),
+            }),
+        );
+    }
+
+    /// drop all sessions with peer at `data_addr`
+    pub(crate) fn remove(&self, data_addr: &SocketAddr) {
+        self.sessions
+            .write()
+            .unwrap()
+            .retain(|_, it| it.data_addr != *data_addr);
+    }
+
+    pub(crate) fn has_session(&self, data_addr: &SocketAddr) -> bool {
+        self.tx_session(data_addr).is_some()
+    }
+
+    /// newest session with peer at `data_addr`
+    fn tx_session(&self, data_addr: &SocketAddr) -> Option<Arc<Session>> {
+        self.sessions
+            .read()
+            .unwrap()
+            .values()
+            .filter(|it| it.data_addr == *data_addr)
+            .max_by_key(|it| it.established)
+            .cloned()
+    }
+
+    /// encrypt `frame` for peer at `data_addr` and append it to `out`, whose bytes are
+    /// authenticated along, return length of `out`, `None` if no session with that peer
+    pub(crate) fn seal(
+        &self,
+        data_addr: &SocketAddr,
+        frame: &[u8],
+        out: &mut Vec<u8>,
+    ) -> Option<usize> {
+        let session = self.tx_session(data_addr)?;
+        let counter = session.tx_counter.fetch_add(1, Ordering::Relaxed);
+
+        Some(seal_with(&session, counter, frame, out))
+    }
+
+    /// as `seal`, for control message to peer at `data_addr`, counted apart from frames
+    pub(crate) fn seal_control(
+        &self,
+        data_addr: &SocketAddr,
+        msg: &[u8],
+        out: &mut Vec<u8>,
+    ) -> Option<usize> {
+        let session = self.tx_session(data_addr)?;
+        let counter = session.ctl_counter.fetch_add(1, Ordering::Relaxed);
+
+        Some(seal_with(&session, counter, msg, out))
+    }
+
+    /// decrypt frame sealed at `start` of `data` in place, bytes before it authenticated
+    /// along, return plain frame length, which follows our header, and data addr of the
+    /// peer sealed it
+    pub(crate) fn open(
+        &self,
+        data: &mut [u8],
+        start: usize,
+    ) -> Result<(usize, SocketAddr), OpenError> {
+        if data.len() < start + OVERHEAD {
+            return Err(OpenError::Malformed);
+        }
+
+        let mut index = [0; 4];
+        index.copy_from_slice(&data[start..start + 4]);
+        let index = u32::from_be_bytes(index);
+
+        let mut counter = [0; 8];
+        counter.copy_from_slice(&data[start + 4..start + HEADER_LEN]);
+        let counter = u64::from_be_bytes(counter);
+
+        let session = {
+            let sessions = self.sessions.read().unwrap();
+            sessions
+                .get(&index)
+                .cloned()
+                .ok_or(OpenError::UnknownSession)?
+        };
+
+        let tag_start = data.len() - TAG_LEN;
+        let tag = *Tag::from_slice(&data[tag_start..]);
+        let (header, payload) = data[..tag_start].split_at_mut(start + HEADER_LEN);
+
+        session
+            .rx_cipher
+            .decrypt_in_place_detached(&nonce(counter), header, payload, &tag)
+            .map_err(|_| OpenError::AuthFailed)?;
+
+        let window = if counter & CONTROL_COUNTER != 0 {
+            &session.ctl_window
+        } else {
+            &session.window
+        };
+
+        // only authenticated frames may touch replay state
+        if !window.lock().unwrap().check_and_update(counter) {
+            return Err(OpenError::Replayed);
+        }
+
+        Ok((tag_start - start - HEADER_LEN, session.data_addr))
+    }
+}
+
+/// seal `frame` with `counter` of `session`, appended to `out`, return length of `out`
+fn seal_with(session: &Session, counter: u64, frame: &[u8], out: &mut Vec<u8>) -> usize {
+    let start = out.len();
+    out.extend_from_slice(&session.remote_index.to_be_bytes());
+    out.extend_from_slice(&counter.to_be_bytes());
+    out.extend_from_slice(frame);
+
+    let (header, payload) = out.split_at_mut(start + HEADER_LEN);
+    let tag = session
+        .tx_cipher
+        .encrypt_in_place_detached(&nonce(counter), header, payload)
+        .expect("frame too large");
+
+    out.extend_from_slice(&tag);
+
+    out.len()
+}
+
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+
+    *Nonce::from_slice(&nonce)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_replay_window() {
+        let mut window = ReplayWindow::default();
+
+        assert!(window.check_and_update(10));
+        assert!(!window.check_and_update(10));
+        assert!(window.check_and_update(12));
+        assert!(window.check_and_update(11));
+        assert!(!window.check_and_update(11));
+        assert!(window.check_and_update(100));
+        assert!(!window.check_and_update(12));
+        assert!(window.check_and_update(99));
+    }
+
+    #[test]
+    fn test_seal_open() {
+        let data_addr = "10.0.0.1:9908".parse().unwrap();
+        let sender = DataCipher::new();
+        let receiver = DataCipher::new();
+        let frame = b"hello tap";
+
+        sender.install(
+            data_addr,
+            SessionKeys {
+                local_index: 1,
+                remote_index: 2,
+                tx: [7; 32],
+                rx: [8; 32],
+            },
+        );
+        receiver.install(
+            data_addr,
+            SessionKeys {
+                local_index: 2,
+                remote_index: 1,
+                tx: [8; 32],
+                rx: [7; 32],
+            },
+        );
+
+        let mut sealed = Vec::new();
+        let size = sender.seal(&data_addr, frame, &mut sealed).unwrap();
+        assert_eq!(size, frame.len() + OVERHEAD);
+
+        let mut replay = sealed.clone();
+
+        let (size, from) = receiver.open(&mut sealed, 0).unwrap();
+        assert_eq!(from, data_addr);
+        assert_eq!(&sealed[HEADER_LEN..HEADER_LEN + size], frame);
+
+        assert!(matches!(
+            receiver.open(&mut replay, 0),
+            Err(OpenError::Replayed)
+        ));
+
+        let mut tampered = Vec::new();
+        sender.seal(&data_addr, frame, &mut tampered);
+        tampered[HEADER_LEN] ^= 1;
+        assert!(matches!(
+            receiver.open(&mut tampered, 0),
+            Err(OpenError::AuthFailed)
+        ));
+
+        // behind a prefix, which is authenticated too
+        let mut sealed = b"prefix".to_vec();
+        let size = sender.seal(&data_addr, frame, &mut sealed).unwrap();
+        assert_eq!(size, 6 + frame.len() + OVERHEAD);
+
+        let mut tampered = sealed.clone();
+        assert_eq!(receiver.open(&mut sealed, 6).unwrap().0, frame.len());
+        assert_eq!(&sealed[6 + HEADER_LEN..6 + HEADER_LEN + frame.len()], frame);
+
+        tampered[0] ^= 1;
+        assert!(matches!(
+            receiver.open(&mut tampered, 6),
+            Err(OpenError::AuthFailed)
+        ));
+
+        // control message sealed before many frames is not pushed out of the window
+        let mut control = Vec::new();
+        sender.seal_control(&data_addr, b"ping", &mut control);
+        for _ in 0..REPLAY_WINDOW * 2 {
+            let mut sealed = Vec::new();
+            sender.seal(&data_addr, frame, &mut sealed);
+            receiver.open(&mut sealed, 0).unwrap();
+        }
+        assert!(receiver.open(&mut control, 0).is_ok());
+    }
+}
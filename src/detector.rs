@@ -0,0 +1,584 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use log::{debug, info};
+use rand::seq::SliceRandom;
+
+use crate::app::AppState;
+use crate::discovery::sleep_interval;
+use crate::error::{AppResult, TapDemoError};
+use crate::handshake::handshake;
+use crate::membership::{exchange_round, gossip_round};
+use crate::msg::*;
+use crate::peer::{Peer, PeerState};
+use crate::rendezvous::rendezvous_round;
+use crate::routing::routing_round;
+
+/// number of peers asked to probe a peer not answering direct ping
+const INDIRECT_PROBES: usize = 3;
+
+/// peers answering are recorded as seen in state file every this many rounds
+const STORE_REFRESH_ROUNDS: u64 = 360;
+
+/// ping requests of peers handled at once, each waits up to `probe_timeout`
+const PING_REQ_WORKERS: usize = 4;
+
+/// ping requests waiting for a worker, more are dropped
+const PING_REQ_QUEUE: usize = 64;
+
+/// request of peer at `src` to ping `target`, answered through `via` if given
+pub(crate) struct PingReq {
+    pub(crate) target: SocketAddr,
+    pub(crate) src: SocketAddr,
+    pub(crate) via: Option<SocketAddr>,
+}
+
+fn is_pong(msg: &ControlMsg) -> bool {
+    matches!(msg, ControlMsg::Pong)
+}
+
+/// ping `ctl_addr` and wait `timeout` for pong, return round trip time
+pub(crate) fn ping(
+    state: &AppState,
+    ctl_addr: SocketAddr,
+    timeout: Duration,
+) -> AppResult<Duration> {
+    let start = Instant::now();
+    state
+        .ctl
+        .request(ControlMsg::Ping, &[ctl_addr], is_pong, timeout)?;
+
+    Ok(start.elapsed())
+}
+
+/// start workers pinging targets on behalf of peers, return queue of their requests
+pub(crate) fn ping_req_workers(state: &Arc<AppState>) -> SyncSender<PingReq> {
+    let (tx, rx) = mpsc::sync_channel::<PingReq>(PING_REQ_QUEUE);
+    let rx = Arc::new(Mutex::new(rx));
+
+    for _ in 0..PING_REQ_WORKERS {
+        let state = Arc::clone(state);
+        let rx = Arc::clone(&rx);
+
+        std::thread::spawn(move || loop {
+            let req = match rx.lock().unwrap().recv() {
+                Ok(req) => req,
+                Err(_) => return,
+            };
+
+            let timeout = Duration::from_secs(state.config().probe_timeout);
+
+            if ping(&state, req.target, timeout).is_ok() {
+                let _ = state.ctl.send_via(ControlMsg::Pong, req.src, req.via);
+            }
+        });
+    }
+
+    tx
+}
+
+/// ask `helpers` to ping `target`, any pong relayed back means it's alive, return the
+/// helper answered first, which can relay to it
+fn ping_indirect(
+    state: &AppState,
+    target: SocketAddr,
+    helpers: &[SocketAddr],
+    timeout: Duration,
+) -> AppResult<SocketAddr> {
+    if helpers.is_empty() {
+        return Err(TapDemoError::PeerLost);
+    }
+
+    // helpers wait `timeout` for target themselves
+    let (helper, _) = state.ctl.request_via(
+        ControlMsg::PingReq(target),
+        helpers,
+        None,
+        is_pong,
+        timeout * 2,
+    )?;
+
+    Ok(helper)
+}
+
+/// peer to relay to `target`, for node not reached directly, the next hop of its route
+/// if any, else a peer reaching it directly
+pub(crate) fn find_relay(state: &AppState, target: SocketAddr) -> AppResult<SocketAddr> {
+    let next_hop = {
+        let peers = state.peers.read().unwrap();
+        state.routing.next_hop_to(&peers, target)
+    };
+
+    match next_hop {
+        Some(next_hop) if next_hop != target => return Ok(next_hop),
+        _ => {}
+    }
+
+    let helpers: Vec<SocketAddr> = {
+        let peers = state.peers.read().unwrap();
+        peers
+            .iter()
+            .filter(|it| it.ctl_addr != target && it.via.is_none() && it.state == PeerState::Alive)
+            .map(|it| it.ctl_addr)
+            .collect()
+    };
+
+    let helpers: Vec<SocketAddr> = helpers
+        .choose_multiple(&mut rand::thread_rng(), INDIRECT_PROBES)
+        .copied()
+        .collect();
+    let timeout = Duration::from_secs(state.config().probe_timeout);
+
+    ping_indirect(state, target, &helpers, timeout)
+}
+
+/// how peer answered a round of probes
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Probe {
+    Direct,
+    /// only through the peer at this ctl addr
+    Relayed(SocketAddr),
+    Lost,
+}
+
+impl Probe {
+    fn via(self) -> Option<SocketAddr> {
+        match self {
+            Probe::Relayed(via) => Some(via),
+            _ => None,
+        }
+    }
+}
+
+/// swim style failure detector, run by heartbeats thread
+///
+/// a peer answering neither ping nor ping through other peers is suspect, and dead once
+/// suspect for `suspect_timeout`. dead peers are not sent to but still probed, they
+/// handshake again once answering, and are dropped after dead for `peer_expire`.
+///
+/// a peer answering only through another peer is reached through that one, until
+/// answering ping directly again. that's the next hop of its route if any, so it may be
+/// several hops away, else a peer reaching it directly.
+pub(crate) struct FailureDetector {
+    /// when peer became suspect or dead, by ctl addr
+    since: HashMap<SocketAddr, Instant>,
+    /// rounds until peers are recorded in state file again
+    refresh_in: u64,
+}
+
+impl FailureDetector {
+    pub(crate) fn new() -> FailureDetector {
+        FailureDetector {
+            since: HashMap::new(),
+            refresh_in: 0,
+        }
+    }
+
+    /// probe all peers at once, peers lock is only held to copy peers and update states,
+    /// so forwarding goes on while dead peers time out
+    pub(crate) fn round(&mut self, state: &AppState) {
+        let config = state.config();
+        let timeout = Duration::from_secs(config.probe_timeout);
+        let peers = { state.peers.read().unwrap().clone() };
+
+        let answered = probe_all(state, &peers, timeout);
+
+        let now = Instant::now();
+        let suspect_timeout = Duration::from_secs(config.suspect_timeout);
+        let dead_timeout = Duration::from_secs(config.peer_expire);
+        let mut changes = Vec::new();
+        let mut paths = Vec::new();
+        let mut expired = Vec::new();
+        let mut revived = Vec::new();
+
+        self.since
+            .retain(|addr, _| peers.iter().any(|it| it.ctl_addr == *addr));
+
+        for (peer, probe) in peers.iter().zip(answered) {
+            let since = self.since.get(&peer.ctl_addr).copied().unwrap_or(now);
+            let answered = probe != Probe::Lost;
+
+            if answered && probe.via() != peer.via {
+                paths.push((peer.ctl_addr, probe.via()));
+            }
+
+            let next = match (peer.state, answered) {
+                (PeerState::Dead, true) => {
+                    let mut peer = peer.clone();
+                    peer.via = probe.via();
+                    revived.push(peer);
+                    continue;
+                }
+                (_, true) => PeerState::Alive,
+                (PeerState::Alive, false) => PeerState::Suspect,
+                (PeerState::Suspect, false) if now - since >= suspect_timeout => PeerState::Dead,
+                (PeerState::Dead, false) if now - since >= dead_timeout => {
+                    expired.push(peer.ctl_addr);
+                    continue;
+                }
+                (current, false) => current,
+            };
+
+            if next == peer.state {
+                continue;
+            }
+
+            info!("peer {} {}", peer.name, next);
+
+            match next {
+                PeerState::Alive => self.since.remove(&peer.ctl_addr),
+                _ => self.since.insert(peer.ctl_addr, now),
+            };
+
+            changes.push((peer.ctl_addr, peer.state, next));
+        }
+
+        let mut changed = Vec::new();
+
+        if !changes.is_empty() || !paths.is_empty() {
+            let mut peers = state.peers.write().unwrap();
+            let mut fdb = state.fdb.write().unwrap();
+
+            for (ctl_addr, via) in paths {
+                let relay = via.map(|via| {
+                    peers
+                        .iter()
+                        .find(|it| it.ctl_addr == via)
+                        .map(|it| it.name.clone())
+                });
+
+                let peer = peers.iter_mut().find(|it| it.ctl_addr == ctl_addr);
+
+                match (peer, relay) {
+                    // removed meanwhile
+                    (None, _) | (_, Some(None)) => {}
+                    (Some(peer), Some(Some(relay))) => {
+                        info!("peer {} through {}", peer.name, relay);
+                        peer.via = via;
+                    }
+                    (Some(peer), None) => {
+                        info!("peer {} direct", peer.name);
+                        peer.via = None;
+                    }
+                }
+            }
+
+            for (ctl_addr, current, next) in changes {
+                // skip peers changed meanwhile, eg, by handshake
+                let peer = peers
+                    .iter_mut()
+                    .find(|it| it.ctl_addr == ctl_addr && it.state == current);
+
+                if let Some(peer) = peer {
+                    if next == PeerState::Dead {
+                        fdb.forget(&peer.data_addr);
+                    }
+
+                    peer.state = next;
+                    changed.push(peer.clone());
+                }
+            }
+        }
+
+        // spread suspicion and death to other members
+        for peer in changed {
+            state.membership.peer_state_changed(&peer, peer.state);
+        }
+
+        if !expired.is_empty() {
+            state.remove_peers_where(|it| {
+                it.state == PeerState::Dead && expired.contains(&it.ctl_addr)
+            });
+        }
+
+        // restarted peer has lost its session
+        for mut peer in revived {
+            match handshake(state, &mut peer) {
+                Ok(_) => {
+                    info!("peer {} alive", peer.name);
+                    self.since.remove(&peer.ctl_addr);
+                    state.add_peer(peer);
+                }
+                Err(e) => debug!("handshake with dead peer {} failed, {}", peer.name, e),
+            }
+        }
+
+        // long lived peers are not expired from state file, dead ones are left as is
+        if self.refresh_in == 0 {
+            let peers = state.peers.read().unwrap();
+            state.store.update(&peers, &[]);
+            self.refresh_in = STORE_REFRESH_ROUNDS;
+        }
+        self.refresh_in -= 1;
+    }
+}
+
+/// ping all peers, those not answering are pinged again through the next hop of their
+/// route, or through up to `INDIRECT_PROBES` peers that did
+fn probe_all(state: &AppState, peers: &[Peer], timeout: Duration) -> Vec<Probe> {
+    let rtts: Vec<Option<Duration>> = std::thread::scope(|scope| {
+        let probes: Vec<_> = peers
+            .iter()
+            .map(|peer| scope.spawn(move || ping(state, peer.ctl_addr, timeout).ok()))
+            .collect();
+
+        probes
+            .into_iter()
+            .map(|it| it.join().unwrap_or(None))
+            .collect()
+    });
+
+    for (peer, rtt) in peers.iter().zip(&rtts) {
+        match rtt {
+            Some(rtt) => state.routing.link_up(peer.hw_addr, peer.ctl_addr, *rtt),
+            None => state.routing.link_down(&peer.hw_addr),
+        }
+    }
+
+    let direct: Vec<bool> = rtts.iter().map(Option::is_some).collect();
+
+    // only peers reached directly relay
+    let helpers: Vec<SocketAddr> = peers
+        .iter()
+        .zip(&direct)
+        .filter(|(_, answered)| **answered)
+        .map(|(it, _)| it.ctl_addr)
+        .collect();
+
+    std::thread::scope(|scope| {
+        let probes: Vec<_> = peers
+            .iter()
+            .zip(direct)
+            .map(|(peer, answered)| {
+                let helpers = &helpers;
+
+                scope.spawn(move || {
+                    if answered {
+                        return Probe::Direct;
+                    }
+
+                    if let Some(next_hop) = state.routing.next_hop(&peer.hw_addr) {
+                        let reply = state.ctl.request_via(
+                            ControlMsg::Ping,
+                            &[peer.ctl_addr],
+                            Some(next_hop),
+                            is_pong,
+                            timeout * 2,
+                        );
+
+                        if reply.is_ok() {
+                            return Probe::Relayed(next_hop);
+                        }
+                    }
+
+                    let helpers: Vec<SocketAddr> = helpers
+                        .choose_multiple(&mut rand::thread_rng(), INDIRECT_PROBES)
+                        .copied()
+                        .collect();
+
+                    match ping_indirect(state, peer.ctl_addr, &helpers, timeout) {
+                        Ok(helper) => Probe::Relayed(helper),
+                        Err(_) => Probe::Lost,
+                    }
+                })
+            })
+            .collect();
+
+        probes
+            .into_iter()
+            .map(|it| it.join().unwrap_or(Probe::Lost))
+            .collect()
+    })
+}
+
+pub(crate) fn heartbeats_thread(state: Arc<AppState>) -> JoinHandle<()> {
+    debug!("heartbeats_thread start");
+
+    std::thread::spawn(move || {
+        let mut detector = FailureDetector::new();
+
+        loop {
+            detector.round(&state);
+            gossip_round(&state);
+            exchange_round(&state);
+            rendezvous_round(&state);
+            routing_round(&state);
+
+            sleep_interval(&state, |it| it.heartbeat_interval);
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::UdpSocket;
+
+    use bincode::serialize;
+
+    use crate::config::Config;
+    use crate::dispatch::{dispatch_from_peers, dispatch_from_tap};
+    use crate::eth::ETH_HEADER_LEN;
+    use crate::test_util::{connect, control_node, fake_node, frame};
+
+    /// control socket answering ping, and ping request for `relayed` as if reached it
+    fn responder(sock: UdpSocket, relayed: Option<SocketAddr>) -> SocketAddr {
+        let addr = sock.local_addr().unwrap();
+        let pong = serialize(&Msg {
+            inner: ControlMsg::Pong,
+        })
+        .unwrap();
+
+        std::thread::spawn(move || {
+            let mut buff = vec![0; 512];
+
+            while let Ok((size, src)) = sock.recv_from(&mut buff) {
+                let answer = match decode::<Msg>(&buff[..size]).map(|it| it.inner) {
+                    Ok(ControlMsg::Ping) => true,
+                    Ok(ControlMsg::PingReq(target)) => Some(target) == relayed,
+                    _ => false,
+                };
+
+                if answer {
+                    let _ = sock.send_to(&pong, src);
+                }
+            }
+        });
+
+        addr
+    }
+
+    fn silent_peer(state: &AppState, name: &str) -> UdpSocket {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut peer: Peer = format!("{}={}", name, sock.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        // routes are by hw addr
+        peer.hw_addr[..2].copy_from_slice(&sock.local_addr().unwrap().port().to_be_bytes());
+        state.peers.write().unwrap().push(peer);
+
+        sock
+    }
+
+    fn peer_state(state: &AppState, name: &str) -> PeerState {
+        let peers = state.peers.read().unwrap();
+        peers.iter().find(|it| it.name == name).unwrap().state
+    }
+
+    fn peer_via(state: &AppState, name: &str) -> Option<SocketAddr> {
+        let peers = state.peers.read().unwrap();
+        peers.iter().find(|it| it.name == name).unwrap().via
+    }
+
+    fn set_timeouts(state: &AppState, probe_timeout: u64, suspect_timeout: u64) {
+        state.set_config(Config {
+            probe_timeout,
+            suspect_timeout,
+            ..(*state.config()).clone()
+        });
+    }
+
+    #[test]
+    fn test_heartbeats_not_block_forwarding() {
+        let a_mac = [2, 0, 0, 0, 0, 1];
+        let b_mac = [2, 0, 0, 0, 0, 2];
+
+        let (a, a_wire) = control_node("a", a_mac);
+        let (b, b_wire) = fake_node("b", b_mac);
+        connect(&a, &b, 1, 2);
+        connect(&b, &a, 2, 1);
+        a.peers.write().unwrap()[0].ctl_addr =
+            responder(UdpSocket::bind("127.0.0.1:0").unwrap(), None);
+        set_timeouts(&a, 1, 30);
+
+        // peers never answering, kept bound so nothing is refused
+        let _dead: Vec<UdpSocket> = (0..4)
+            .map(|i| silent_peer(&a, &format!("dead-{}", i)))
+            .collect();
+
+        {
+            let a = Arc::clone(&a);
+            std::thread::spawn(move || dispatch_from_tap(a));
+        }
+        {
+            let b = Arc::clone(&b);
+            std::thread::spawn(move || dispatch_from_peers(b));
+        }
+
+        let heartbeats = {
+            let a = Arc::clone(&a);
+            std::thread::spawn(move || {
+                let start = Instant::now();
+                FailureDetector::new().round(&a);
+                start.elapsed()
+            })
+        };
+
+        // frames keep flowing while dead peers time out
+        let mut buff = vec![0; 2048];
+        let mut forwarded = 0;
+        while !heartbeats.is_finished() {
+            let sent = frame(b_mac, a_mac, 60 + ETH_HEADER_LEN);
+            let start = Instant::now();
+            a_wire.send(&sent).unwrap();
+
+            let size = b_wire.recv(&mut buff).unwrap();
+            assert_eq!(&buff[..size], &sent[..]);
+            assert!(start.elapsed() < Duration::from_secs(1));
+
+            forwarded += 1;
+            std::thread::sleep(Duration::from_millis(100));
+        }
+
+        // dead peers time out together, direct then indirect, not one after another
+        let elapsed = heartbeats.join().unwrap();
+        assert!(elapsed < Duration::from_secs(5));
+        assert!(forwarded > 10);
+
+        assert_eq!(peer_state(&a, "b"), PeerState::Alive);
+        assert_eq!(peer_state(&a, "dead-0"), PeerState::Suspect);
+    }
+
+    #[test]
+    fn test_failure_detector() {
+        let (a, _a_wire) = control_node("a", [2, 0, 0, 0, 0, 1]);
+        set_timeouts(&a, 1, 0);
+
+        // c is only reached through b, d never answers, e answers from second round
+        let c = silent_peer(&a, "c");
+        let b = silent_peer(&a, "b");
+        let _d = silent_peer(&a, "d");
+        let e = silent_peer(&a, "e");
+        let b = responder(b, Some(c.local_addr().unwrap()));
+
+        let mut detector = FailureDetector::new();
+        detector.round(&a);
+
+        // seen, though added without state file
+        assert!(a.store.peers().iter().any(|it| it.ctl_addr == b));
+
+        assert_eq!(peer_state(&a, "b"), PeerState::Alive);
+        assert_eq!(peer_state(&a, "c"), PeerState::Alive);
+        assert_eq!(peer_state(&a, "d"), PeerState::Suspect);
+        assert_eq!(peer_state(&a, "e"), PeerState::Suspect);
+        assert_eq!(peer_via(&a, "b"), None);
+        assert_eq!(peer_via(&a, "c"), Some(b));
+
+        responder(e, None);
+        detector.round(&a);
+
+        assert_eq!(peer_state(&a, "c"), PeerState::Alive);
+        assert_eq!(peer_state(&a, "d"), PeerState::Dead);
+        assert_eq!(peer_state(&a, "e"), PeerState::Alive);
+
+        // back to direct path once c answers
+        responder(c, None);
+        detector.round(&a);
+
+        assert_eq!(peer_via(&a, "c"), None);
+    }
+}
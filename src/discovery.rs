@@ -1,82 +1,225 @@
-use std::net::SocketAddr;
-use std::net::{IpAddr, Ipv4Addr};
+use std::io;
+use std::mem::size_of;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV6};
+use std::os::raw::{c_int, c_void};
+use std::os::unix::io::AsRawFd;
 use std::sync::Arc;
 use std::thread::JoinHandle;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use bincode::{deserialize, serialize};
-use lazy_static::lazy_static;
-use log::debug;
+use bincode::serialize;
+use log::{debug, warn};
 
 use crate::app::AppState;
+use crate::config::Config;
+use crate::detector::find_relay;
 use crate::error::{AppResult, TapDemoError};
+use crate::handshake::handshake;
 use crate::msg::*;
-use crate::peer::Peer;
+use crate::netlink::if_index;
+use crate::peer::{Peer, PeerState};
+use crate::stats::Stats;
+use crate::underlay::{bind_socket, canonical};
 
-use socket2::{Domain, Protocol, SockAddr, Socket, Type};
-
-lazy_static! {
-    pub(crate) static ref IPV4: IpAddr = Ipv4Addr::new(224, 0, 0, 100).into();
-}
+use socket2::{SockAddr, Socket};
 
 pub(crate) fn send_msg(msg: Msg, sock: &Socket, addr: &SockAddr) -> std::io::Result<usize> {
     let msg_reply = serialize(&msg).unwrap();
 
-    sock.send_to(&msg_reply, &addr)
+    sock.send_to(&msg_reply, addr)
 }
 
-fn check_peer(peer: &Peer) -> Result<(), TapDemoError> {
-    let sock = new_sender()?;
-    sock.set_write_timeout(Some(Duration::from_secs(5)))?;
+/// socket bound to `port`, recv times out so threads don't block forever
+pub(crate) fn new_socket(port: u16) -> io::Result<Socket> {
+    let socket = bind_socket(port)?;
+    socket.set_read_timeout(Some(Duration::from_secs(5)))?;
 
-    let msg = Msg {
-        inner: ControlMsg::Ping,
-    };
-    let _result = send_msg(msg, &sock, &peer.ctl_addr.into())?;
+    Ok(socket)
+}
 
-    let mut buff = vec![0; 512];
-    let _result = sock.recv(&mut buff)?;
+pub(crate) fn new_sender() -> io::Result<Socket> {
+    new_socket(0)
+}
 
-    let msg: Msg = deserialize(&buff)?;
+fn is_ipv6(sock: &Socket) -> io::Result<bool> {
+    Ok(sock.local_addr()?.as_std().is_some_and(|it| it.is_ipv6()))
+}
 
-    if let ControlMsg::Pong = msg.inner {
-        return Ok(());
+/// port discovery requests are sent to, control port unless given
+pub(crate) fn discovery_port(config: &Config) -> u16 {
+    config.discovery_port.unwrap_or(config.ctl_port)
+}
+
+/// index of `multicast_interface`, 0 to let kernel choose by routes
+fn multicast_if_index(config: &Config) -> io::Result<u32> {
+    match config.multicast_interface {
+        Some(ref name) => if_index(name),
+        None => Ok(0),
     }
+}
+
+/// set ipv4 multicast option taking `ip_mreqn`, which names interface by index
+/// rather than by address as `ip_mreq`
+fn set_mreqn(sock: &Socket, option: c_int, group: Ipv4Addr, index: u32) -> io::Result<()> {
+    let mreqn = libc::ip_mreqn {
+        imr_multiaddr: libc::in_addr {
+            s_addr: u32::from_ne_bytes(group.octets()),
+        },
+        imr_address: libc::in_addr { s_addr: 0 },
+        imr_ifindex: index as c_int,
+    };
 
-    debug!("peer lost!");
+    let rc = unsafe {
+        libc::setsockopt(
+            sock.as_raw_fd(),
+            libc::IPPROTO_IP,
+            option,
+            &mreqn as *const libc::ip_mreqn as *const c_void,
+            size_of::<libc::ip_mreqn>() as libc::socklen_t,
+        )
+    };
 
-    Err(TapDemoError::PeerLost)
+    match rc {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
 }
 
-pub(crate) fn new_socket() -> std::io::Result<Socket> {
-    let socket = Socket::new(Domain::ipv4(), Type::dgram(), Some(Protocol::udp()))?;
-    socket.set_read_timeout(Some(Duration::from_secs(5)))?;
+/// join ipv4 discovery group, and ipv6 one on dual stack socket, on `multicast_interface`
+/// if given. a host without multicast route of either is found over the other only
+pub(crate) fn join_groups(sock: &Socket, config: &Config) -> io::Result<()> {
+    let index = multicast_if_index(config)?;
+    let group = config.multicast_group;
 
-    Ok(socket)
+    if let Err(e) = set_mreqn(sock, libc::IP_ADD_MEMBERSHIP, group, index) {
+        warn!("join {} failed, {}", group, e);
+    }
+
+    if is_ipv6(sock)? {
+        if let Err(e) = sock.join_multicast_v6(&config.multicast_group_v6, index) {
+            warn!("join {} failed, {}", config.multicast_group_v6, e);
+        }
+    }
+
+    Ok(())
 }
 
-pub(crate) fn new_sender() -> std::io::Result<Socket> {
-    let socket = new_socket()?;
+/// discovery groups to send to from `sock`, which is set to send them out of
+/// `multicast_interface` with `multicast_ttl`
+fn groups(sock: &Socket, config: &Config) -> io::Result<Vec<SocketAddr>> {
+    let index = multicast_if_index(config)?;
+    let port = discovery_port(config);
+    let mut groups = vec![SocketAddr::new(config.multicast_group.into(), port)];
+
+    sock.set_multicast_ttl_v4(config.multicast_ttl)?;
+    if index != 0 {
+        set_mreqn(sock, libc::IP_MULTICAST_IF, Ipv4Addr::UNSPECIFIED, index)?;
+    }
 
-    socket.bind(&SockAddr::from(SocketAddr::new(
-        Ipv4Addr::new(0, 0, 0, 0).into(),
-        0,
-    )))?;
+    if is_ipv6(sock)? {
+        sock.set_multicast_hops_v6(config.multicast_ttl)?;
+        sock.set_multicast_if_v6(index)?;
 
-    Ok(socket)
+        groups.push(SocketAddrV6::new(config.multicast_group_v6, port, 0, index).into());
+    }
+
+    Ok(groups)
 }
 
-pub(crate) fn heartbeats_thread(state: Arc<AppState>) -> JoinHandle<()> {
-    debug!("heartbeats_thread start");
+pub(crate) fn discovery_reply(state: &AppState) -> ControlMsg {
+    let config = state.config();
 
-    std::thread::spawn(move || loop {
-        {
-            let mut peers = state.peers.write().unwrap();
+    ControlMsg::DiscoveryReply(MsgDiscoveryReply {
+        name: config.name.clone(),
+        hw_addr: state.hw_addr,
+        data_port: config.data_port,
+        cluster_id: config.cluster_id.clone(),
+    })
+}
 
-            peers.retain(|peer| check_peer(&peer).is_ok());
+/// socket joined to discovery groups on `discovery_port`, none if that is the control
+/// port, whose socket joins them itself
+pub(crate) fn bind_listener(config: &Config) -> io::Result<Option<Socket>> {
+    if discovery_port(config) == config.ctl_port {
+        return Ok(None);
+    }
+
+    let sock = new_socket(discovery_port(config))?;
+    join_groups(&sock, config)?;
+
+    Ok(Some(sock))
+}
+
+/// answer discovery requests received on `sock` from control socket, so the scanning
+/// node sees our control address
+pub(crate) fn listener_thread(state: Arc<AppState>, sock: Socket) -> JoinHandle<()> {
+    debug!("listener_thread start");
+
+    std::thread::spawn(move || {
+        let mut buff = vec![0; MAX_MSG_LEN];
+
+        loop {
+            let (size, src) = match sock.recv_from(&mut buff) {
+                Ok((size, src)) => match src.as_std() {
+                    Some(src) => (size, canonical(src)),
+                    None => continue,
+                },
+                Err(_) => continue,
+            };
+
+            match decode::<Msg>(&buff[..size]).map(|it| it.inner) {
+                Ok(ControlMsg::DiscoveryRequest) => {
+                    let _ = state.ctl.send(discovery_reply(&state), src);
+                }
+                Ok(msg) => {
+                    Stats::incr(&state.stats.ctl_unexpected);
+                    debug!("unexpected msg from {}, {:?}", src, msg);
+                }
+                Err(e) => {
+                    Stats::incr(&state.stats.ctl_malformed);
+                    debug!("drop msg from {}, {}", src, e);
+                }
+            }
         }
+    })
+}
+
+/// sleep for `interval` seconds of config, checked every second so a reload takes
+/// effect without waiting for the old interval
+pub(crate) fn sleep_interval(state: &AppState, interval: fn(&Config) -> u64) {
+    let start = Instant::now();
+
+    while start.elapsed() < Duration::from_secs(interval(&state.config())) {
+        std::thread::sleep(Duration::from_secs(1));
+    }
+}
+
+/// peer answered discovery from `ctl_addr`, none for ourselves and nodes of other
+/// clusters
+fn discovered(state: &AppState, reply: MsgDiscoveryReply, ctl_addr: SocketAddr) -> Option<Peer> {
+    let config = state.config();
 
-        std::thread::sleep(Duration::from_secs(120));
+    if reply.name == config.name || reply.data_port == 0 {
+        return None;
+    }
+
+    if reply.cluster_id != config.cluster_id {
+        debug!("ignore {} of cluster {}", reply.name, reply.cluster_id);
+        return None;
+    }
+
+    let mut data_addr = ctl_addr;
+    data_addr.set_port(reply.data_port);
+
+    Some(Peer {
+        name: reply.name,
+        ctl_addr,
+        data_addr,
+        hw_addr: reply.hw_addr,
+        public_key: [0; 32],
+        ip_addr: None,
+        state: PeerState::Alive,
+        via: None,
     })
 }
 
@@ -89,7 +232,15 @@ pub(crate) fn scan_node(state: Arc<AppState>) -> AppResult<Vec<Peer>> {
     };
     let req = serialize(&req)?;
 
-    sock.send_to(&req, &SockAddr::from(SocketAddr::new(*IPV4, 9909)))?;
+    // either group may have no route, eg, ipv6 only hosts
+    let sent: Vec<io::Result<usize>> = groups(&sock, &state.config())?
+        .into_iter()
+        .map(|group| sock.send_to(&req, &SockAddr::from(group)))
+        .collect();
+
+    if sent.iter().all(|it| it.is_err()) {
+        return Err(sent.into_iter().find_map(|it| it.err()).unwrap().into());
+    }
 
     let mut buff = vec![0; 512];
 
@@ -97,28 +248,32 @@ pub(crate) fn scan_node(state: Arc<AppState>) -> AppResult<Vec<Peer>> {
         let size_and_addr = sock.recv_from(&mut buff);
 
         match size_and_addr {
-            Ok((_size, addr)) => {
-                let msg: Msg = deserialize(&buff)?;
-
-                match msg.inner {
-                    ControlMsg::DiscoveryReply(reply) => {
-                        if reply.name == state.name {
-                            continue;
-                        }
-
-                        let mut data_addr = SocketAddr::V4(addr.as_inet().unwrap());
-                        data_addr.set_port(data_addr.port() - 1);
-
-                        let peer = Peer {
-                            name: reply.name,
-                            ctl_addr: SocketAddr::V4(addr.as_inet().unwrap()),
-                            data_addr,
-                            hw_addr: reply.hw_addr,
-                        };
-
-                        peers.push(peer);
+            Ok((size, addr)) => {
+                let msg: Msg = match decode(&buff[..size]) {
+                    Ok(msg) => msg,
+                    Err(e) => {
+                        Stats::incr(&state.stats.ctl_malformed);
+                        debug!("drop msg from {:?}, {}", addr, e);
+                        continue;
                     }
-                    _ => unreachable!(),
+                };
+
+                let reply = match msg.inner {
+                    ControlMsg::DiscoveryReply(reply) => reply,
+                    msg => {
+                        Stats::incr(&state.stats.ctl_unexpected);
+                        debug!("unexpected msg from {:?}, {:?}", addr, msg);
+                        continue;
+                    }
+                };
+
+                let ctl_addr = match addr.as_std() {
+                    Some(ctl_addr) => canonical(ctl_addr),
+                    None => continue,
+                };
+
+                if let Some(peer) = discovered(&state, reply, ctl_addr) {
+                    peers.push(peer);
                 }
             }
             Err(err) => {
@@ -149,68 +304,181 @@ pub(crate) fn discovery_thread(state: Arc<AppState>) -> JoinHandle<()> {
             };
 
             if let Ok(peers) = peers {
+                let peers = connect_peers(&state, peers);
                 state.add_peers(peers);
             }
         }
 
-        std::thread::sleep(Duration::from_secs(60));
+        sleep_interval(&state, |it| it.discovery_interval);
     })
 }
 
-pub(crate) fn init_peer_hw_addr(peer: &mut Peer) -> AppResult<()> {
-    if peer.hw_addr != [0; 6] {
+/// handshake with peer, which also learns peer's hw addr, through a peer reaching it
+/// if not answering directly
+pub(crate) fn init_peer_hw_addr(state: &AppState, peer: &mut Peer) -> AppResult<()> {
+    if peer.public_key != [0; 32] && state.cipher.has_session(&peer.data_addr) {
         return Ok(());
     }
 
-    let sock = new_sender()?;
-    sock.set_read_timeout(Some(Duration::from_secs(5)))?;
+    match handshake(state, peer) {
+        // no answer, or no route at all
+        Err(e @ TapDemoError::NoReply) | Err(e @ TapDemoError::IOError(_))
+            if peer.via.is_none() =>
+        {
+            peer.via = Some(find_relay(state, peer.ctl_addr).map_err(|_| e)?);
 
-    let msg = Msg {
-        inner: ControlMsg::HwAddrRequest,
-    };
+            let result = handshake(state, peer);
+            if result.is_err() {
+                peer.via = None;
+            }
 
-    let peer_ctl_addr = peer.ctl_addr.clone();
-    send_msg(msg, &sock, &SockAddr::from(peer_ctl_addr))?;
+            result
+        }
+        result => result,
+    }
+}
 
-    let mut buff = vec![0; 512];
-    let _size = sock.recv(&mut buff)?;
+/// handshake with new peers, return those succeed
+pub(crate) fn connect_peers(state: &AppState, peers: Vec<Peer>) -> Vec<Peer> {
+    let (known, connected): (Vec<SocketAddr>, Vec<[u8; 6]>) = {
+        let peers = state.peers.read().unwrap();
+        let connected = peers
+            .iter()
+            .filter(|it| state.cipher.has_session(&it.data_addr))
+            .map(|it| it.hw_addr)
+            .collect();
+
+        (peers.iter().map(|it| it.ctl_addr).collect(), connected)
+    };
 
-    let msg: Msg = deserialize(&buff)?;
+    peers
+        .into_iter()
+        .filter_map(|mut peer| {
+            if known.contains(&peer.ctl_addr) && state.cipher.has_session(&peer.data_addr) {
+                return None;
+            }
 
-    match msg.inner {
-        ControlMsg::HwAddrReply(hw_addr) => {
-            peer.hw_addr = hw_addr;
-            Ok(())
-        }
-        _ => Err(TapDemoError::GetHWAddrError),
-    }
+            // node with several addresses answered scan from another one, eg, ipv4 and
+            // ipv6 or link-local one
+            if peer.hw_addr != [0; 6] && connected.contains(&peer.hw_addr) {
+                return None;
+            }
+
+            match init_peer_hw_addr(state, &mut peer) {
+                Ok(_) => Some(peer),
+                Err(e) => {
+                    debug!("connect {} failed, {}", peer.name, e);
+                    None
+                }
+            }
+        })
+        .collect()
 }
 
-pub(crate) fn init_peers_hw_addr(state: Arc<AppState>) {
+/// handshake with `peers` given at start, and add them once succeed
+pub(crate) fn init_peers_hw_addr(state: Arc<AppState>, peers: Vec<Peer>) {
     debug!("init peers hw addr...");
-    let mut peers = state.peers.write().unwrap();
+    let mut uninitialized = Vec::new();
 
-    for peer in &mut *peers {
-        if peer.hw_addr != [0; 6] {
-            continue;
+    for mut peer in peers {
+        match init_peer_hw_addr(&state, &mut peer) {
+            Ok(_) => {
+                state.add_peer(peer);
+            }
+            Err(_) => uninitialized.push(peer),
         }
-
-        let _result = init_peer_hw_addr(peer);
     }
 
-    // check whether all peers are initialized
-    let first_uninitialized = peers.iter().find(|it| it.hw_addr == [0; 6]);
+    uninitialized.retain(|peer| is_wanted(&state, peer));
 
-    if first_uninitialized.is_some() {
+    // check whether all peers are initialized
+    if !uninitialized.is_empty() {
         // schedule next init
         debug!("schedule for next init");
         let state = state.clone();
         std::thread::spawn(move || {
             std::thread::sleep(Duration::from_secs(15));
 
-            init_peers_hw_addr(state);
+            init_peers_hw_addr(state, uninitialized);
         });
     }
 
     debug!("init done");
 }
+
+/// whether `peer` failed to init is retried, peers removed from config by reload, or
+/// expired from state file, are not. by address as reload does, name of peer is the one
+/// it gives in handshake
+fn is_wanted(state: &AppState, peer: &Peer) -> bool {
+    let configured = state.config().static_peers().unwrap_or_default();
+
+    configured.iter().any(|it| it.ctl_addr == peer.ctl_addr) || state.store.contains(&peer.ctl_addr)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::UdpSocket;
+
+    use crate::config::{Config, PeerConfig};
+    use crate::test_util::fake_node;
+
+    #[test]
+    fn test_discovery_listener() {
+        let (a, _a_wire) = fake_node("a", [2, 0, 0, 0, 0, 1]);
+        let (b, _b_wire) = fake_node("b", [2, 0, 0, 0, 0, 2]);
+
+        // discovery on its own port, answered from control port
+        let listener = new_socket(0).unwrap();
+        let port = listener.local_addr().unwrap().as_std().unwrap().port();
+        listener_thread(Arc::clone(&b), listener);
+
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let req = serialize(&Msg {
+            inner: ControlMsg::DiscoveryRequest,
+        })
+        .unwrap();
+        sock.send_to(&req, ("127.0.0.1", port)).unwrap();
+
+        let mut buff = [0; MAX_MSG_LEN];
+        let (size, src) = sock.recv_from(&mut buff).unwrap();
+        assert_eq!(src.port(), b.config().ctl_port);
+
+        let reply = match decode::<Msg>(&buff[..size]).unwrap().inner {
+            ControlMsg::DiscoveryReply(reply) => reply,
+            msg => panic!("unexpected {:?}", msg),
+        };
+        assert_eq!(reply.cluster_id, "default");
+
+        let peer = discovered(&a, reply.clone(), src).unwrap();
+        assert_eq!(peer.data_addr.port(), b.config().data_port);
+        assert_eq!(peer.hw_addr, b.hw_addr);
+
+        // ourselves, and node of another overlay on the same lan
+        assert!(discovered(&b, reply.clone(), src).is_none());
+        let other = MsgDiscoveryReply {
+            cluster_id: "other".to_owned(),
+            ..reply
+        };
+        assert!(discovered(&a, other, src).is_none());
+    }
+
+    #[test]
+    fn test_is_wanted() {
+        let (a, _a_wire) = fake_node("a", [2, 0, 0, 0, 0, 1]);
+        a.set_config(Config {
+            peers: vec![PeerConfig {
+                name: "b".to_owned(),
+                addr: "127.0.0.2:9909".to_owned(),
+            }],
+            ..(*a.config()).clone()
+        });
+
+        // renamed peer is still retried, another one of the same name is not
+        let renamed: Peer = "node-b=127.0.0.2:9909".parse().unwrap();
+        let moved: Peer = "b=127.0.0.3:9909".parse().unwrap();
+        assert!(is_wanted(&a, &renamed));
+        assert!(!is_wanted(&a, &moved));
+    }
+}
@@ -1,51 +1,957 @@
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
 
 use crate::app::AppState;
+use crate::config::{Config, Encapsulation};
+use crate::crypto::{DataCipher, OpenError, HEADER_LEN, OVERHEAD};
 use crate::error::TapDemoError;
-use crate::eth::EthV2;
+use crate::eth::{EthV2, ETH_HEADER_LEN};
+use crate::geneve::{self, Metadata};
+use crate::packet::{PacketHeader, PacketType, PACKET_HEADER_LEN};
+use crate::peer::{Peer, PeerState};
+use crate::routing::{next_hop, MAX_HOPS};
+use crate::stats::Stats;
+use crate::underlay::canonical;
+use crate::vxlan;
 
-use log::error;
-use std::io::Write;
+use log::{debug, error};
+use std::io::{Read, Write};
 
-pub(crate) struct DispatchRoutine(pub(crate) Arc<AppState>);
+/// underlay ipv4 and udp header, packet header, our header and tag, and ethernet header
+/// of frame, with another packet header and relay header of frames relayed through a
+/// peer, ipv6 underlay 20 more
+pub(crate) const TUNNEL_OVERHEAD: usize =
+    20 + 8 + PACKET_HEADER_LEN + RELAY_HEADER_LEN + PACKET_HEADER_LEN + OVERHEAD + ETH_HEADER_LEN;
+
+/// ttl, then hw addr of node sent the frame and of node it's for, behind packet header
+/// of relay type
+const RELAY_HEADER_LEN: usize = 1 + 6 + 6;
+
+/// tap mtu which fits in 1500 bytes underlay without fragmentation
+pub(crate) const DEFAULT_MTU: usize = 1500 - TUNNEL_OVERHEAD;
+
+pub(crate) const MIN_MTU: usize = 68;
+
+/// largest udp payload over ipv4
+const MAX_DATAGRAM_LEN: usize = 65507;
+
+/// relayed frames included
+pub(crate) const MAX_MTU: usize = MAX_DATAGRAM_LEN
+    - PACKET_HEADER_LEN
+    - RELAY_HEADER_LEN
+    - PACKET_HEADER_LEN
+    - OVERHEAD
+    - ETH_HEADER_LEN;
+
+/// dispatch frames read from tap, the buffer holds frame sealed for each peer
+pub(crate) struct DispatchRoutine(pub(crate) Arc<AppState>, Vec<u8>);
 
 impl DispatchRoutine {
+    pub(crate) fn new(state: Arc<AppState>) -> DispatchRoutine {
+        let capacity = state.config().mtu + ETH_HEADER_LEN + PACKET_HEADER_LEN + OVERHEAD;
+
+        DispatchRoutine(state, Vec::with_capacity(capacity))
+    }
+
     /// dispatch packet to peers
-    pub(crate) fn dispatch_to_peers(&self, eth: EthV2) -> Result<(), TapDemoError> {
-        let peers = self.0.peers.read().unwrap();
-        // for brd
-        if eth.dst_mac == [255, 255, 255, 255, 255, 255] {
-            for peer in &*peers {
-                // don't send to self
-                if peer.hw_addr == self.0.hw_addr {
-                    continue;
-                }
-                let _result = self.0.data_sock.send_to(&eth.data, &peer.data_addr)?;
+    pub(crate) fn dispatch_to_peers(&mut self, eth: EthV2) -> Result<(), TapDemoError> {
+        let state = &self.0;
+        let buff = &mut self.1;
+        let peers = state.peers.read().unwrap();
+
+        // for brd and multicast
+        if eth.dst_mac[0] & 0x01 != 0 {
+            if !state.storm.admit(state.config().broadcast_limit) {
+                Stats::incr(&state.stats.storm_dropped);
+                return Ok(());
             }
-        } else {
-            let peer = peers.iter().find(|&it| it.hw_addr == eth.dst_mac);
 
-            match peer {
-                Some(peer) => {
-                    self.0.data_sock.send_to(&eth.data, &peer.data_addr)?;
-                }
+            // sent by us, or received from a peer, and back through a bridged lan
+            if !state.storm.first_seen(eth.data) {
+                Stats::incr(&state.stats.looped);
+                debug!("drop looped frame from {:x?}", eth.src_mac);
+                return Ok(());
+            }
+
+            return flood(state, buff, &peers, &eth);
+        }
+
+        let peer = peers.iter().find(|&it| it.hw_addr == eth.dst_mac);
+
+        if let Some(peer) = peer {
+            if peer.state == PeerState::Dead {
+                debug!("peer {} is dead, drop", peer.name);
+                return Ok(());
+            }
+
+            return send_to_peer(state, buff, &peers, &peer.data_addr, &eth);
+        }
+
+        // mac behind peer's tap, eg, vm or container bridged to tap0
+        let learned = { state.fdb.read().unwrap().lookup(&eth.dst_mac) };
+
+        match learned {
+            Some(data_addr) => send_to_peer(state, buff, &peers, &data_addr, &eth),
+            None => {
+                debug!(
+                    "unknown dst {:x?}, proto {:#06x}, flood",
+                    eth.dst_mac, eth.proto_type
+                );
+
+                flood(state, buff, &peers, &eth)
+            }
+        }
+    }
+}
+
+/// seal packet with session of peer at `data_addr`, or put it in vxlan or geneve, and
+/// send, through the peer relaying to it if not reached directly
+fn send_to_peer(
+    state: &AppState,
+    buff: &mut Vec<u8>,
+    peers: &[Peer],
+    data_addr: &SocketAddr,
+    eth: &EthV2,
+) -> Result<(), TapDemoError> {
+    let config = state.config();
+
+    let size = match config.encapsulation {
+        Encapsulation::Native => {
+            match seal_frame(&state.cipher, config.network_id, data_addr, eth.data, buff) {
+                Some(size) => size,
                 None => {
-                    error!("unknown dst {:x?}", eth.dst_mac);
+                    debug!("no session with {}, drop", data_addr);
+                    return Ok(());
                 }
             }
         }
+        Encapsulation::Vxlan => vxlan::encap(config.vni, eth.data, buff),
+        Encapsulation::Geneve => {
+            let metadata = Metadata {
+                network_id: Some(config.network_id),
+                sender: Some(sender_hint(&state.handshake.identity.public_key)),
+                hops: Some(MAX_HOPS),
+            };
+
+            geneve::encap(config.vni, &metadata, eth.data, buff)
+        }
+    };
+
+    let peer = peers.iter().find(|it| it.data_addr == *data_addr);
+
+    let (peer, via) = match peer.and_then(|it| it.via.map(|via| (it, via))) {
+        Some(relayed) => relayed,
+        None => {
+            state.data_sock.send_to(&buff[..size], data_addr)?;
+            return Ok(());
+        }
+    };
+
+    // only native frames carry relay header
+    if config.encapsulation != Encapsulation::Native {
+        debug!("{} only reached through relay, drop", peer.name);
+        return Ok(());
+    }
+
+    let relay = peers
+        .iter()
+        .find(|it| it.ctl_addr == via && it.state != PeerState::Dead);
+
+    match relay {
+        Some(relay) => {
+            let header = relay_header(config.network_id, &state.hw_addr, &peer.hw_addr);
+            buff.splice(..0, header);
+            state.data_sock.send_to(buff, relay.data_addr)?;
+        }
+        None => debug!("no relay to {}, drop", peer.name),
+    }
+
+    Ok(())
+}
 
-        Ok(())
+/// leading bytes of public key, sent in geneve option to tell nodes apart behind the
+/// same address
+fn sender_hint(public_key: &[u8; 32]) -> [u8; 8] {
+    let mut hint = [0; 8];
+    hint.copy_from_slice(&public_key[..8]);
+
+    hint
+}
+
+/// packet header of relay type and relay header, put in front of data packet
+fn relay_header(
+    network_id: u32,
+    src: &[u8; 6],
+    dst: &[u8; 6],
+) -> [u8; PACKET_HEADER_LEN + RELAY_HEADER_LEN] {
+    let mut header = [0; PACKET_HEADER_LEN + RELAY_HEADER_LEN];
+    let (packet, relay) = header.split_at_mut(PACKET_HEADER_LEN);
+    packet.copy_from_slice(&PacketHeader::new(PacketType::Relay, network_id).encode());
+    relay[0] = MAX_HOPS;
+    relay[1..7].copy_from_slice(src);
+    relay[7..].copy_from_slice(dst);
+
+    header
+}
+
+/// relay header, behind packet header of relay type
+pub(crate) struct RelayHeader {
+    pub(crate) ttl: u8,
+    /// hw addr of node sent the frame
+    pub(crate) src: [u8; 6],
+    /// hw addr of node the frame is for
+    pub(crate) dst: [u8; 6],
+}
+
+impl RelayHeader {
+    /// relay header of relay packet `data`, which starts with packet header
+    pub(crate) fn parse(data: &[u8]) -> Result<RelayHeader, OpenError> {
+        let header = data
+            .get(PACKET_HEADER_LEN..PACKET_HEADER_LEN + RELAY_HEADER_LEN)
+            .ok_or(OpenError::Malformed)?;
+
+        let mut src = [0; 6];
+        let mut dst = [0; 6];
+        src.copy_from_slice(&header[1..7]);
+        dst.copy_from_slice(&header[7..]);
+
+        Ok(RelayHeader {
+            ttl: header[0],
+            src,
+            dst,
+        })
     }
 }
 
+/// handle relay packet from `src_addr`, pass it on to next hop towards the node it's
+/// for, return whether it's for this node
+fn relayed(state: &AppState, data: &mut [u8], src_addr: SocketAddr) -> bool {
+    let RelayHeader { ttl, src, dst } = match RelayHeader::parse(data) {
+        Ok(header) => header,
+        Err(_) => {
+            Stats::incr(&state.stats.malformed);
+            return false;
+        }
+    };
+    let peers = state.peers.read().unwrap();
+
+    // each hop of the path reaches the next directly
+    let sender = peers
+        .iter()
+        .find(|it| it.data_addr == src_addr && it.via.is_none());
+
+    let sender = match sender {
+        Some(sender) => sender,
+        None => {
+            debug!("drop relayed frame from {}, not a peer", src_addr);
+            return false;
+        }
+    };
+
+    // the peer sent it is known by its session once opened, not by what header says
+    if dst == state.hw_addr {
+        return true;
+    }
+
+    if ttl <= 1 {
+        Stats::incr(&state.stats.ttl_expired);
+        debug!("drop frame of {:x?} to {:x?}, ttl expired", src, dst);
+        return false;
+    }
+
+    match next_hop(state, &peers, &dst) {
+        Some(next) if state.config().relay && next.data_addr != src_addr => {
+            data[PACKET_HEADER_LEN] = ttl - 1;
+            let _ = state.data_sock.send_to(data, next.data_addr);
+            Stats::incr(&state.stats.relayed);
+        }
+        _ => debug!(
+            "drop frame of {:x?} from {} to relay to {:x?}",
+            src, sender.name, dst
+        ),
+    }
+
+    false
+}
+
+/// send packet to all peers
+fn flood(
+    state: &AppState,
+    buff: &mut Vec<u8>,
+    peers: &[Peer],
+    eth: &EthV2,
+) -> Result<(), TapDemoError> {
+    for peer in peers {
+        // don't send to self
+        if peer.hw_addr == state.hw_addr || peer.state == PeerState::Dead {
+            continue;
+        }
+        send_to_peer(state, buff, peers, &peer.data_addr, eth)?;
+    }
+
+    // vteps not running handshake, eg, linux vxlan interfaces
+    let config = state.config();
+    for ip in &config.remotes {
+        let data_addr = SocketAddr::new(*ip, config.data_port);
+
+        if !peers.iter().any(|it| it.data_addr == data_addr) {
+            send_to_peer(state, buff, peers, &data_addr, eth)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// read frames from tap and send them to peers
+pub(crate) fn dispatch_from_tap(state: Arc<AppState>) {
+    // larger than mtu, so frames sent after mtu of tap is raised are seen and dropped
+    let mut buff = vec![0; MAX_DATAGRAM_LEN];
+    let max_frame_len = state.config().mtu + ETH_HEADER_LEN;
+    let mut dispatch_routine = DispatchRoutine::new(state.clone());
+    let mut tap_dev = &state.tap_dev;
+
+    loop {
+        let size = match tap_dev.read(&mut buff) {
+            Ok(size) => size,
+            Err(_) => continue,
+        };
+
+        if size > max_frame_len {
+            Stats::incr(&state.stats.oversized);
+            debug!(
+                "drop frame of {} bytes from tap, mtu {}",
+                size,
+                state.config().mtu
+            );
+            continue;
+        }
+
+        let eth = match EthV2::parse(&buff[..size]) {
+            Some(eth) => eth,
+            None => continue,
+        };
+
+        let result = dispatch_routine.dispatch_to_peers(eth);
+
+        if let Err(e) = result {
+            error!("error dispatch to peers, {:?}", e);
+        }
+    }
+}
+
+/// data packet of `frame` sealed for peer at `data_addr` into `out`, return its
+/// length, `None` if no session with that peer
+pub(crate) fn seal_frame(
+    cipher: &DataCipher,
+    network_id: u32,
+    data_addr: &SocketAddr,
+    frame: &[u8],
+    out: &mut Vec<u8>,
+) -> Option<usize> {
+    out.clear();
+    out.extend_from_slice(&PacketHeader::new(PacketType::Data, network_id).encode());
+
+    cipher.seal(data_addr, frame, out)
+}
+
+/// open data packet received from peer, return frame with data addr of the peer sealed
+/// it, packets of other types and frames too short for ethernet header are malformed
+pub(crate) fn open_frame<'a>(
+    cipher: &DataCipher,
+    network_id: u32,
+    data: &'a mut [u8],
+) -> Result<(EthV2<'a>, SocketAddr), OpenError> {
+    if PacketHeader::parse(data, network_id)?.kind != PacketType::Data {
+        return Err(OpenError::Malformed);
+    }
+
+    let (size, data_addr) = cipher.open(data, PACKET_HEADER_LEN)?;
+    let data: &'a [u8] = data;
+    let start = PACKET_HEADER_LEN + HEADER_LEN;
+
+    let eth = EthV2::parse(&data[start..start + size]).ok_or(OpenError::Malformed)?;
+
+    Ok((eth, data_addr))
+}
+
+/// frame of datagram from `src_addr`, with data addr of the node sent it, `None` if
+/// a keepalive, passed on to another peer or dropped by relay
+fn receive_frame<'a>(
+    state: &AppState,
+    config: &Config,
+    data: &'a mut [u8],
+    src_addr: SocketAddr,
+) -> Option<Result<(EthV2<'a>, SocketAddr), OpenError>> {
+    // linux vxlan and geneve send from any port, and listen on the one they send to
+    let origin = SocketAddr::new(src_addr.ip(), config.data_port);
+
+    match config.encapsulation {
+        Encapsulation::Native => {}
+        Encapsulation::Vxlan => {
+            let eth = vxlan::decap(data, config.vni)
+                .and_then(|frame| EthV2::parse(frame).ok_or(OpenError::Malformed));
+
+            return Some(eth.map(|it| (it, origin)));
+        }
+        Encapsulation::Geneve => {
+            return Some(geneve::decap(data, config.vni).and_then(|packet| {
+                check_geneve(state, config, packet.metadata, origin)?;
+                let eth = EthV2::parse(packet.frame).ok_or(OpenError::Malformed)?;
+
+                Ok((eth, origin))
+            }));
+        }
+    }
+
+    let header = match PacketHeader::parse(data, config.network_id) {
+        Ok(header) => header,
+        Err(e) => return Some(Err(e)),
+    };
+
+    // either way the frame is from the peer whose session opens it
+    let start = match header.kind {
+        PacketType::Data => 0,
+        PacketType::Relay if relayed(state, data, src_addr) => PACKET_HEADER_LEN + RELAY_HEADER_LEN,
+        PacketType::Relay => return None,
+        // of peer behind nat
+        PacketType::Keepalive => return None,
+    };
+
+    Some(open_frame(
+        &state.cipher,
+        config.network_id,
+        &mut data[start..],
+    ))
+}
+
+/// check options of geneve frame from `origin`. options are not authenticated, so the
+/// frame is learned at `origin` whatever sender option says, it's only checked against
+/// the peer there
+fn check_geneve(
+    state: &AppState,
+    config: &Config,
+    metadata: Metadata,
+    origin: SocketAddr,
+) -> Result<(), OpenError> {
+    if metadata
+        .network_id
+        .is_some_and(|it| it != config.network_id)
+    {
+        return Err(OpenError::WrongNetwork);
+    }
+
+    if metadata.hops == Some(0) {
+        return Err(OpenError::TtlExpired);
+    }
+
+    let hint = match metadata.sender {
+        Some(hint) => hint,
+        None => return Ok(()),
+    };
+
+    let peers = state.peers.read().unwrap();
+    let mut at_origin = peers
+        .iter()
+        .filter(|it| it.data_addr == origin && it.public_key != [0; 32])
+        .peekable();
+
+    // unknown address, or one of the nodes behind it
+    if at_origin.peek().is_none() || at_origin.any(|it| sender_hint(&it.public_key) == hint) {
+        return Ok(());
+    }
+
+    Err(OpenError::WrongSender)
+}
+
 pub(crate) fn dispatch_from_peers(state: Arc<AppState>) {
     let data_sock = &state.data_sock;
-    let mut buff = vec![0; 1500];
+    let mut buff = vec![0; MAX_DATAGRAM_LEN];
+    let max_frame_len = state.config().mtu + ETH_HEADER_LEN;
     let mut tap_dev = &state.tap_dev;
 
     loop {
-        let _result = data_sock.recv(&mut buff);
-        let _result = tap_dev.write(&buff);
+        let result = data_sock.recv_from(&mut buff);
+
+        let (size, src_addr) = match result {
+            Ok((size, src_addr)) => (size, canonical(src_addr)),
+            Err(_) => continue,
+        };
+
+        let config = state.config();
+
+        let (eth, src_addr) = match receive_frame(&state, &config, &mut buff[..size], src_addr) {
+            Some(Ok(received)) => received,
+            None => continue,
+            Some(Err(e)) => {
+                let counter = match e {
+                    OpenError::Malformed => &state.stats.malformed,
+                    OpenError::UnknownSession => &state.stats.unknown_session,
+                    OpenError::AuthFailed => &state.stats.auth_failed,
+                    OpenError::Replayed => &state.stats.replayed,
+                    OpenError::WrongNetwork => &state.stats.wrong_network,
+                    OpenError::BadMagic => &state.stats.bad_magic,
+                    OpenError::WrongVersion => &state.stats.wrong_version,
+                    OpenError::UnknownType => &state.stats.unknown_type,
+                    OpenError::UnknownOption => &state.stats.unknown_option,
+                    OpenError::WrongSender => &state.stats.wrong_sender,
+                    OpenError::TtlExpired => &state.stats.ttl_expired,
+                };
+                Stats::incr(counter);
+
+                debug!("drop frame from {}, {:?}", src_addr, e);
+                continue;
+            }
+        };
+
+        // sent by peer with larger mtu
+        if eth.data.len() > max_frame_len {
+            Stats::incr(&state.stats.oversized);
+            debug!("drop frame of {} bytes from {}", eth.data.len(), src_addr);
+            continue;
+        }
+
+        // copy of a frame flooded by another node too, eg, from a lan bridged to both,
+        // it's neither written to tap nor learned
+        if eth.dst_mac[0] & 0x01 != 0 && !state.storm.first_seen(eth.data) {
+            Stats::incr(&state.stats.looped);
+            debug!("drop looped frame from {}", src_addr);
+            continue;
+        }
+
+        state.fdb.write().unwrap().learn(eth.src_mac, src_addr);
+
+        let _result = tap_dev.write(eth.data);
+    }
+}
+
+pub(crate) fn fdb_ageing_thread(state: Arc<AppState>) -> JoinHandle<()> {
+    debug!("fdb_ageing_thread start");
+
+    std::thread::spawn(move || loop {
+        {
+            state.fdb.write().unwrap().expire();
+        }
+
+        std::thread::sleep(Duration::from_secs(30));
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::UdpSocket;
+
+    use crate::handshake::handshake;
+    use crate::test_util::{connect, control_node, fake_node, frame};
+
+    #[test]
+    fn test_frame_length() {
+        let a_mac = [2, 0, 0, 0, 0, 1];
+        let b_mac = [2, 0, 0, 0, 0, 2];
+
+        let (a, a_wire) = fake_node("a", a_mac);
+        let (b, b_wire) = fake_node("b", b_mac);
+        connect(&a, &b, 1, 2);
+        connect(&b, &a, 2, 1);
+
+        for state in [&a, &b].iter() {
+            let state = Arc::clone(state);
+            std::thread::spawn(move || dispatch_from_tap(state));
+        }
+        for state in [&a, &b].iter() {
+            let state = Arc::clone(state);
+            std::thread::spawn(move || dispatch_from_peers(state));
+        }
+
+        let max_frame_len = DEFAULT_MTU + ETH_HEADER_LEN;
+        let mut buff = vec![0; max_frame_len * 2];
+
+        // shortest frame, a small one after a full sized one, and a full sized one
+        for len in [60, max_frame_len, 98, max_frame_len].iter() {
+            let sent = frame(b_mac, a_mac, *len);
+            a_wire.send(&sent).unwrap();
+
+            let size = b_wire.recv(&mut buff).unwrap();
+            assert_eq!(&buff[..size], &sent[..]);
+
+            let sent = frame(a_mac, b_mac, *len);
+            b_wire.send(&sent).unwrap();
+
+            let size = a_wire.recv(&mut buff).unwrap();
+            assert_eq!(&buff[..size], &sent[..]);
+        }
+
+        // oversized frame is dropped, the next one still arrives
+        a_wire
+            .send(&frame(b_mac, a_mac, max_frame_len + 1))
+            .unwrap();
+        let sent = frame(b_mac, a_mac, 60);
+        a_wire.send(&sent).unwrap();
+
+        let size = b_wire.recv(&mut buff).unwrap();
+        assert_eq!(&buff[..size], &sent[..]);
+        assert_eq!(a.stats.snapshot().oversized, 1);
+
+        // full sized frame fits 1500 bytes underlay, relayed too
+        let mut sealed = Vec::new();
+        let size = seal_frame(
+            &a.cipher,
+            a.config().network_id,
+            &b.data_sock.local_addr().unwrap(),
+            &frame(b_mac, a_mac, max_frame_len),
+            &mut sealed,
+        )
+        .unwrap();
+        let header = relay_header(a.config().network_id, &a_mac, &b_mac);
+        assert_eq!(20 + 8 + header.len() + size, 1500);
+    }
+
+    #[test]
+    fn test_broadcast_loop() {
+        let a_mac = [2, 0, 0, 0, 0, 1];
+        let b_mac = [2, 0, 0, 0, 0, 2];
+        let brd = [0xff; 6];
+
+        let (a, a_wire) = fake_node("a", a_mac);
+        let (b, b_wire) = fake_node("b", b_mac);
+        connect(&a, &b, 1, 2);
+        connect(&b, &a, 2, 1);
+
+        for state in [&a, &b].iter() {
+            let state = Arc::clone(state);
+            std::thread::spawn(move || dispatch_from_tap(state));
+        }
+        for state in [&a, &b].iter() {
+            let state = Arc::clone(state);
+            std::thread::spawn(move || dispatch_from_peers(state));
+        }
+
+        let mut buff = vec![0; 2048];
+        a_wire
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+
+        let sent = frame(brd, a_mac, 60);
+        a_wire.send(&sent).unwrap();
+        let size = b_wire.recv(&mut buff).unwrap();
+        assert_eq!(&buff[..size], &sent[..]);
+
+        // back on tap of b through a bridged lan, not flooded again
+        b_wire.send(&sent).unwrap();
+        assert!(a_wire.recv(&mut buff).is_err());
+        assert_eq!(b.stats.snapshot().looped, 1);
+
+        // another frame still is
+        let sent = frame(brd, b_mac, 60);
+        b_wire.send(&sent).unwrap();
+        let size = a_wire.recv(&mut buff).unwrap();
+        assert_eq!(&buff[..size], &sent[..]);
+    }
+
+    #[test]
+    fn test_packet_header() {
+        let a_mac = [2, 0, 0, 0, 0, 1];
+        let b_mac = [2, 0, 0, 0, 0, 2];
+
+        let (a, _a_wire) = fake_node("a", a_mac);
+        let (b, b_wire) = fake_node("b", b_mac);
+        connect(&a, &b, 1, 2);
+        connect(&b, &a, 2, 1);
+
+        {
+            let b = Arc::clone(&b);
+            std::thread::spawn(move || dispatch_from_peers(b));
+        }
+
+        let b_data = b.data_sock.local_addr().unwrap();
+        let mut packet = Vec::new();
+        let sent = frame(b_mac, a_mac, 60);
+        seal_frame(&a.cipher, 0, &b_data, &sent, &mut packet).unwrap();
+
+        // keepalive is taken silently, then bad magic, another version, unknown type
+        // and another network
+        let keepalive = PacketHeader::new(PacketType::Keepalive, 0).encode();
+        a.data_sock.send_to(&keepalive, b_data).unwrap();
+
+        for (i, byte) in &[(0, b'x'), (2, 2), (3, 9), (11, 1)] {
+            let mut bad = packet.clone();
+            bad[*i] = *byte;
+            a.data_sock.send_to(&bad, b_data).unwrap();
+        }
+
+        // relay header in front of data packet is of another network too
+        let mut relayed = relay_header(1, &a_mac, &b_mac).to_vec();
+        relayed.extend_from_slice(&packet);
+        a.data_sock.send_to(&relayed, b_data).unwrap();
+
+        a.data_sock.send_to(&packet, b_data).unwrap();
+        let mut buff = vec![0; 2048];
+        let size = b_wire.recv(&mut buff).unwrap();
+        assert_eq!(&buff[..size], &sent[..]);
+
+        let stats = b.stats.snapshot();
+        assert_eq!(stats.bad_magic, 1);
+        assert_eq!(stats.wrong_version, 1);
+        assert_eq!(stats.unknown_type, 1);
+        assert_eq!(stats.wrong_network, 2);
+        assert_eq!(stats.malformed, 0);
+    }
+
+    /// handshake `a` with `b` through peer of `a` at `via` if given, and add as peers
+    fn join(a: &AppState, b: &AppState, via: Option<SocketAddr>) -> SocketAddr {
+        let mut peer: Peer = format!("{}=127.0.0.1:{}", b.config().name, b.config().ctl_port)
+            .parse()
+            .unwrap();
+        peer.via = via;
+        let ctl_addr = peer.ctl_addr;
+
+        handshake(a, &mut peer).unwrap();
+        a.add_peer(peer);
+
+        ctl_addr
+    }
+
+    #[test]
+    fn test_vxlan() {
+        let a_mac = [2, 0, 0, 0, 0, 1];
+        let vtep_mac = [2, 0, 0, 0, 0, 9];
+
+        let (a, a_wire) = fake_node("a", a_mac);
+        a.set_config(Config {
+            encapsulation: Encapsulation::Vxlan,
+            vni: 42,
+            remotes: vec!["127.0.0.2".parse().unwrap()],
+            ..(*a.config()).clone()
+        });
+
+        // vtep listens on data port, as frames are sent there, and sends from any port
+        let vtep = UdpSocket::bind(("127.0.0.2", a.config().data_port)).unwrap();
+        vtep.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let vtep_tx = UdpSocket::bind("127.0.0.2:0").unwrap();
+        let a_data = a.data_sock.local_addr().unwrap();
+
+        {
+            let a = Arc::clone(&a);
+            std::thread::spawn(move || dispatch_from_tap(a));
+        }
+        {
+            let a = Arc::clone(&a);
+            std::thread::spawn(move || dispatch_from_peers(a));
+        }
+
+        // flooded to remote
+        let mut buff = vec![0; 2048];
+        let sent = frame([0xff; 6], a_mac, 60);
+        a_wire.send(&sent).unwrap();
+        let size = vtep.recv(&mut buff).unwrap();
+        assert_eq!(&buff[..8], &[0x08, 0, 0, 0, 0, 0, 42, 0]);
+        assert_eq!(&buff[8..size], &sent[..]);
+
+        // from vtep, learned at its data port
+        let mut packet = Vec::new();
+        let reply = frame(a_mac, vtep_mac, 60);
+        vxlan::encap(42, &reply, &mut packet);
+        vtep_tx.send_to(&packet, a_data).unwrap();
+        let size = a_wire.recv(&mut buff).unwrap();
+        assert_eq!(&buff[..size], &reply[..]);
+
+        let sent = frame(vtep_mac, a_mac, 60);
+        a_wire.send(&sent).unwrap();
+        let size = vtep.recv(&mut buff).unwrap();
+        assert_eq!(&buff[8..size], &sent[..]);
+
+        // another vni
+        vxlan::encap(43, &reply, &mut packet);
+        vtep_tx.send_to(&packet, a_data).unwrap();
+        a_wire
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        assert!(a_wire.recv(&mut buff).is_err());
+        assert_eq!(a.stats.snapshot().wrong_network, 1);
+    }
+
+    #[test]
+    fn test_geneve() {
+        let a_mac = [2, 0, 0, 0, 0, 1];
+        let vtep_mac = [2, 0, 0, 0, 0, 9];
+
+        let (a, a_wire) = fake_node("a", a_mac);
+        a.set_config(Config {
+            encapsulation: Encapsulation::Geneve,
+            vni: 42,
+            network_id: 7,
+            remotes: vec!["127.0.0.3".parse().unwrap()],
+            ..(*a.config()).clone()
+        });
+
+        let vtep = UdpSocket::bind(("127.0.0.3", a.config().data_port)).unwrap();
+        vtep.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let vtep_tx = UdpSocket::bind("127.0.0.3:0").unwrap();
+        let a_data = a.data_sock.local_addr().unwrap();
+
+        {
+            let a = Arc::clone(&a);
+            std::thread::spawn(move || dispatch_from_tap(a));
+        }
+        {
+            let a = Arc::clone(&a);
+            std::thread::spawn(move || dispatch_from_peers(a));
+        }
+
+        // flooded to remote, with metadata
+        let mut buff = vec![0; 2048];
+        let sent = frame([0xff; 6], a_mac, 60);
+        a_wire.send(&sent).unwrap();
+        let size = vtep.recv(&mut buff).unwrap();
+        let packet = geneve::decap(&buff[..size], 42).unwrap();
+        assert_eq!(packet.frame, &sent[..]);
+        assert_eq!(packet.metadata.network_id, Some(7));
+        assert_eq!(
+            packet.metadata.sender,
+            Some(sender_hint(&a.handshake.identity.public_key))
+        );
+        assert_eq!(packet.metadata.hops, Some(MAX_HOPS));
+
+        // from linux, without options
+        let mut packet = Vec::new();
+        let reply = frame(a_mac, vtep_mac, 60);
+        geneve::encap(42, &Metadata::default(), &reply, &mut packet);
+        vtep_tx.send_to(&packet, a_data).unwrap();
+        let size = a_wire.recv(&mut buff).unwrap();
+        assert_eq!(&buff[..size], &reply[..]);
+        let vtep_data = SocketAddr::new(vtep.local_addr().unwrap().ip(), a.config().data_port);
+        assert_eq!(a.fdb.read().unwrap().lookup(&vtep_mac), Some(vtep_data));
+
+        // learned at source address, sender option only checked against the peer there
+        let (b, _b_wire) = fake_node("b", [2, 0, 0, 0, 0, 2]);
+        let (c, _c_wire) = fake_node("c", [2, 0, 0, 0, 0, 3]);
+        connect(&a, &c, 1, 3);
+        connect(&a, &b, 1, 2);
+        a.peers.write().unwrap()[1].data_addr = vtep_data;
+
+        let moved = [2, 0, 0, 0, 0, 8];
+        for (sender, expected) in [(&c, None), (&b, Some(vtep_data))] {
+            let metadata = Metadata {
+                sender: Some(sender_hint(&sender.handshake.identity.public_key)),
+                ..Metadata::default()
+            };
+            geneve::encap(42, &metadata, &frame(a_mac, moved, 60), &mut packet);
+            vtep_tx.send_to(&packet, a_data).unwrap();
+            std::thread::sleep(Duration::from_millis(100));
+            assert_eq!(a.fdb.read().unwrap().lookup(&moved), expected);
+        }
+        a_wire.recv(&mut buff).unwrap();
+
+        // another network, no more hops
+        for metadata in &[
+            Metadata {
+                network_id: Some(8),
+                ..Metadata::default()
+            },
+            Metadata {
+                hops: Some(0),
+                ..Metadata::default()
+            },
+        ] {
+            geneve::encap(42, metadata, &reply, &mut packet);
+            vtep_tx.send_to(&packet, a_data).unwrap();
+        }
+        a_wire
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        assert!(a_wire.recv(&mut buff).is_err());
+
+        let stats = a.stats.snapshot();
+        assert_eq!(stats.wrong_network, 1);
+        assert_eq!(stats.ttl_expired, 1);
+        assert_eq!(stats.wrong_sender, 1);
+    }
+
+    #[test]
+    fn test_relay() {
+        let a_mac = [2, 0, 0, 0, 0, 1];
+        let c_mac = [2, 0, 0, 0, 0, 3];
+
+        let (a, a_wire) = control_node("a", a_mac);
+        let (b, _b_wire) = control_node("b", [2, 0, 0, 0, 0, 2]);
+        let (c, c_wire) = control_node("c", c_mac);
+
+        // a and c don't connect directly when learning each other from b
+        let key_of = |state: &AppState| {
+            let mut peer: Peer = "key=127.0.0.1:2".parse().unwrap();
+            peer.public_key = state.handshake.identity.public_key;
+            peer
+        };
+        a.membership.ignore(&[key_of(&c)]);
+        c.membership.ignore(&[key_of(&a)]);
+
+        let b_ctl = join(&a, &b, None);
+        join(&c, &b, None);
+        join(&a, &c, Some(b_ctl));
+
+        for state in [&a, &b, &c].iter() {
+            let state = Arc::clone(state);
+            std::thread::spawn(move || dispatch_from_tap(state));
+        }
+        for state in [&a, &b, &c].iter() {
+            let state = Arc::clone(state);
+            std::thread::spawn(move || dispatch_from_peers(state));
+        }
+
+        // handshake from a was passed on by b, so c reaches a through b too
+        let via_of = |state: &AppState, name: &str| {
+            let peers = state.peers.read().unwrap();
+            peers.iter().find(|it| it.name == name).unwrap().via
+        };
+        assert_eq!(via_of(&a, "c"), Some(b_ctl));
+        assert_eq!(
+            via_of(&c, "a").map(|it| it.port()),
+            Some(b.config().ctl_port)
+        );
+
+        let mut buff = vec![0; 2048];
+
+        let sent = frame(c_mac, a_mac, 98);
+        a_wire.send(&sent).unwrap();
+        let size = c_wire.recv(&mut buff).unwrap();
+        assert_eq!(&buff[..size], &sent[..]);
+
+        let sent = frame(a_mac, c_mac, 98);
+        c_wire.send(&sent).unwrap();
+        let size = a_wire.recv(&mut buff).unwrap();
+        assert_eq!(&buff[..size], &sent[..]);
+
+        assert_eq!(b.stats.snapshot().relayed, 2);
+
+        // mac of a is learned at a, not at the relay
+        let a_data = a.data_sock.local_addr().unwrap();
+        assert_eq!(c.fdb.read().unwrap().lookup(&a_mac), Some(a_data));
+
+        // relay header naming a as sender, of a frame sealed by b, is learned at b
+        let x_mac = [2, 0, 0, 0, 0, 9];
+        let b_data = b.data_sock.local_addr().unwrap();
+        let c_data = c.data_sock.local_addr().unwrap();
+        let mut forged = relay_header(0, &a_mac, &c_mac).to_vec();
+        let mut sealed = Vec::new();
+        let sent = frame(c_mac, x_mac, 98);
+        seal_frame(&b.cipher, 0, &c_data, &sent, &mut sealed).unwrap();
+        forged.extend_from_slice(&sealed);
+        b.data_sock.send_to(&forged, c_data).unwrap();
+
+        let size = c_wire.recv(&mut buff).unwrap();
+        assert_eq!(&buff[..size], &sent[..]);
+        assert_eq!(c.fdb.read().unwrap().lookup(&x_mac), Some(b_data));
+
+        // relay drops frames which went through too many hops
+        let mut looped = relay_header(0, &a_mac, &c_mac).to_vec();
+        looped[PACKET_HEADER_LEN] = 1;
+        looped.extend_from_slice(&[0; 64]);
+        a.data_sock.send_to(&looped, b_data).unwrap();
+
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(b.stats.snapshot().ttl_expired, 1);
+        assert_eq!(b.stats.snapshot().relayed, 2);
     }
 }
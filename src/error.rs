@@ -8,9 +8,66 @@ pub enum TapDemoError {
     TapSetupError,
 
     PeerLost,
+    NoReply,
     MsgDeserializeError(bincode::Error),
+
+    IdentityParseError,
+    HandshakeError(snow::Error),
+    HandshakeFailed,
+    PeerNotAuthorized,
+    WrongNetwork(u32),
+    NoSession,
+
+    SubnetParseError(String),
+    SubnetExhausted,
+
+    MtuParseError(String),
+    ConfigError(String),
+
+    AdminFrameTooLarge(usize),
+    UnexpectedMsg,
+    NoSuchNetwork(String),
 }
 
+impl std::fmt::Display for TapDemoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TapDemoError::IOError(err) => write!(f, "io error, {}", err),
+            TapDemoError::TapCreateError(rc) => write!(f, "create tap failed, rc = {}", rc),
+            TapDemoError::GetHWAddrError => write!(f, "get hw addr failed"),
+            TapDemoError::PeerParseError => write!(f, "error parse peer"),
+            TapDemoError::PeerAddressParseError(err) => {
+                write!(f, "error parse peer address, {}", err)
+            }
+            TapDemoError::TapSetupError => write!(f, "setup tap failed"),
+            TapDemoError::PeerLost => write!(f, "peer lost"),
+            TapDemoError::NoReply => write!(f, "no reply"),
+            TapDemoError::MsgDeserializeError(err) => write!(f, "error deserialize msg, {}", err),
+            TapDemoError::IdentityParseError => write!(f, "error parse identity key"),
+            TapDemoError::HandshakeError(err) => write!(f, "handshake error, {}", err),
+            TapDemoError::HandshakeFailed => write!(f, "handshake failed"),
+            TapDemoError::PeerNotAuthorized => write!(f, "peer not authorized"),
+            TapDemoError::WrongNetwork(id) => write!(f, "peer in another network {}", id),
+            TapDemoError::NoSession => write!(f, "no session with peer"),
+            TapDemoError::SubnetParseError(s) => write!(f, "error parse subnet {}", s),
+            TapDemoError::SubnetExhausted => write!(f, "no free address in subnet"),
+            TapDemoError::ConfigError(s) => write!(f, "invalid config, {}", s),
+            TapDemoError::MtuParseError(s) => write!(
+                f,
+                "invalid mtu {}, must be between {} and {}",
+                s,
+                crate::dispatch::MIN_MTU,
+                crate::dispatch::MAX_MTU
+            ),
+            TapDemoError::AdminFrameTooLarge(len) => write!(f, "admin frame too large, {}", len),
+            TapDemoError::UnexpectedMsg => write!(f, "unexpected msg"),
+            TapDemoError::NoSuchNetwork(name) => write!(f, "no network {}", name),
+        }
+    }
+}
+
+impl std::error::Error for TapDemoError {}
+
 impl From<std::io::Error> for TapDemoError {
     fn from(err: std::io::Error) -> Self {
         TapDemoError::IOError(err)
@@ -29,4 +86,10 @@ impl From<bincode::Error> for TapDemoError {
     }
 }
 
+impl From<snow::Error> for TapDemoError {
+    fn from(err: snow::Error) -> Self {
+        TapDemoError::HandshakeError(err)
+    }
+}
+
 pub type AppResult<T> = Result<T, TapDemoError>;
@@ -1,3 +1,5 @@
+pub const ETH_HEADER_LEN: usize = 14;
+
 #[derive(Debug)]
 pub struct EthV2<'a> {
     pub dst_mac: [u8; 6],
@@ -5,3 +7,28 @@ pub struct EthV2<'a> {
     pub proto_type: u16,
     pub data: &'a [u8],
 }
+
+impl<'a> EthV2<'a> {
+    /// parse ethernet ii header, `None` if too short
+    pub fn parse(data: &'a [u8]) -> Option<EthV2<'a>> {
+        if data.len() < ETH_HEADER_LEN {
+            return None;
+        }
+
+        let mut dst_mac = [0; 6];
+        dst_mac.copy_from_slice(&data[0..6]);
+
+        let mut src_mac = [0; 6];
+        src_mac.copy_from_slice(&data[6..12]);
+
+        let mut proto_type = [0; 2];
+        proto_type.copy_from_slice(&data[12..14]);
+
+        Some(EthV2 {
+            dst_mac,
+            src_mac,
+            proto_type: u16::from_be_bytes(proto_type),
+            data,
+        })
+    }
+}
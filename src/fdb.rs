@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+/// entries not refreshed within this time are dropped, same default as linux bridge
+pub(crate) const FDB_AGEING_TIME: Duration = Duration::from_secs(300);
+
+#[derive(Debug)]
+struct FdbEntry {
+    data_addr: SocketAddr,
+    last_seen: Instant,
+}
+
+/// fdb entry as shown by `fdb list`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct FdbRecord {
+    pub(crate) hw_addr: [u8; 6],
+    pub(crate) peer: Option<String>,
+    pub(crate) data_addr: SocketAddr,
+    pub(crate) age: u64,
+}
+
+/// forwarding database, maps mac address learned from peers to the peer data address
+#[derive(Debug)]
+pub(crate) struct Fdb {
+    ageing_time: Duration,
+    entries: HashMap<[u8; 6], FdbEntry>,
+}
+
+impl Fdb {
+    pub(crate) fn new(ageing_time: Duration) -> Fdb {
+        Fdb {
+            ageing_time,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// record `hw_addr` as reachable via `data_addr`
+    pub(crate) fn learn(&mut self, hw_addr: [u8; 6], data_addr: SocketAddr) {
+        // group address can not be a source
+        if hw_addr[0] & 0x01 != 0 || hw_addr == [0; 6] {
+            return;
+        }
+
+        self.entries.insert(
+            hw_addr,
+            FdbEntry {
+                data_addr,
+                last_seen: Instant::now(),
+            },
+        );
+    }
+
+    pub(crate) fn lookup(&self, hw_addr: &[u8; 6]) -> Option<SocketAddr> {
+        self.entries
+            .get(hw_addr)
+            .filter(|it| it.last_seen.elapsed() < self.ageing_time)
+            .map(|it| it.data_addr)
+    }
+
+    /// remove entries older than ageing time
+    pub(crate) fn expire(&mut self) {
+        let ageing_time = self.ageing_time;
+
+        self.entries
+            .retain(|_, it| it.last_seen.elapsed() < ageing_time);
+    }
+
+    /// remove all entries learned from `data_addr`, used when a peer is removed
+    pub(crate) fn forget(&mut self, data_addr: &SocketAddr) {
+        self.entries.retain(|_, it| it.data_addr != *data_addr);
+    }
+
+    pub(crate) fn records(&self) -> Vec<FdbRecord> {
+        self.entries
+            .iter()
+            .filter(|(_, it)| it.last_seen.elapsed() < self.ageing_time)
+            .map(|(hw_addr, it)| FdbRecord {
+                hw_addr: *hw_addr,
+                peer: None,
+                data_addr: it.data_addr,
+                age: it.last_seen.elapsed().as_secs(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fdb() {
+        let mac = [2, 0, 0, 0, 0, 1];
+        let other = [2, 0, 0, 0, 0, 2];
+        let b: SocketAddr = "127.0.0.2:9908".parse().unwrap();
+        let c: SocketAddr = "127.0.0.3:9908".parse().unwrap();
+
+        let mut fdb = Fdb::new(FDB_AGEING_TIME);
+        assert_eq!(fdb.lookup(&mac), None);
+
+        fdb.learn(mac, b);
+        fdb.learn(other, b);
+        assert_eq!(fdb.lookup(&mac), Some(b));
+
+        // moved behind another peer
+        fdb.learn(mac, c);
+        assert_eq!(fdb.lookup(&mac), Some(c));
+        assert_eq!(fdb.records().len(), 2);
+
+        // group and zero address are never a source
+        for group in [
+            [0xff; 6],
+            [0x01, 0, 0x5e, 0, 0, 1],
+            [0x33, 0x33, 0, 0, 0, 1],
+            [0; 6],
+        ] {
+            fdb.learn(group, b);
+            assert_eq!(fdb.lookup(&group), None);
+        }
+
+        // only entries of removed peer are forgotten
+        fdb.forget(&b);
+        assert_eq!(fdb.lookup(&other), None);
+        assert_eq!(fdb.lookup(&mac), Some(c));
+    }
+
+    #[test]
+    fn test_ageing() {
+        let mac = [2, 0, 0, 0, 0, 1];
+        let b: SocketAddr = "127.0.0.2:9908".parse().unwrap();
+
+        let mut fdb = Fdb::new(Duration::from_millis(50));
+        fdb.learn(mac, b);
+        assert_eq!(fdb.lookup(&mac), Some(b));
+
+        // not seen within ageing time, refreshed entry is kept
+        std::thread::sleep(Duration::from_millis(100));
+        fdb.learn([2, 0, 0, 0, 0, 2], b);
+        assert_eq!(fdb.lookup(&mac), None);
+        assert!(fdb.records().iter().all(|it| it.hw_addr != mac));
+
+        fdb.expire();
+        assert!(!fdb.entries.contains_key(&mac));
+        assert_eq!(fdb.entries.len(), 1);
+    }
+}
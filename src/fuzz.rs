@@ -0,0 +1,74 @@
+//! entry points for fuzz targets under `fuzz/`, not a stable api
+
+use std::net::SocketAddr;
+
+use crate::crypto::{DataCipher, SessionKeys};
+use crate::dispatch::{open_frame, seal_frame, RelayHeader};
+use crate::eth::EthV2;
+use crate::fdb::{Fdb, FDB_AGEING_TIME};
+use crate::geneve;
+use crate::msg::{decode, HandshakePayload, Msg};
+use crate::packet::{PacketHeader, PacketType};
+use crate::vxlan;
+
+/// decode bytes received on control port, or as handshake payload
+pub fn control_msg(data: &[u8]) {
+    let _ = decode::<Msg>(data);
+    let _ = decode::<HandshakePayload>(data);
+}
+
+/// open bytes received on data port as is, then sealed with a valid session so
+/// the frame parsing behind packet header and authentication is reached too
+pub fn data_frame(data: &[u8]) {
+    let data_addr: SocketAddr = ([127, 0, 0, 1], 9908).into();
+    let cipher = DataCipher::new();
+    let mut fdb = Fdb::new(FDB_AGEING_TIME);
+
+    // loopback session, frames sealed here open with the same cipher
+    cipher.install(
+        data_addr,
+        SessionKeys {
+            local_index: 1,
+            remote_index: 1,
+            tx: [1; 32],
+            rx: [1; 32],
+        },
+    );
+
+    // packet header, and relay header behind it of relay packets
+    if let Ok(header) = PacketHeader::parse(data, 0) {
+        if header.kind == PacketType::Relay {
+            let _ = RelayHeader::parse(data);
+        }
+    }
+
+    let mut raw = data.to_vec();
+    let _ = open_frame(&cipher, 0, &mut raw);
+
+    let mut sealed = Vec::new();
+    if let Some(size) = seal_frame(&cipher, 0, &data_addr, data, &mut sealed) {
+        if let Ok((eth, data_addr)) = open_frame(&cipher, 0, &mut sealed[..size]) {
+            fdb.learn(eth.src_mac, data_addr);
+        }
+    }
+
+    // frames read from tap
+    let _ = EthV2::parse(data);
+}
+
+/// decap bytes received on data port with vxlan or geneve encapsulation, vni taken from
+/// the bytes themselves so option walking of geneve is reached
+pub fn encap_frame(data: &[u8]) {
+    let vni = match data.get(4..7) {
+        Some(vni) => u32::from_be_bytes([0, vni[0], vni[1], vni[2]]),
+        None => 0,
+    };
+
+    if let Ok(frame) = vxlan::decap(data, vni) {
+        let _ = EthV2::parse(frame);
+    }
+
+    if let Ok(packet) = geneve::decap(data, vni) {
+        let _ = EthV2::parse(packet.frame);
+    }
+}
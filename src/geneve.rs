@@ -0,0 +1,211 @@
+//! geneve encapsulation of rfc 8926, to interwork with linux `geneve` interfaces, with
+//! options carrying metadata of frame
+
+use crate::crypto::OpenError;
+
+/// iana assigned port
+pub(crate) const GENEVE_PORT: u16 = 6081;
+
+/// version and options length, flags, protocol type, vni and reserved
+const BASE_HEADER_LEN: usize = 8;
+
+const VERSION: u8 = 0;
+
+/// transparent ethernet bridging
+const PROTO_ETH: u16 = 0x6558;
+
+/// `O` flag, control packet for tunnel endpoint rather than a frame
+const FLAG_OAM: u8 = 0x80;
+
+/// option class for experimental use, rfc 8926 section 7.2
+const OPTION_CLASS: u16 = 0xfff0;
+
+/// high bit of option type, packets with such options not understood must be dropped
+const TYPE_CRITICAL: u8 = 0x80;
+
+/// our options are all informational, so receivers not knowing them, eg, linux which
+/// drops packets with `C` flag, still take the frame
+const OPTION_NETWORK_ID: u8 = 0x01;
+const OPTION_SENDER: u8 = 0x02;
+const OPTION_HOPS: u8 = 0x03;
+
+/// metadata of frame carried in options, each is left out when not given
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Metadata {
+    pub(crate) network_id: Option<u32>,
+    /// leading bytes of public key of node sent the frame
+    pub(crate) sender: Option<[u8; 8]>,
+    /// hops the frame may still go
+    pub(crate) hops: Option<u8>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Packet<'a> {
+    pub(crate) metadata: Metadata,
+    pub(crate) frame: &'a [u8],
+}
+
+fn push_option(out: &mut Vec<u8>, kind: u8, value: &[u8]) {
+    out.extend_from_slice(&OPTION_CLASS.to_be_bytes());
+    out.push(kind);
+    out.push((value.len() / 4) as u8);
+    out.extend_from_slice(value);
+}
+
+/// put `frame` behind geneve header of `vni` and options of `metadata` into `out`,
+/// return packet length
+pub(crate) fn encap(vni: u32, metadata: &Metadata, frame: &[u8], out: &mut Vec<u8>) -> usize {
+    out.clear();
+    out.extend_from_slice(&[VERSION << 6, 0]);
+    out.extend_from_slice(&PROTO_ETH.to_be_bytes());
+    out.extend_from_slice(&(vni << 8).to_be_bytes());
+
+    if let Some(network_id) = metadata.network_id {
+        push_option(out, OPTION_NETWORK_ID, &network_id.to_be_bytes());
+    }
+
+    if let Some(ref sender) = metadata.sender {
+        push_option(out, OPTION_SENDER, sender);
+    }
+
+    if let Some(hops) = metadata.hops {
+        push_option(out, OPTION_HOPS, &[hops, 0, 0, 0]);
+    }
+
+    // in 4 bytes words
+    out[0] |= ((out.len() - BASE_HEADER_LEN) / 4) as u8;
+    out.extend_from_slice(frame);
+
+    out.len()
+}
+
+/// options we know are taken, unknown ones skipped unless critical. all options are
+/// checked, whether `C` flag is set or not
+fn parse_options(mut options: &[u8]) -> Result<Metadata, OpenError> {
+    let mut metadata = Metadata::default();
+
+    while !options.is_empty() {
+        if options.len() < 4 {
+            return Err(OpenError::Malformed);
+        }
+
+        let class = u16::from_be_bytes([options[0], options[1]]);
+        let kind = options[2];
+        let len = (options[3] & 0x1f) as usize * 4;
+
+        if options.len() < 4 + len {
+            return Err(OpenError::Malformed);
+        }
+
+        let value = &options[4..4 + len];
+
+        match (class, kind, len) {
+            (OPTION_CLASS, OPTION_NETWORK_ID, 4) => {
+                metadata.network_id =
+                    Some(u32::from_be_bytes([value[0], value[1], value[2], value[3]]));
+            }
+            (OPTION_CLASS, OPTION_SENDER, 8) => {
+                let mut sender = [0; 8];
+                sender.copy_from_slice(value);
+                metadata.sender = Some(sender);
+            }
+            (OPTION_CLASS, OPTION_HOPS, 4) => metadata.hops = Some(value[0]),
+            _ if kind & TYPE_CRITICAL != 0 => return Err(OpenError::UnknownOption),
+            _ => {}
+        }
+
+        options = &options[4 + len..];
+    }
+
+    Ok(metadata)
+}
+
+/// frame and metadata of geneve packet, packets of another version or protocol, and
+/// oam packets are malformed, those of another vni are of another network
+pub(crate) fn decap(data: &[u8], vni: u32) -> Result<Packet<'_>, OpenError> {
+    if data.len() < BASE_HEADER_LEN || data[0] >> 6 != VERSION {
+        return Err(OpenError::Malformed);
+    }
+
+    let header_len = BASE_HEADER_LEN + (data[0] & 0x3f) as usize * 4;
+
+    if data.len() < header_len
+        || data[1] & FLAG_OAM != 0
+        || u16::from_be_bytes([data[2], data[3]]) != PROTO_ETH
+    {
+        return Err(OpenError::Malformed);
+    }
+
+    if u32::from_be_bytes([0, data[4], data[5], data[6]]) != vni {
+        return Err(OpenError::WrongNetwork);
+    }
+
+    Ok(Packet {
+        metadata: parse_options(&data[BASE_HEADER_LEN..header_len])?,
+        frame: &data[header_len..],
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_geneve() {
+        let frame = [0xffu8; 60];
+        let metadata = Metadata {
+            network_id: Some(7),
+            sender: Some([1, 2, 3, 4, 5, 6, 7, 8]),
+            hops: Some(16),
+        };
+        let mut packet = Vec::new();
+
+        let size = encap(42, &metadata, &frame, &mut packet);
+        assert_eq!(size, BASE_HEADER_LEN + 28 + 60);
+        // 7 words of options, no flags, ethernet, vni
+        assert_eq!(&packet[..8], &[0x07, 0, 0x65, 0x58, 0, 0, 42, 0]);
+
+        let decoded = decap(&packet, 42).unwrap();
+        assert_eq!(decoded.metadata, metadata);
+        assert_eq!(decoded.frame, &frame[..]);
+
+        assert!(matches!(decap(&packet, 43), Err(OpenError::WrongNetwork)));
+        assert!(matches!(
+            decap(&packet[..20], 42),
+            Err(OpenError::Malformed)
+        ));
+
+        // as from linux, without options
+        encap(42, &Metadata::default(), &frame, &mut packet);
+        assert_eq!(decap(&packet, 42).unwrap().metadata, Metadata::default());
+
+        // unknown option is skipped, unless critical
+        let with_option = |class: u16, kind: u8| {
+            let mut packet = vec![0x02, 0, 0x65, 0x58, 0, 0, 42, 0];
+            packet.extend_from_slice(&class.to_be_bytes());
+            packet.extend_from_slice(&[kind, 1, 0, 0, 0, 0]);
+            packet.extend_from_slice(&frame);
+            packet
+        };
+        assert!(decap(&with_option(0x0100, 0x01), 42).is_ok());
+        assert!(matches!(
+            decap(&with_option(0x0100, 0x81), 42),
+            Err(OpenError::UnknownOption)
+        ));
+        assert!(matches!(
+            decap(&with_option(OPTION_CLASS, 0x81), 42),
+            Err(OpenError::UnknownOption)
+        ));
+
+        // option longer than options, another version, oam
+        let mut bad = with_option(0x0100, 0x01);
+        bad[11] = 2;
+        assert!(matches!(decap(&bad, 42), Err(OpenError::Malformed)));
+
+        for (i, byte) in &[(0, 0x42), (1, FLAG_OAM), (2, 0x08)] {
+            let mut bad = packet.clone();
+            bad[*i] = *byte;
+            assert!(matches!(decap(&bad, 42), Err(OpenError::Malformed)));
+        }
+    }
+}
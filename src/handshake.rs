@@ -0,0 +1,377 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use bincode::serialize;
+use blake2::{Blake2s256, Digest};
+use log::{info, warn};
+use snow::HandshakeState;
+
+use crate::app::AppState;
+use crate::config::MAX_NAME_LEN;
+use crate::control::REPLY_TIMEOUT;
+use crate::crypto::SessionKeys;
+use crate::error::{AppResult, TapDemoError};
+use crate::identity::{fingerprint, Identity};
+use crate::ipam::resolve_conflict;
+use crate::msg::*;
+use crate::peer::{Peer, PeerState};
+
+pub(crate) const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+const NOISE_PARAMS_PSK: &str = "Noise_XXpsk3_25519_ChaChaPoly_BLAKE2s";
+
+/// pending responder handshakes older than this are dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// handshakes waiting for finish, the oldest gives way to a new one
+const MAX_PENDING_HANDSHAKES: usize = 64;
+
+/// noise handshake settings of this node
+pub(crate) struct HandshakeConfig {
+    pub(crate) identity: Identity,
+    pub(crate) psk: Option<[u8; 32]>,
+    pub(crate) authorized_keys: Option<Vec<[u8; 32]>>,
+}
+
+impl HandshakeConfig {
+    fn builder(&self) -> AppResult<snow::Builder<'_>> {
+        let builder = match self.psk {
+            Some(ref psk) => snow::Builder::new(NOISE_PARAMS_PSK.parse()?).psk(3, psk),
+            None => snow::Builder::new(NOISE_PARAMS.parse()?),
+        };
+
+        Ok(builder.local_private_key(&self.identity.private_key))
+    }
+
+    fn is_authorized(&self, public_key: &[u8; 32]) -> bool {
+        match self.authorized_keys {
+            Some(ref keys) => keys.contains(public_key),
+            None => true,
+        }
+    }
+
+    fn remote_key(&self, hs: &HandshakeState) -> AppResult<[u8; 32]> {
+        let mut public_key = [0; 32];
+        public_key.copy_from_slice(
+            hs.get_remote_static()
+                .ok_or(TapDemoError::HandshakeFailed)?,
+        );
+
+        if !self.is_authorized(&public_key) {
+            warn!("reject unauthorized key {}", fingerprint(&public_key));
+
+            return Err(TapDemoError::PeerNotAuthorized);
+        }
+
+        Ok(public_key)
+    }
+}
+
+/// derive pre-shared key mixed into handshake from passphrase
+pub(crate) fn derive_psk(passphrase: &[u8]) -> [u8; 32] {
+    let mut hasher = Blake2s256::new();
+    hasher.update(b"tap-demo psk");
+    hasher.update(passphrase);
+
+    let mut psk = [0; 32];
+    psk.copy_from_slice(&hasher.finalize());
+
+    psk
+}
+
+fn is_response(msg: &ControlMsg) -> bool {
+    matches!(msg, ControlMsg::HandshakeResponse(_))
+}
+
+fn is_complete(msg: &ControlMsg) -> bool {
+    matches!(msg, ControlMsg::HandshakeComplete(_))
+}
+
+/// data address of peer at `ctl_addr`, as seen by rendezvous if learned from it, or
+/// `data_port` of the same host
+fn data_addr(state: &AppState, ctl_addr: SocketAddr, data_port: u16) -> SocketAddr {
+    state
+        .rendezvous
+        .data_addr(&ctl_addr)
+        .unwrap_or_else(|| SocketAddr::new(ctl_addr.ip(), data_port))
+}
+
+fn local_payload(state: &AppState, index: u32) -> AppResult<Vec<u8>> {
+    let payload = HandshakePayload {
+        name: state.config().name.clone(),
+        hw_addr: state.hw_addr,
+        ctl_port: state.config().ctl_port,
+        data_port: state.config().data_port,
+        ip_addr: state.ipam.as_ref().and_then(|it| it.assigned()),
+        index,
+        network_id: state.config().network_id,
+    };
+
+    Ok(serialize(&payload)?)
+}
+
+/// peer given the control port of another network of the same daemon, or configured
+/// with another network id
+fn check_network(state: &AppState, remote: &HandshakePayload) -> AppResult<()> {
+    if remote.network_id != state.config().network_id {
+        warn!("reject {} of network {}", remote.name, remote.network_id);
+
+        return Err(TapDemoError::WrongNetwork(remote.network_id));
+    }
+
+    // could not be sent on in gossip
+    if remote.name.len() > MAX_NAME_LEN {
+        return Err(TapDemoError::HandshakeFailed);
+    }
+
+    Ok(())
+}
+
+/// run handshake with `peer` as initiator, install data session and fill peer's
+/// public key and hw addr
+pub(crate) fn handshake(state: &AppState, peer: &mut Peer) -> AppResult<()> {
+    let ctl_addr = [peer.ctl_addr];
+    let via = peer.via;
+
+    let mut hs = state.handshake.builder()?.build_initiator()?;
+    let mut buff = vec![0; 1024];
+    let mut payload = vec![0; 1024];
+
+    // -> e
+    let size = hs.write_message(&[], &mut buff)?;
+    let msg = ControlMsg::HandshakeInit(buff[..size].to_vec());
+
+    // <- e, ee, s, es
+    let reply = match state
+        .ctl
+        .request_via(msg, &ctl_addr, via, is_response, REPLY_TIMEOUT)?
+    {
+        (_, ControlMsg::HandshakeResponse(reply)) => reply,
+        _ => return Err(TapDemoError::HandshakeFailed),
+    };
+
+    let size = hs.read_message(&reply, &mut payload)?;
+    let remote: HandshakePayload = decode(&payload[..size])?;
+    let public_key = state.handshake.remote_key(&hs)?;
+    check_network(state, &remote)?;
+
+    // -> s, se, psk
+    let local_index = state.cipher.alloc_index();
+    let size = hs.write_message(&local_payload(state, local_index)?, &mut buff)?;
+    let msg = ControlMsg::HandshakeFinish(buff[..size].to_vec());
+
+    match state
+        .ctl
+        .request_via(msg, &ctl_addr, via, is_complete, REPLY_TIMEOUT)?
+    {
+        (_, ControlMsg::HandshakeComplete(true)) => {}
+        (_, ControlMsg::HandshakeComplete(false)) => return Err(TapDemoError::PeerNotAuthorized),
+        _ => return Err(TapDemoError::HandshakeFailed),
+    }
+
+    if remote.data_port == 0 {
+        return Err(TapDemoError::HandshakeFailed);
+    }
+    peer.data_addr = data_addr(state, peer.ctl_addr, remote.data_port);
+
+    let (tx, rx) = hs.dangerously_get_raw_split();
+    state.cipher.install(
+        peer.data_addr,
+        SessionKeys {
+            local_index,
+            remote_index: remote.index,
+            tx,
+            rx,
+        },
+    );
+
+    peer.public_key = public_key;
+    peer.hw_addr = remote.hw_addr;
+    peer.ip_addr = remote.ip_addr;
+
+    resolve_conflict(state, peer);
+
+    info!(
+        "handshake with {}({}) done, {}",
+        peer.name,
+        peer.ctl_addr,
+        fingerprint(&public_key)
+    );
+
+    Ok(())
+}
+
+struct PendingHandshake {
+    hs: HandshakeState,
+    local_index: u32,
+    started: Instant,
+}
+
+/// responder side of handshake, kept by control thread between init and finish
+pub(crate) struct Responder {
+    pending: HashMap<SocketAddr, PendingHandshake>,
+}
+
+impl Responder {
+    pub(crate) fn new() -> Responder {
+        Responder {
+            pending: HashMap::new(),
+        }
+    }
+
+    /// handle first handshake message from `src_addr`, return reply
+    pub(crate) fn on_init(
+        &mut self,
+        state: &AppState,
+        src_addr: SocketAddr,
+        data: &[u8],
+    ) -> AppResult<Vec<u8>> {
+        self.pending
+            .retain(|_, it| it.started.elapsed() < HANDSHAKE_TIMEOUT);
+
+        // inits from spoofed addresses must not lock real peers out
+        if self.pending.len() >= MAX_PENDING_HANDSHAKES {
+            let oldest = self
+                .pending
+                .iter()
+                .min_by_key(|(_, it)| it.started)
+                .map(|(addr, _)| *addr);
+
+            if let Some(oldest) = oldest {
+                self.pending.remove(&oldest);
+            }
+        }
+
+        let mut hs = state.handshake.builder()?.build_responder()?;
+        let mut buff = vec![0; 1024];
+
+        // <- e
+        hs.read_message(data, &mut buff)?;
+
+        // -> e, ee, s, es
+        let local_index = state.cipher.alloc_index();
+        let size = hs.write_message(&local_payload(state, local_index)?, &mut buff)?;
+
+        self.pending.insert(
+            src_addr,
+            PendingHandshake {
+                hs,
+                local_index,
+                started: Instant::now(),
+            },
+        );
+
+        Ok(buff[..size].to_vec())
+    }
+
+    /// handle last handshake message from `src_addr`, passed on by peer at `via` if
+    /// given, install data session on success
+    pub(crate) fn on_finish(
+        &mut self,
+        state: &AppState,
+        src_addr: SocketAddr,
+        via: Option<SocketAddr>,
+        data: &[u8],
+    ) -> AppResult<()> {
+        let PendingHandshake {
+            mut hs,
+            local_index,
+            ..
+        } = self
+            .pending
+            .remove(&src_addr)
+            .ok_or(TapDemoError::HandshakeFailed)?;
+
+        // <- s, se, psk
+        let mut payload = vec![0; 1024];
+        let size = hs.read_message(data, &mut payload)?;
+        let remote: HandshakePayload = decode(&payload[..size])?;
+        let public_key = state.handshake.remote_key(&hs)?;
+        check_network(state, &remote)?;
+
+        if remote.data_port == 0 || remote.ctl_port == 0 {
+            return Err(TapDemoError::HandshakeFailed);
+        }
+
+        let data_addr = data_addr(state, src_addr, remote.data_port);
+
+        let (rx, tx) = hs.dangerously_get_raw_split();
+        state.cipher.install(
+            data_addr,
+            SessionKeys {
+                local_index,
+                remote_index: remote.index,
+                tx,
+                rx,
+            },
+        );
+
+        info!(
+            "handshake from {}({}) done, {}",
+            remote.name,
+            data_addr,
+            fingerprint(&public_key)
+        );
+
+        // sent from control socket of remote, maybe through nat
+        let ctl_addr = src_addr;
+
+        // address conflict is left to initiator, which has the session only once we reply
+
+        // initiator is a peer of this node too, so joining one member is enough
+        state.add_peer(Peer {
+            name: remote.name,
+            ctl_addr,
+            data_addr,
+            hw_addr: remote.hw_addr,
+            public_key,
+            ip_addr: remote.ip_addr,
+            state: PeerState::Alive,
+            via,
+        });
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::test_util::fake_node;
+
+    /// first handshake message of a new initiator towards `state`
+    fn init(state: &AppState) -> Vec<u8> {
+        let mut hs = state
+            .handshake
+            .builder()
+            .unwrap()
+            .build_initiator()
+            .unwrap();
+        let mut buff = vec![0; 1024];
+        let size = hs.write_message(&[], &mut buff).unwrap();
+
+        buff[..size].to_vec()
+    }
+
+    #[test]
+    fn test_pending_handshakes() {
+        let (a, _a_wire) = fake_node("a", [2, 0, 0, 0, 0, 1]);
+        let mut responder = Responder::new();
+
+        // flood of inits from spoofed addresses
+        for port in 1..=MAX_PENDING_HANDSHAKES as u16 * 2 {
+            let src = SocketAddr::from(([10, 0, 0, 1], port));
+            responder.on_init(&a, src, &init(&a)).unwrap();
+        }
+        assert_eq!(responder.pending.len(), MAX_PENDING_HANDSHAKES);
+
+        // a real peer still gets its reply, and the newest inits are kept
+        let src = SocketAddr::from(([10, 0, 0, 2], 9909));
+        assert!(responder.on_init(&a, src, &init(&a)).is_ok());
+        assert!(responder.pending.contains_key(&src));
+        assert!(responder
+            .pending
+            .contains_key(&SocketAddr::from(([10, 0, 0, 1], 128))));
+        assert_eq!(responder.pending.len(), MAX_PENDING_HANDSHAKES);
+    }
+}
@@ -0,0 +1,121 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use blake2::{Blake2s256, Digest};
+use log::info;
+
+use crate::error::{AppResult, TapDemoError};
+use crate::handshake::NOISE_PARAMS;
+
+pub(crate) const DEFAULT_IDENTITY_PATH: &str = "/var/lib/tap-demo/identity";
+
+/// static x25519 keypair identifying this node
+pub(crate) struct Identity {
+    pub(crate) private_key: [u8; 32],
+    pub(crate) public_key: [u8; 32],
+}
+
+impl Identity {
+    /// load keypair from `path`, generate and save a new one if not exists
+    pub(crate) fn load_or_generate(path: &Path) -> AppResult<Identity> {
+        if path.exists() {
+            let content = fs::read_to_string(path)?;
+            let mut lines = content.lines();
+
+            let private_key = lines
+                .next()
+                .and_then(parse_key)
+                .ok_or(TapDemoError::IdentityParseError)?;
+            let public_key = lines
+                .next()
+                .and_then(parse_key)
+                .ok_or(TapDemoError::IdentityParseError)?;
+
+            return Ok(Identity {
+                private_key,
+                public_key,
+            });
+        }
+
+        let identity = Identity::generate()?;
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)?;
+        writeln!(file, "{}", format_key(&identity.private_key))?;
+        writeln!(file, "{}", format_key(&identity.public_key))?;
+
+        info!("new identity saved to {}", path.display());
+
+        Ok(identity)
+    }
+
+    pub(crate) fn generate() -> AppResult<Identity> {
+        let builder = snow::Builder::new(NOISE_PARAMS.parse()?);
+        let keypair = builder.generate_keypair()?;
+
+        let mut identity = Identity {
+            private_key: [0; 32],
+            public_key: [0; 32],
+        };
+        identity.private_key.copy_from_slice(&keypair.private);
+        identity.public_key.copy_from_slice(&keypair.public);
+
+        Ok(identity)
+    }
+}
+
+/// public keys allowed to join, one hex key per line, anything after key is comment
+pub(crate) fn load_authorized_keys(path: &Path) -> AppResult<Vec<[u8; 32]>> {
+    let content = fs::read_to_string(path)?;
+
+    content
+        .lines()
+        .map(|it| it.trim())
+        .filter(|it| !it.is_empty() && !it.starts_with('#'))
+        .map(|it| {
+            let key = it.split_whitespace().next().unwrap_or_default();
+
+            parse_key(key).ok_or(TapDemoError::IdentityParseError)
+        })
+        .collect()
+}
+
+pub(crate) fn parse_key(s: &str) -> Option<[u8; 32]> {
+    let s = s.trim();
+
+    if s.len() != 64 || !s.is_ascii() {
+        return None;
+    }
+
+    let mut key = [0; 32];
+
+    for (idx, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[idx * 2..idx * 2 + 2], 16).ok()?;
+    }
+
+    Some(key)
+}
+
+pub(crate) fn format_key(key: &[u8; 32]) -> String {
+    key.iter().map(|it| format!("{:02x}", it)).collect()
+}
+
+/// short fingerprint of public key, eg, `3f:a1:09:7c:52:e8:d4:10`
+pub(crate) fn fingerprint(public_key: &[u8; 32]) -> String {
+    let hash = Blake2s256::digest(public_key);
+
+    hash[..8]
+        .iter()
+        .map(|it| format!("{:02x}", it))
+        .collect::<Vec<String>>()
+        .join(":")
+}
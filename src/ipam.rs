@@ -0,0 +1,219 @@
+use std::convert::TryFrom;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use blake2::{Blake2s256, Digest};
+use log::{debug, info, warn};
+use serde::Deserialize;
+
+use crate::app::AppState;
+use crate::control::send_sealed;
+use crate::error::{AppResult, TapDemoError};
+use crate::msg::*;
+use crate::netlink::{add_ipv4_addr, del_ipv4_addr, if_index};
+use crate::peer::Peer;
+
+/// overlay subnet, eg, `10.0.0.0/24`
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub(crate) struct Subnet {
+    pub(crate) network: Ipv4Addr,
+    pub(crate) prefix: u8,
+}
+
+impl FromStr for Subnet {
+    type Err = TapDemoError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let pairs: Vec<&str> = s.split('/').collect();
+
+        if pairs.len() != 2 {
+            return Err(TapDemoError::SubnetParseError(s.to_owned()));
+        }
+
+        let addr: Ipv4Addr = pairs[0].parse()?;
+        let prefix: u8 = pairs[1]
+            .parse()
+            .map_err(|_| TapDemoError::SubnetParseError(s.to_owned()))?;
+
+        // need at least two hosts, and a mask
+        if prefix == 0 || prefix > 30 {
+            return Err(TapDemoError::SubnetParseError(s.to_owned()));
+        }
+
+        let mask = u32::MAX << (32 - prefix as u32);
+
+        Ok(Subnet {
+            network: Ipv4Addr::from(u32::from(addr) & mask),
+            prefix,
+        })
+    }
+}
+
+impl TryFrom<String> for Subnet {
+    type Error = TapDemoError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl Subnet {
+    /// usable hosts, without network and broadcast address
+    fn host_count(&self) -> u32 {
+        (1u32 << (32 - self.prefix as u32)) - 2
+    }
+
+    fn host(&self, n: u32) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.network) + n + 1)
+    }
+
+    /// host number derived from `hw_addr`
+    fn offset(&self, hw_addr: &[u8; 6]) -> u32 {
+        let hash = Blake2s256::digest(hw_addr);
+        let mut n = [0; 4];
+        n.copy_from_slice(&hash[..4]);
+
+        u32::from_be_bytes(n) % self.host_count()
+    }
+
+    /// first address not in `used`, probing from the one derived from `hw_addr`
+    fn allocate(&self, hw_addr: &[u8; 6], used: &[Ipv4Addr]) -> Option<Ipv4Addr> {
+        let count = self.host_count();
+        let offset = self.offset(hw_addr);
+
+        (0..count)
+            .map(|it| self.host((offset + it) % count))
+            .find(|it| !used.contains(it))
+    }
+}
+
+/// assign address of tap from overlay subnet
+///
+/// each node derives its address from its mac, addresses are exchanged in handshake,
+/// on conflict the node with the lower mac keeps the address and the other one picks next.
+pub(crate) struct Ipam {
+    subnet: Subnet,
+    if_name: String,
+    assigned: Mutex<Option<Ipv4Addr>>,
+}
+
+impl Ipam {
+    pub(crate) fn new(subnet: Subnet, if_name: &str) -> Ipam {
+        Ipam {
+            subnet,
+            if_name: if_name.to_owned(),
+            assigned: Mutex::new(None),
+        }
+    }
+
+    pub(crate) fn assigned(&self) -> Option<Ipv4Addr> {
+        *self.assigned.lock().unwrap()
+    }
+
+    /// pick address not in `used` and apply it to tap
+    pub(crate) fn assign(&self, hw_addr: &[u8; 6], used: &[Ipv4Addr]) -> AppResult<Ipv4Addr> {
+        let addr = self
+            .subnet
+            .allocate(hw_addr, used)
+            .ok_or(TapDemoError::SubnetExhausted)?;
+
+        let index = if_index(&self.if_name)?;
+        let mut assigned = self.assigned.lock().unwrap();
+
+        if let Some(old) = *assigned {
+            if old == addr {
+                return Ok(addr);
+            }
+
+            del_ipv4_addr(index, old, self.subnet.prefix)?;
+        }
+
+        add_ipv4_addr(index, addr, self.subnet.prefix)?;
+        *assigned = Some(addr);
+
+        info!("assign {}/{} to {}", addr, self.subnet.prefix, self.if_name);
+
+        Ok(addr)
+    }
+}
+
+/// called when address of `peer` is learned by handshake or announce, on conflict the
+/// node with the higher mac reassigns its address and tells peers and `peer` about it
+pub(crate) fn resolve_conflict(state: &AppState, peer: &Peer) {
+    let ipam = match state.ipam {
+        Some(ref ipam) => ipam,
+        None => return,
+    };
+
+    let addr = match ipam.assigned() {
+        Some(addr) if peer.ip_addr == Some(addr) => addr,
+        _ => return,
+    };
+
+    // lower mac keeps address, and tells `peer` to move, which may not have seen ours
+    if state.hw_addr < peer.hw_addr {
+        announce_addr(state, addr, vec![peer.clone()]);
+        return;
+    }
+
+    let mut peers: Vec<Peer> = state.peers.read().unwrap().clone();
+    if !peers.iter().any(|it| it.data_addr == peer.data_addr) {
+        peers.push(peer.clone());
+    }
+
+    let mut used: Vec<Ipv4Addr> = peers.iter().filter_map(|it| it.ip_addr).collect();
+    used.push(addr);
+
+    warn!(
+        "address {} conflicts with {:x?}, reassign",
+        addr, peer.hw_addr
+    );
+
+    match ipam.assign(&state.hw_addr, &used) {
+        Ok(addr) => announce_addr(state, addr, peers),
+        Err(e) => warn!("reassign address failed, {}", e),
+    }
+}
+
+/// tell `peers` our address, over their sessions
+fn announce_addr(state: &AppState, addr: Ipv4Addr, peers: Vec<Peer>) {
+    let msg = ControlMsg::AddrAnnounce {
+        hw_addr: state.hw_addr,
+        ip_addr: addr,
+    };
+
+    for peer in peers {
+        if let Err(e) = send_sealed(state, &msg, &peer) {
+            debug!("announce addr to {} failed, {}", peer.name, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Subnet;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_allocate() {
+        let subnet: Subnet = "10.0.0.17/30".parse().unwrap();
+        assert_eq!(subnet.network, Ipv4Addr::new(10, 0, 0, 16));
+
+        // no mask to apply, and too few hosts
+        assert!("0.0.0.0/0".parse::<Subnet>().is_err());
+        assert!("10.0.0.0/31".parse::<Subnet>().is_err());
+
+        let hw_addr = [2, 0, 0, 0, 0, 1];
+        let first = subnet.allocate(&hw_addr, &[]).unwrap();
+        let second = subnet.allocate(&hw_addr, &[first]).unwrap();
+
+        assert_ne!(first, second);
+        assert!(subnet.allocate(&hw_addr, &[first, second]).is_none());
+
+        for addr in &[first, second] {
+            assert!(addr.octets()[3] == 17 || addr.octets()[3] == 18);
+        }
+    }
+}
@@ -0,0 +1,492 @@
+use clap::{App, Arg, ArgMatches, SubCommand};
+use log::{error, info};
+use prettytable::{row, Table};
+
+use crate::admin::admin_request;
+use crate::app::run;
+use crate::config::admin_socket;
+use crate::error::TapDemoError;
+use crate::fdb::FdbRecord;
+use crate::identity::fingerprint;
+use crate::msg::AdminMsg;
+use crate::peer::Peer;
+use crate::rendezvous::{serve, DEFAULT_RENDEZVOUS_PORT};
+use crate::routing::RouteRecord;
+use crate::stats::StatsRecord;
+
+mod admin;
+mod app;
+mod config;
+mod control;
+mod crypto;
+mod detector;
+mod discovery;
+mod dispatch;
+mod error;
+mod eth;
+mod fdb;
+#[doc(hidden)]
+pub mod fuzz;
+mod geneve;
+mod handshake;
+mod identity;
+mod ipam;
+mod membership;
+mod msg;
+mod netlink;
+mod packet;
+mod peer;
+mod reload;
+mod rendezvous;
+mod routing;
+mod stats;
+mod store;
+mod storm;
+mod tap;
+#[cfg(test)]
+mod test_util;
+mod underlay;
+mod vxlan;
+
+fn format_hw_addr(hw_addr: &[u8; 6]) -> String {
+    format!(
+        "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+        hw_addr[0], hw_addr[1], hw_addr[2], hw_addr[3], hw_addr[4], hw_addr[5],
+    )
+}
+
+fn display_peers(peers: &[Peer]) {
+    let mut table = Table::new();
+    table.add_row(row!(
+        "Name",
+        "IP Address",
+        "MAC Address",
+        "Overlay Address",
+        "Fingerprint",
+        "State",
+        "Path"
+    ));
+
+    for peer in peers {
+        let hw_addr = format_hw_addr(&peer.hw_addr);
+        let fingerprint = if peer.public_key == [0; 32] {
+            "-".to_owned()
+        } else {
+            fingerprint(&peer.public_key)
+        };
+        let path = match peer.via {
+            Some(via) => match peers.iter().find(|it| it.ctl_addr == via) {
+                Some(relay) => format!("via {}", relay.name),
+                None => format!("via {}", via),
+            },
+            None => "direct".to_owned(),
+        };
+
+        table.add_row(row!(
+            peer.name,
+            peer.ctl_addr.to_string(),
+            hw_addr,
+            peer.ip_addr.map_or("-".to_owned(), |it| it.to_string()),
+            fingerprint,
+            peer.state,
+            path
+        ));
+    }
+
+    table.printstd();
+}
+
+fn display_fdb(records: &[FdbRecord]) {
+    let mut table = Table::new();
+    table.add_row(row!("MAC Address", "Peer", "Data Address", "Age"));
+
+    for record in records {
+        let hw_addr = format_hw_addr(&record.hw_addr);
+        let peer = record.peer.as_deref().unwrap_or("-");
+
+        table.add_row(row!(
+            hw_addr,
+            peer,
+            record.data_addr.to_string(),
+            format!("{}s", record.age)
+        ));
+    }
+
+    table.printstd();
+}
+
+fn display_stats(stats: &StatsRecord) {
+    let mut table = Table::new();
+    table.add_row(row!("Counter", "Value"));
+    table.add_row(row!("auth failed", stats.auth_failed));
+    table.add_row(row!("replayed", stats.replayed));
+    table.add_row(row!("malformed", stats.malformed));
+    table.add_row(row!("unknown session", stats.unknown_session));
+    table.add_row(row!("oversized", stats.oversized));
+    table.add_row(row!("control malformed", stats.ctl_malformed));
+    table.add_row(row!("control unexpected", stats.ctl_unexpected));
+    table.add_row(row!("relayed", stats.relayed));
+    table.add_row(row!("ttl expired", stats.ttl_expired));
+    table.add_row(row!("looped", stats.looped));
+    table.add_row(row!("storm dropped", stats.storm_dropped));
+    table.add_row(row!("wrong network", stats.wrong_network));
+    table.add_row(row!("unknown option", stats.unknown_option));
+    table.add_row(row!("wrong sender", stats.wrong_sender));
+    table.add_row(row!("bad magic", stats.bad_magic));
+    table.add_row(row!("wrong version", stats.wrong_version));
+    table.add_row(row!("unknown type", stats.unknown_type));
+
+    table.printstd();
+}
+
+fn display_routes(records: &[RouteRecord]) {
+    let mut table = Table::new();
+    table.add_row(row!(
+        "Destination",
+        "MAC Address",
+        "Next Hop",
+        "Latency",
+        "Hops"
+    ));
+
+    for record in records {
+        let hw_addr = format_hw_addr(&record.hw_addr);
+        let name = record.name.as_deref().unwrap_or("-");
+        let next_hop = record.next_hop.as_deref().unwrap_or("direct");
+
+        table.add_row(row!(
+            name,
+            hw_addr,
+            next_hop,
+            format!("{:.1}ms", record.metric as f64 / 1000.0),
+            record.hops
+        ));
+    }
+
+    table.printstd();
+}
+
+/// `--network` of admin commands
+fn network_arg() -> Arg<'static, 'static> {
+    Arg::with_name("network")
+        .help("network of daemon to manage, default the first one")
+        .takes_value(true)
+        .long("network")
+        .global(true)
+}
+
+/// `--network` given to admin command, found on the innermost subcommand, eg, `list`
+/// of `peers list`
+fn network<'a>(matches: &'a ArgMatches) -> Option<&'a str> {
+    let mut matches = matches;
+
+    while let (_, Some(sub)) = matches.subcommand() {
+        matches = sub;
+    }
+
+    matches.value_of("network")
+}
+
+/// entry of `tap-demo` binary
+pub fn main() {
+    simple_logger::init().unwrap();
+
+    let matches = App::new("tap demo")
+        .version("0.1")
+        .author("admin@snowstar.org")
+        .about("tap tunnel via udp")
+        .arg(
+            Arg::with_name("admin socket")
+                .help("unix socket for admin commands, default /run/tap-demo.sock")
+                .takes_value(true)
+                .long("admin-socket")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("config")
+                .help("toml config file, flags override values in it")
+                .takes_value(true)
+                .long("config")
+                .short("c")
+                .global(true),
+        )
+        .subcommand(
+            SubCommand::with_name("start")
+                .about("start main loop")
+                .arg(
+                    Arg::with_name("name")
+                        .help("node name, default from HOSTNAME")
+                        .takes_value(true)
+                        .long("name"),
+                )
+                .arg(
+                    Arg::with_name("peers")
+                        .help("peers address, eg, peer1=10.0.0.1,peer2=10.0.0.2")
+                        .takes_value(true)
+                        .required(false)
+                        .long("peers")
+                        .short("p"),
+                )
+                .arg(
+                    Arg::with_name("auto")
+                        .help("auto discovery peers in lan")
+                        .long("auto")
+                        .short("a"),
+                )
+                .arg(
+                    Arg::with_name("cluster id")
+                        .help("only discover nodes of this cluster, so overlays share a lan, default `default`")
+                        .takes_value(true)
+                        .long("cluster-id"),
+                )
+                .arg(
+                    Arg::with_name("multicast interface")
+                        .help("interface to discover on, default the one of route to discovery group")
+                        .takes_value(true)
+                        .long("multicast-interface"),
+                )
+                .arg(
+                    Arg::with_name("key")
+                        .help("pre-shared key mixed into handshake, all peers must use the same key")
+                        .takes_value(true)
+                        .long("key")
+                        .short("k")
+                        .conflicts_with("key file"),
+                )
+                .arg(
+                    Arg::with_name("key file")
+                        .help("read pre-shared key from file")
+                        .takes_value(true)
+                        .long("key-file"),
+                )
+                .arg(
+                    Arg::with_name("subnet")
+                        .help("overlay subnet, assign address to tap0 automatically, eg, 10.0.0.0/24")
+                        .takes_value(true)
+                        .long("subnet")
+                        .short("s"),
+                )
+                .arg(
+                    Arg::with_name("mtu")
+                        .help("mtu of tap0, default 1393 to fit 1500 underlay, raise it on underlay with jumbo frames")
+                        .takes_value(true)
+                        .long("mtu"),
+                )
+                .arg(
+                    Arg::with_name("identity")
+                        .help("static keypair file, generated if not exists, default /var/lib/tap-demo/identity")
+                        .takes_value(true)
+                        .long("identity"),
+                )
+                .arg(
+                    Arg::with_name("state file")
+                        .help("keep peers across restarts in this file, default /var/lib/tap-demo/peers")
+                        .takes_value(true)
+                        .long("state-file"),
+                )
+                .arg(
+                    Arg::with_name("authorized keys")
+                        .help("only accept peers with public key listed in this file")
+                        .takes_value(true)
+                        .long("authorized-keys"),
+                )
+                .arg(
+                    Arg::with_name("rendezvous")
+                        .help("rendezvous to reach nodes behind nat, eg, rv.example.com:9910")
+                        .takes_value(true)
+                        .long("rendezvous")
+                        .short("r"),
+                )
+                .arg(
+                    Arg::with_name("no relay")
+                        .help("don't relay between peers not reaching each other")
+                        .long("no-relay"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("rendezvous")
+                .about("run rendezvous for nodes behind nat")
+                .arg(
+                    Arg::with_name("port")
+                        .help("udp port, default 9910")
+                        .takes_value(true)
+                        .long("port"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("peers")
+                .about("peers manage")
+                .arg(network_arg())
+                .subcommand(SubCommand::with_name("list").about("list peers"))
+                .subcommand(
+                    SubCommand::with_name("add")
+                        .about("add peer")
+                        .arg(
+                            Arg::with_name("peer name")
+                                .takes_value(true)
+                                .required(true)
+                                .help("eg, peer-01"),
+                        )
+                        .arg(
+                            Arg::with_name("peer address")
+                                .takes_value(true)
+                                .required(true)
+                                .help("eg, 10.0.0.1:9909"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("remove")
+                        .about("remove peer")
+                        .arg(
+                            Arg::with_name("peer name")
+                                .short("n")
+                                .long("name")
+                                .help("peer name")
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("peer ip address")
+                                .short("h")
+                                .long("host")
+                                .help("peer ip address")
+                                .takes_value(true),
+                        ),
+                )
+                .subcommand(SubCommand::with_name("scan").about("scan nodes")),
+        )
+        .subcommand(
+            SubCommand::with_name("fdb")
+                .about("forwarding database")
+                .arg(network_arg())
+                .subcommand(SubCommand::with_name("list").about("list learned mac address")),
+        )
+        .subcommand(
+            SubCommand::with_name("stats")
+                .about("show data plane counters")
+                .arg(network_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("routes")
+                .about("show routes to nodes")
+                .arg(network_arg()),
+        )
+        .get_matches();
+
+    if let Some(arg) = matches.subcommand_matches("start") {
+        if let Err(e) = run(arg) {
+            error!("{}", e);
+        }
+        return;
+    }
+
+    if let Some(arg) = matches.subcommand_matches("rendezvous") {
+        let port = match arg.value_of("port").map(|it| it.parse()) {
+            Some(Ok(port)) => port,
+            Some(Err(_)) => {
+                error!("invalid port");
+                return;
+            }
+            None => DEFAULT_RENDEZVOUS_PORT,
+        };
+
+        if let Err(e) = serve(port) {
+            error!("{}", e);
+        }
+        return;
+    }
+
+    let admin_socket = match admin_socket(&matches) {
+        Ok(admin_socket) => admin_socket,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+    let admin_socket = admin_socket.as_path();
+    let network = network(&matches);
+
+    if matches.subcommand_matches("stats").is_some() {
+        match admin_request(admin_socket, network, AdminMsg::StatsRequest) {
+            Ok(AdminMsg::StatsReply(stats)) => display_stats(&stats),
+            Ok(_) => error!("response error"),
+            Err(e) => error!("{}", e),
+        }
+
+        return;
+    }
+
+    if matches.subcommand_matches("routes").is_some() {
+        match admin_request(admin_socket, network, AdminMsg::ListRoutesRequest) {
+            Ok(AdminMsg::ListRoutesReply(records)) => display_routes(&records),
+            Ok(_) => error!("response error"),
+            Err(e) => error!("{}", e),
+        }
+
+        return;
+    }
+
+    if let Some(fdb_cmd) = matches.subcommand_matches("fdb") {
+        if fdb_cmd.subcommand_matches("list").is_some() {
+            match admin_request(admin_socket, network, AdminMsg::ListFdbRequest) {
+                Ok(AdminMsg::ListFdbReply(records)) => display_fdb(&records),
+                Ok(_) => error!("response error"),
+                Err(e) => error!("{}", e),
+            }
+        }
+    }
+
+    if let Some(peers_cmd) = matches.subcommand_matches("peers") {
+        if let Some(add_peer) = peers_cmd.subcommand_matches("add") {
+            let peer_name = add_peer.value_of("peer name").unwrap();
+            let peer_address = add_peer.value_of("peer address").unwrap();
+
+            let peer: Result<Peer, TapDemoError> =
+                format!("{}={}", peer_name, peer_address).parse();
+
+            match peer {
+                Ok(peer) => {
+                    match admin_request(admin_socket, network, AdminMsg::AddPeerRequest(peer)) {
+                        Ok(AdminMsg::AddPeerReply(true)) => info!("add success"),
+                        Ok(_) => error!("add failed"),
+                        Err(e) => error!("add failed, {}", e),
+                    }
+                }
+                Err(_) => error!("error parse peer"),
+            }
+        }
+
+        if peers_cmd.subcommand_matches("list").is_some() {
+            match admin_request(admin_socket, network, AdminMsg::ListPeerRequest) {
+                Ok(AdminMsg::ListPeerReply(peers)) => display_peers(&peers),
+                Ok(_) => error!("response error"),
+                Err(e) => error!("{}", e),
+            }
+        }
+
+        if let Some(remove_peer) = peers_cmd.subcommand_matches("remove") {
+            let peer_name = remove_peer.value_of("peer name").map(|it| it.to_owned());
+            let peer_address = remove_peer
+                .value_of("peer ip address")
+                .and_then(|it| it.parse().ok());
+
+            let msg = AdminMsg::RemovePeerRequest {
+                name: peer_name,
+                addr: peer_address,
+            };
+
+            match admin_request(admin_socket, network, msg) {
+                Ok(AdminMsg::RemovePeerReply(true)) => info!("remove success"),
+                Ok(_) => error!("remove failed"),
+                Err(e) => error!("remove failed, {}", e),
+            }
+        }
+
+        if peers_cmd.subcommand_matches("scan").is_some() {
+            match admin_request(admin_socket, network, AdminMsg::ScanNodeRequest) {
+                Ok(AdminMsg::ScanNodeReply(peers)) => display_peers(&peers),
+                Ok(_) => error!("response error"),
+                Err(e) => error!("{}", e),
+            }
+        }
+    }
+}
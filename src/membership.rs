@@ -0,0 +1,897 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::{debug, info};
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+
+use crate::app::AppState;
+use crate::config::MAX_NAME_LEN;
+use crate::control::send_sealed;
+use crate::discovery::init_peer_hw_addr;
+use crate::msg::*;
+use crate::peer::{Peer, PeerState};
+
+/// peers each round of updates is sent to
+const GOSSIP_FANOUT: usize = 3;
+
+/// each update is sent `RETRANSMIT_MULT * log2(cluster size)` times
+const RETRANSMIT_MULT: u32 = 3;
+
+/// members accepted from one peer exchange reply
+const MAX_EXCHANGE_MEMBERS: usize = 256;
+
+/// replies to a peer exchange request are accepted for this long
+const EXCHANGE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// peer exchange requests from the same peer are answered at most this often
+const EXCHANGE_MIN_INTERVAL: Duration = Duration::from_secs(5);
+
+/// first retry after failing to connect a member, doubled on each failure
+const CONNECT_RETRY: Duration = Duration::from_secs(30);
+
+/// members failed to connect this many times in a row are dropped as stale
+const MAX_CONNECT_FAILURES: u32 = 5;
+
+/// highest incarnation this node refutes to, the last one left for leaving
+const MAX_INCARNATION: u64 = u64::MAX - 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum MemberState {
+    Alive,
+    Suspect,
+    Dead,
+    /// left on its own, eg, stopped by SIGTERM
+    Left,
+}
+
+/// node of the overlay as spread by gossip, identified by public key
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct Member {
+    pub(crate) name: String,
+    pub(crate) public_key: [u8; 32],
+    /// unspecified means the sender itself, at the address it's sent from
+    pub(crate) ctl_addr: SocketAddr,
+    pub(crate) state: MemberState,
+    /// raised by the member to refute suspicion, newer incarnation wins
+    pub(crate) incarnation: u64,
+}
+
+impl Member {
+    /// whether `self` replaces `old`, same incarnation is ordered as
+    /// alive < suspect < dead < left
+    fn supersedes(&self, old: &Member) -> bool {
+        if self.incarnation != old.incarnation {
+            return self.incarnation > old.incarnation;
+        }
+
+        self.state > old.state
+    }
+}
+
+struct Entry {
+    member: Member,
+    updated: Instant,
+}
+
+/// failed attempts to connect a member
+struct Backoff {
+    failures: u32,
+    retry_at: Instant,
+}
+
+struct Inner {
+    incarnation: u64,
+    members: HashMap<[u8; 32], Entry>,
+    /// updates to spread, with times sent
+    updates: Vec<(Member, u32)>,
+    /// members being connected
+    connecting: HashSet<[u8; 32]>,
+    /// members removed by admin or config, not connected again by gossip
+    ignored: HashSet<[u8; 32]>,
+    backoff: HashMap<[u8; 32], Backoff>,
+    /// peer exchange requests sent, by ctl addr, with members still accepted
+    pending: HashMap<SocketAddr, (Instant, usize)>,
+    /// when peer exchange requests were last answered, by ctl addr
+    answered: HashMap<SocketAddr, Instant>,
+    last_exchange: Instant,
+}
+
+/// swim style membership, updates are sent to a few random peers every heartbeat
+/// round
+///
+/// peers also exchange peers they are connected to, with each new peer and every
+/// `discovery_interval`, so a node reaches the overlay from one bootstrap peer
+/// without multicast.
+pub(crate) struct Membership {
+    inner: Mutex<Inner>,
+}
+
+/// what merged updates mean for local peers, done after membership lock released
+enum Action {
+    Connect(Member),
+    Alive([u8; 32]),
+    Dead([u8; 32]),
+    Remove([u8; 32]),
+}
+
+impl Membership {
+    pub(crate) fn new() -> Membership {
+        // a restarted node starts with newer incarnation than before
+        let incarnation = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|it| it.as_secs())
+            .unwrap_or(0);
+
+        Membership {
+            inner: Mutex::new(Inner {
+                incarnation,
+                members: HashMap::new(),
+                updates: Vec::new(),
+                connecting: HashSet::new(),
+                ignored: HashSet::new(),
+                backoff: HashMap::new(),
+                pending: HashMap::new(),
+                answered: HashMap::new(),
+                last_exchange: Instant::now(),
+            }),
+        }
+    }
+
+    fn local_member(state: &AppState, inner: &Inner, member_state: MemberState) -> Member {
+        let config = state.config();
+
+        Member {
+            name: config.name.clone(),
+            public_key: state.handshake.identity.public_key,
+            ctl_addr: SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), config.ctl_port),
+            state: member_state,
+            incarnation: inner.incarnation,
+        }
+    }
+
+    /// this node, alive peers it's connected to, and members known dead or left
+    ///
+    /// alive members only heard of are left out, so members nobody can reach are
+    /// not passed on forever.
+    fn exchange_members(&self, state: &AppState) -> Vec<Member> {
+        let peers: Vec<Peer> = {
+            let peers = state.peers.read().unwrap();
+            peers
+                .iter()
+                .filter(|it| it.state == PeerState::Alive)
+                .cloned()
+                .collect()
+        };
+
+        let inner = self.inner.lock().unwrap();
+        let mut members = vec![Membership::local_member(state, &inner, MemberState::Alive)];
+
+        for peer in peers {
+            let member = match inner.members.get(&peer.public_key) {
+                Some(entry) if entry.member.state == MemberState::Alive => entry.member.clone(),
+                _ => Member {
+                    name: peer.name,
+                    public_key: peer.public_key,
+                    ctl_addr: peer.ctl_addr,
+                    state: MemberState::Alive,
+                    incarnation: 0,
+                },
+            };
+
+            members.push(member);
+        }
+
+        members.extend(
+            inner
+                .members
+                .values()
+                .filter(|it| it.member.state >= MemberState::Dead)
+                .map(|it| it.member.clone()),
+        );
+
+        members
+    }
+
+    fn enqueue(inner: &mut Inner, member: Member) {
+        inner
+            .updates
+            .retain(|(it, _)| it.public_key != member.public_key);
+        inner.updates.push((member, 0));
+    }
+
+    /// updates to send this round, least sent first
+    fn take_updates(&self) -> Vec<Member> {
+        let mut inner = self.inner.lock().unwrap();
+        let cluster_size = inner.members.len() as u32 + 2;
+        let limit = RETRANSMIT_MULT * (32 - cluster_size.leading_zeros());
+
+        inner.updates.sort_by_key(|(_, sent)| *sent);
+
+        // as many as fit in one message
+        let mut len = 0;
+        let mut updates = Vec::new();
+        for (member, sent) in inner.updates.iter_mut() {
+            len += encoded_len(member);
+            if len > MAX_ITEMS_LEN {
+                break;
+            }
+
+            *sent += 1;
+            updates.push(member.clone());
+        }
+
+        inner.updates.retain(|(_, sent)| *sent < limit);
+
+        updates
+    }
+
+    /// state of peer changed by local failure detector
+    pub(crate) fn peer_state_changed(&self, peer: &Peer, peer_state: PeerState) {
+        let member_state = match peer_state {
+            PeerState::Alive => return,
+            PeerState::Suspect => MemberState::Suspect,
+            PeerState::Dead => MemberState::Dead,
+        };
+
+        let mut inner = self.inner.lock().unwrap();
+
+        let mut member = match inner.members.get(&peer.public_key) {
+            Some(entry) => entry.member.clone(),
+            None => Member {
+                name: peer.name.clone(),
+                public_key: peer.public_key,
+                ctl_addr: peer.ctl_addr,
+                state: MemberState::Alive,
+                incarnation: 0,
+            },
+        };
+
+        if member.state >= member_state {
+            return;
+        }
+
+        member.state = member_state;
+        inner.members.insert(
+            member.public_key,
+            Entry {
+                member: member.clone(),
+                updated: Instant::now(),
+            },
+        );
+        Membership::enqueue(&mut inner, member);
+    }
+
+    /// peers removed by admin or config are not connected again by gossip
+    pub(crate) fn ignore(&self, peers: &[Peer]) {
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.ignored.extend(peers.iter().map(|it| it.public_key));
+    }
+
+    pub(crate) fn unignore(&self, public_key: &[u8; 32]) {
+        self.inner.lock().unwrap().ignored.remove(public_key);
+    }
+
+    pub(crate) fn is_ignored(&self, public_key: &[u8; 32]) -> bool {
+        self.inner.lock().unwrap().ignored.contains(public_key)
+    }
+
+    /// attempt to connect member done, failed members are retried with backoff and
+    /// dropped after `MAX_CONNECT_FAILURES`
+    fn connected(&self, public_key: &[u8; 32], ok: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.connecting.remove(public_key);
+
+        if ok {
+            inner.backoff.remove(public_key);
+            return;
+        }
+
+        let failures = inner.backoff.get(public_key).map_or(0, |it| it.failures) + 1;
+
+        if failures >= MAX_CONNECT_FAILURES {
+            debug!("drop stale member {:x?}", &public_key[..4]);
+            inner.backoff.remove(public_key);
+            inner.members.remove(public_key);
+            return;
+        }
+
+        inner.backoff.insert(
+            *public_key,
+            Backoff {
+                failures,
+                retry_at: Instant::now() + CONNECT_RETRY * 2u32.pow(failures - 1),
+            },
+        );
+    }
+
+    /// whether member should be connected now, it's marked as connecting if so
+    fn should_connect(inner: &mut Inner, key: &[u8; 32]) -> bool {
+        if inner.ignored.contains(key) {
+            return false;
+        }
+
+        if let Some(backoff) = inner.backoff.get(key) {
+            if backoff.retry_at > Instant::now() {
+                return false;
+            }
+        }
+
+        inner.connecting.insert(*key)
+    }
+
+    /// record request sent to `ctl_addr`, so its replies are accepted
+    fn exchange_requested(&self, ctl_addr: SocketAddr) {
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.last_exchange = Instant::now();
+        inner
+            .pending
+            .retain(|_, (sent, _)| sent.elapsed() < EXCHANGE_TIMEOUT);
+        inner
+            .pending
+            .insert(ctl_addr, (Instant::now(), MAX_EXCHANGE_MEMBERS));
+    }
+
+    /// take members of reply from `ctl_addr` which are still accepted, none if not
+    /// requested
+    fn accept_reply(&self, ctl_addr: SocketAddr, mut members: Vec<Member>) -> Option<Vec<Member>> {
+        let mut inner = self.inner.lock().unwrap();
+
+        let (sent, remaining) = inner.pending.get_mut(&ctl_addr)?;
+        if sent.elapsed() >= EXCHANGE_TIMEOUT {
+            return None;
+        }
+
+        members.truncate(*remaining);
+        *remaining -= members.len();
+
+        Some(members)
+    }
+
+    /// whether request from `ctl_addr` should be answered, ie, not answered just before
+    fn should_answer(&self, ctl_addr: SocketAddr) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+
+        inner
+            .answered
+            .retain(|_, it| it.elapsed() < EXCHANGE_MIN_INTERVAL);
+
+        if inner.answered.contains_key(&ctl_addr) {
+            return false;
+        }
+
+        inner.answered.insert(ctl_addr, Instant::now());
+
+        true
+    }
+
+    /// merge `members` received from `src`, refute suspicion of this node
+    ///
+    /// members of gossip are connected when heard alive for the first time, those of
+    /// peer exchange whenever not connected yet.
+    fn merge_members(
+        &self,
+        state: &AppState,
+        src: SocketAddr,
+        members: Vec<Member>,
+        exchange: bool,
+    ) -> Vec<Action> {
+        let own_key = state.handshake.identity.public_key;
+        let mut inner = self.inner.lock().unwrap();
+        let mut actions = Vec::new();
+
+        for mut member in members {
+            // could not be sent on in gossip
+            if member.name.len() > MAX_NAME_LEN {
+                continue;
+            }
+
+            if member.ctl_addr.ip().is_unspecified() {
+                member.ctl_addr = src;
+            }
+
+            if member.public_key == own_key {
+                let stale =
+                    member.state != MemberState::Alive || member.incarnation > inner.incarnation;
+
+                // gossip past highest incarnation is ignored, not wrapped
+                let refuted = Some(member.incarnation)
+                    .filter(|it| stale && *it >= inner.incarnation)
+                    .and_then(|it| it.checked_add(1))
+                    .filter(|it| *it <= MAX_INCARNATION);
+
+                if let Some(incarnation) = refuted {
+                    inner.incarnation = incarnation;
+                    info!(
+                        "refute {:?} of this node, incarnation {}",
+                        member.state, inner.incarnation
+                    );
+
+                    let alive = Membership::local_member(state, &inner, MemberState::Alive);
+                    Membership::enqueue(&mut inner, alive);
+                }
+
+                continue;
+            }
+
+            let key = member.public_key;
+
+            let old_state = match inner.members.get(&key) {
+                Some(entry) if !member.supersedes(&entry.member) => {
+                    let alive = entry.member.state == MemberState::Alive;
+
+                    if exchange && alive && Membership::should_connect(&mut inner, &key) {
+                        actions.push(Action::Connect(member));
+                    }
+
+                    continue;
+                }
+                Some(entry) => Some(entry.member.state),
+                None => None,
+            };
+
+            if old_state != Some(member.state) {
+                debug!(
+                    "member {}({}) {:?}",
+                    member.name, member.ctl_addr, member.state
+                );
+            }
+
+            let action = match member.state {
+                MemberState::Alive if Membership::should_connect(&mut inner, &key) => {
+                    Some(Action::Connect(member.clone()))
+                }
+                MemberState::Alive => None,
+                MemberState::Suspect => None,
+                MemberState::Dead => Some(Action::Dead(key)),
+                MemberState::Left => Some(Action::Remove(key)),
+            };
+
+            if member.state == MemberState::Alive && old_state.is_some() {
+                actions.push(Action::Alive(key));
+            }
+            actions.extend(action);
+
+            inner.members.insert(
+                key,
+                Entry {
+                    member: member.clone(),
+                    updated: Instant::now(),
+                },
+            );
+            Membership::enqueue(&mut inner, member);
+        }
+
+        actions
+    }
+}
+
+/// handle members of gossip received from `src`
+pub(crate) fn merge(state: &Arc<AppState>, src: SocketAddr, members: Vec<Member>) {
+    apply(
+        state,
+        state.membership.merge_members(state, src, members, false),
+    );
+}
+
+fn apply(state: &Arc<AppState>, actions: Vec<Action>) {
+    for action in actions {
+        match action {
+            Action::Connect(member) => {
+                // same node, or another one at its address, already connected
+                let known = {
+                    let peers = state.peers.read().unwrap();
+                    peers.iter().any(|it| {
+                        it.public_key == member.public_key || it.ctl_addr == member.ctl_addr
+                    })
+                };
+
+                if known {
+                    state.membership.connected(&member.public_key, true);
+                } else {
+                    connect(Arc::clone(state), member);
+                }
+            }
+            Action::Alive(key) => {
+                let mut peers = state.peers.write().unwrap();
+
+                // dead peer is brought back by failure detector, with new session
+                for peer in peers.iter_mut() {
+                    if peer.public_key == key && peer.state == PeerState::Suspect {
+                        peer.state = PeerState::Alive;
+                    }
+                }
+            }
+            Action::Dead(key) => {
+                let mut peers = state.peers.write().unwrap();
+                let mut fdb = state.fdb.write().unwrap();
+
+                for peer in peers.iter_mut() {
+                    if peer.public_key == key && peer.state != PeerState::Dead {
+                        info!("peer {} dead, by gossip", peer.name);
+                        fdb.forget(&peer.data_addr);
+                        peer.state = PeerState::Dead;
+                    }
+                }
+            }
+            Action::Remove(key) => {
+                for peer in state.remove_peers_where(|it| it.public_key == key) {
+                    info!("peer {} left", peer.name);
+                }
+            }
+        }
+    }
+}
+
+/// handshake with member learned by gossip in background, then add it
+fn connect(state: Arc<AppState>, member: Member) {
+    std::thread::spawn(move || {
+        let peer: Result<Peer, _> = format!("{}={}", member.name, member.ctl_addr).parse();
+        let mut ok = false;
+
+        if let Ok(mut peer) = peer {
+            match init_peer_hw_addr(&state, &mut peer) {
+                Ok(_) => {
+                    info!("connect {} learned from peers", peer.name);
+                    state.add_peer(peer);
+                    ok = true;
+                }
+                Err(e) => debug!("connect {} failed, {}", peer.name, e),
+            }
+        }
+
+        state.membership.connected(&member.public_key, ok);
+    });
+}
+
+/// send `members` to `peer` over its session, in as many messages as needed
+fn send_members(
+    state: &AppState,
+    members: &[Member],
+    peer: &Peer,
+    wrap: fn(Vec<Member>) -> ControlMsg,
+) {
+    for chunk in chunks(members) {
+        if let Err(e) = send_sealed(state, &wrap(chunk), peer) {
+            debug!("error send members to {}, {}", peer.name, e);
+        }
+    }
+}
+
+fn peer_at(state: &AppState, ctl_addr: SocketAddr) -> Option<Peer> {
+    let peers = state.peers.read().unwrap();
+    peers.iter().find(|it| it.ctl_addr == ctl_addr).cloned()
+}
+
+/// ask peer at `ctl_addr` for peers it's connected to
+fn request_exchange(state: &AppState, ctl_addr: SocketAddr) {
+    state.membership.exchange_requested(ctl_addr);
+
+    let _ = state.ctl.send(ControlMsg::PeerExchangeRequest, ctl_addr);
+}
+
+/// exchange members with new peer
+pub(crate) fn sync(state: &AppState, ctl_addr: SocketAddr) {
+    let peer = match peer_at(state, ctl_addr) {
+        Some(peer) => peer,
+        None => return,
+    };
+
+    let members = state.membership.exchange_members(state);
+    send_members(state, &members, &peer, ControlMsg::Gossip);
+    request_exchange(state, ctl_addr);
+}
+
+/// reply members to peer at `ctl_addr`
+pub(crate) fn on_exchange_request(state: &AppState, ctl_addr: SocketAddr) {
+    if !state.membership.should_answer(ctl_addr) {
+        debug!(
+            "drop peer exchange request from {}, answered just before",
+            ctl_addr
+        );
+        return;
+    }
+
+    let peer = match peer_at(state, ctl_addr) {
+        Some(peer) => peer,
+        None => return,
+    };
+
+    let members = state.membership.exchange_members(state);
+    send_members(state, &members, &peer, ControlMsg::PeerExchangeReply);
+}
+
+/// handle reply of peer exchange from `src`, false if not requested
+pub(crate) fn on_exchange_reply(
+    state: &Arc<AppState>,
+    src: SocketAddr,
+    members: Vec<Member>,
+) -> bool {
+    let members = match state.membership.accept_reply(src, members) {
+        Some(members) => members,
+        None => return false,
+    };
+
+    apply(
+        state,
+        state.membership.merge_members(state, src, members, true),
+    );
+
+    true
+}
+
+/// ask a random peer for its peers every `discovery_interval`
+pub(crate) fn exchange_round(state: &AppState) {
+    let interval = Duration::from_secs(state.config().discovery_interval);
+
+    {
+        let inner = state.membership.inner.lock().unwrap();
+        if inner.last_exchange.elapsed() < interval {
+            return;
+        }
+    }
+
+    let target = {
+        let peers = state.peers.read().unwrap();
+        let alive: Vec<&Peer> = peers
+            .iter()
+            .filter(|it| it.state == PeerState::Alive)
+            .collect();
+
+        match alive.choose(&mut rand::thread_rng()) {
+            Some(peer) => peer.ctl_addr,
+            None => return,
+        }
+    };
+
+    request_exchange(state, target);
+}
+
+fn gossip_targets(state: &AppState) -> Vec<Peer> {
+    let peers = state.peers.read().unwrap();
+    let alive: Vec<&Peer> = peers
+        .iter()
+        .filter(|it| it.state != PeerState::Dead)
+        .collect();
+
+    alive
+        .choose_multiple(&mut rand::thread_rng(), GOSSIP_FANOUT)
+        .map(|it| (*it).clone())
+        .collect()
+}
+
+/// send pending updates to a few peers, drop dead and left members after `peer_expire`
+pub(crate) fn gossip_round(state: &AppState) {
+    {
+        let expire = Duration::from_secs(state.config().peer_expire);
+        let mut inner = state.membership.inner.lock().unwrap();
+
+        inner
+            .members
+            .retain(|_, it| it.member.state < MemberState::Dead || it.updated.elapsed() < expire);
+    }
+
+    let updates = state.membership.take_updates();
+    if updates.is_empty() {
+        return;
+    }
+
+    for target in gossip_targets(state) {
+        send_members(state, &updates, &target, ControlMsg::Gossip);
+    }
+}
+
+/// tell all peers this node is leaving
+pub(crate) fn leave(state: &AppState) {
+    let left = {
+        let mut inner = state.membership.inner.lock().unwrap();
+        inner.incarnation = inner.incarnation.saturating_add(1);
+
+        Membership::local_member(state, &inner, MemberState::Left)
+    };
+
+    let peers: Vec<Peer> = state.peers.read().unwrap().clone();
+
+    for peer in peers {
+        send_members(
+            state,
+            std::slice::from_ref(&left),
+            &peer,
+            ControlMsg::Gossip,
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    use crate::test_util::{control_node, fake_node};
+
+    fn member(key: u8, state: MemberState, incarnation: u64) -> Member {
+        Member {
+            name: format!("n{}", key),
+            public_key: [key; 32],
+            ctl_addr: "127.0.0.1:1000".parse().unwrap(),
+            state,
+            incarnation,
+        }
+    }
+
+    fn member_state(state: &AppState, key: &[u8; 32]) -> Option<MemberState> {
+        let inner = state.membership.inner.lock().unwrap();
+        inner.members.get(key).map(|it| it.member.state)
+    }
+
+    #[test]
+    fn test_merge() {
+        let (a, _a_wire) = fake_node("a", [2, 0, 0, 0, 0, 1]);
+        let src = "127.0.0.2:9909".parse().unwrap();
+        let membership = &a.membership;
+
+        let actions =
+            membership.merge_members(&a, src, vec![member(2, MemberState::Alive, 5)], false);
+        assert!(matches!(actions[..], [Action::Connect(_)]));
+
+        // older incarnation, and same state again, change nothing
+        let actions = membership.merge_members(
+            &a,
+            src,
+            vec![
+                member(2, MemberState::Suspect, 4),
+                member(2, MemberState::Alive, 5),
+            ],
+            false,
+        );
+        assert!(actions.is_empty());
+        assert_eq!(member_state(&a, &[2; 32]), Some(MemberState::Alive));
+
+        membership.merge_members(&a, src, vec![member(2, MemberState::Suspect, 5)], false);
+        assert_eq!(member_state(&a, &[2; 32]), Some(MemberState::Suspect));
+
+        // refuted by member itself
+        let actions =
+            membership.merge_members(&a, src, vec![member(2, MemberState::Alive, 6)], false);
+        assert!(matches!(actions[..], [Action::Alive(_)]));
+
+        let actions =
+            membership.merge_members(&a, src, vec![member(2, MemberState::Dead, 6)], false);
+        assert!(matches!(actions[..], [Action::Dead(_)]));
+
+        // sender itself, address taken from packet
+        let mut sender = member(3, MemberState::Alive, 1);
+        sender.ctl_addr = "0.0.0.0:9909".parse().unwrap();
+        membership.merge_members(&a, src, vec![sender], false);
+        let inner = membership.inner.lock().unwrap();
+        assert_eq!(inner.members[&[3; 32]].member.ctl_addr, src);
+        drop(inner);
+
+        // suspicion of this node is refuted with newer incarnation
+        let own =
+            Membership::local_member(&a, &membership.inner.lock().unwrap(), MemberState::Suspect);
+        membership.merge_members(&a, src, vec![own.clone()], false);
+        let updates = membership.take_updates();
+        let refuted = updates
+            .iter()
+            .find(|it| it.public_key == own.public_key)
+            .unwrap();
+        assert_eq!(refuted.state, MemberState::Alive);
+        assert_eq!(refuted.incarnation, own.incarnation + 1);
+
+        // up to highest incarnation, gossip past it is ignored
+        let incarnation = |own: &Member, incarnation| {
+            let mut own = own.clone();
+            own.incarnation = incarnation;
+            membership.merge_members(&a, src, vec![own], false);
+            membership.inner.lock().unwrap().incarnation
+        };
+        assert_eq!(incarnation(&own, MAX_INCARNATION - 1), MAX_INCARNATION);
+        assert_eq!(incarnation(&own, MAX_INCARNATION), MAX_INCARNATION);
+        assert_eq!(incarnation(&own, u64::MAX), MAX_INCARNATION);
+    }
+
+    #[test]
+    fn test_exchange() {
+        let (a, _a_wire) = fake_node("a", [2, 0, 0, 0, 0, 1]);
+        let src = "127.0.0.2:9909".parse().unwrap();
+        let membership = &a.membership;
+        let alive = member(2, MemberState::Alive, 5);
+
+        // replies only accepted when requested, up to a limit
+        assert!(membership.accept_reply(src, vec![alive.clone()]).is_none());
+
+        membership.exchange_requested(src);
+        let accepted = membership.accept_reply(src, vec![alive.clone(); 200]);
+        assert_eq!(accepted.unwrap().len(), 200);
+        let accepted = membership.accept_reply(src, vec![alive.clone(); 100]);
+        assert_eq!(accepted.unwrap().len(), MAX_EXCHANGE_MEMBERS - 200);
+
+        // known member is connected again by exchange, not by gossip
+        let actions = membership.merge_members(&a, src, vec![alive.clone()], false);
+        assert!(matches!(actions[..], [Action::Connect(_)]));
+        membership.connected(&alive.public_key, false);
+
+        let actions = membership.merge_members(&a, src, vec![alive.clone()], false);
+        assert!(actions.is_empty());
+
+        // not before retry is due
+        let actions = membership.merge_members(&a, src, vec![alive.clone()], true);
+        assert!(actions.is_empty());
+
+        // only alive peers and tombstones are passed on, not members just heard of
+        membership.merge_members(&a, src, vec![member(3, MemberState::Dead, 1)], false);
+        let members = membership.exchange_members(&a);
+        let keys: Vec<u8> = members.iter().map(|it| it.public_key[0]).collect();
+        assert_eq!(keys, vec![a.handshake.identity.public_key[0], 3]);
+
+        // dropped as stale after failing again and again
+        for _ in 1..MAX_CONNECT_FAILURES {
+            {
+                let mut inner = membership.inner.lock().unwrap();
+                inner.backoff.get_mut(&alive.public_key).unwrap().retry_at = Instant::now();
+            }
+
+            let actions = membership.merge_members(&a, src, vec![alive.clone()], true);
+            assert!(matches!(actions[..], [Action::Connect(_)]));
+            membership.connected(&alive.public_key, false);
+        }
+        assert_eq!(member_state(&a, &alive.public_key), None);
+
+        // requests answered once in a while
+        assert!(membership.should_answer(src));
+        assert!(!membership.should_answer(src));
+    }
+
+    fn join(state: &Arc<AppState>, other: &AppState) {
+        let ctl_addr = format!("127.0.0.1:{}", other.config().ctl_port);
+        let mut peer: Peer = format!("{}={}", other.config().name, ctl_addr)
+            .parse()
+            .unwrap();
+
+        init_peer_hw_addr(state, &mut peer).unwrap();
+        assert!(state.add_peer(peer));
+    }
+
+    fn has_peer(state: &AppState, other: &AppState) -> bool {
+        let peers = state.peers.read().unwrap();
+        let key = other.handshake.identity.public_key;
+
+        peers.iter().any(|it| it.public_key == key)
+    }
+
+    fn wait_for<F: Fn() -> bool>(f: F) -> bool {
+        let start = Instant::now();
+
+        while start.elapsed() < Duration::from_secs(5) {
+            if f() {
+                return true;
+            }
+
+            std::thread::sleep(Duration::from_millis(50));
+        }
+
+        false
+    }
+
+    #[test]
+    fn test_join_and_leave() {
+        let (a, _a_wire) = control_node("a", [2, 0, 0, 0, 0, 1]);
+        let (b, _b_wire) = control_node("b", [2, 0, 0, 0, 0, 2]);
+        let (c, _c_wire) = control_node("c", [2, 0, 0, 0, 0, 3]);
+
+        join(&b, &c);
+
+        // a only knows b, and learns c from it
+        join(&a, &b);
+        assert!(has_peer(&b, &a));
+        assert!(wait_for(|| has_peer(&a, &c) && has_peer(&c, &a)));
+
+        leave(&a);
+        assert!(wait_for(|| !has_peer(&b, &a) && !has_peer(&c, &a)));
+    }
+}
@@ -1,12 +1,89 @@
+use bincode::Options;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
+use crate::error::AppResult;
+use crate::fdb::FdbRecord;
+use crate::membership::Member;
 use crate::peer::Peer;
-use std::net::IpAddr;
+use crate::rendezvous::NodeInfo;
+use crate::routing::{RouteAdvert, RouteRecord};
+use crate::stats::StatsRecord;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 
-#[derive(Serialize, Deserialize, Debug)]
+/// largest control message accepted, larger ones can't be valid
+pub(crate) const MAX_MSG_LEN: usize = 1024;
+
+/// room for items of one message, the rest is left for message itself, sealing and
+/// forwarding through relays
+pub(crate) const MAX_ITEMS_LEN: usize = MAX_MSG_LEN - 128;
+
+/// length of `item` encoded in a message
+pub(crate) fn encoded_len<T: Serialize>(item: &T) -> usize {
+    bincode::serialized_size(item).map_or(usize::MAX, |it| it as usize)
+}
+
+/// split `items` into chunks encoded in `MAX_ITEMS_LEN` each, items too large to fit
+/// alone are left out
+pub(crate) fn chunks<T: Serialize + Clone>(items: &[T]) -> Vec<Vec<T>> {
+    let mut chunks = Vec::new();
+    let mut chunk = Vec::new();
+    let mut len = 0;
+
+    for item in items {
+        let size = encoded_len(item);
+        if size > MAX_ITEMS_LEN {
+            continue;
+        }
+
+        if len + size > MAX_ITEMS_LEN {
+            chunks.push(std::mem::take(&mut chunk));
+            len = 0;
+        }
+
+        chunk.push(item.clone());
+        len += size;
+    }
+
+    if !chunk.is_empty() {
+        chunks.push(chunk);
+    }
+
+    chunks
+}
+
+/// decode message received from network, rejects oversized and trailing input
+pub(crate) fn decode<T: DeserializeOwned>(data: &[u8]) -> AppResult<T> {
+    let msg = bincode::options()
+        .with_fixint_encoding()
+        .with_limit(MAX_MSG_LEN as u64)
+        .deserialize(data)?;
+
+    Ok(msg)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub(crate) struct MsgDiscoveryReply {
     pub(crate) name: String,
     pub(crate) hw_addr: [u8; 6],
+    pub(crate) data_port: u16,
+    /// nodes of other overlays on the same lan are ignored
+    pub(crate) cluster_id: String,
+}
+
+/// carried in noise handshake messages
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct HandshakePayload {
+    pub(crate) name: String,
+    pub(crate) hw_addr: [u8; 6],
+    pub(crate) ctl_port: u16,
+    pub(crate) data_port: u16,
+    /// overlay address assigned by ipam
+    pub(crate) ip_addr: Option<Ipv4Addr>,
+    /// local session index, sent back in header of every data frame
+    pub(crate) index: u32,
+    /// nodes only pair within the same network
+    pub(crate) network_id: u32,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -14,7 +91,7 @@ pub(crate) struct Msg {
     pub(crate) inner: ControlMsg,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub(crate) enum ControlMsg {
     DiscoveryRequest,
     DiscoveryReply(MsgDiscoveryReply),
@@ -25,6 +102,66 @@ pub(crate) enum ControlMsg {
     Ping,
     Pong,
 
+    HandshakeInit(Vec<u8>),
+    HandshakeResponse(Vec<u8>),
+    HandshakeFinish(Vec<u8>),
+    HandshakeComplete(bool),
+
+    AddrAnnounce {
+        hw_addr: [u8; 6],
+        ip_addr: Ipv4Addr,
+    },
+
+    /// ask peer to ping target on our behalf, relayed back as pong if target answers
+    PingReq(SocketAddr),
+
+    /// membership updates, spread by gossip
+    Gossip(Vec<Member>),
+    /// ask for peers connected to
+    PeerExchangeRequest,
+    PeerExchangeReply(Vec<Member>),
+
+    /// register with rendezvous from control socket, and from data socket with key only,
+    /// so it sees public endpoints of both
+    Register {
+        name: String,
+        public_key: [u8; 32],
+    },
+    RegisterData([u8; 32]),
+    /// ask rendezvous for other nodes registered
+    ListNodes,
+    Nodes(Vec<NodeInfo>),
+    /// ask rendezvous to have node of given key and us punch towards each other
+    PunchRequest([u8; 32]),
+    Punch(NodeInfo),
+    /// ask rendezvous to relay between node of given key and us
+    RelayRequest([u8; 32]),
+    RelayReady(NodeInfo),
+
+    /// ask peer to pass msg on towards node at `to`, for nodes not reaching each other
+    /// directly. `from` is filled by the first relay, as it sees the sender
+    Forward {
+        to: SocketAddr,
+        from: SocketAddr,
+        ttl: u8,
+        msg: Box<ControlMsg>,
+    },
+    /// msg passed on by peer, from node at given ctl addr
+    Forwarded {
+        from: SocketAddr,
+        msg: Box<ControlMsg>,
+    },
+
+    /// routes of the sender, every heartbeat round
+    Routes(Vec<RouteAdvert>),
+
+    /// msg sealed with data session of the sender, for changes only a peer may make
+    Sealed(Vec<u8>),
+}
+
+/// admin requests from cli, carried on unix socket only
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) enum AdminMsg {
     AddPeerRequest(Peer),
     AddPeerReply(bool),
 
@@ -39,4 +176,72 @@ pub(crate) enum ControlMsg {
 
     ScanNodeRequest,
     ScanNodeReply(Vec<Peer>),
+
+    ListFdbRequest,
+    ListFdbReply(Vec<FdbRecord>),
+
+    StatsRequest,
+    StatsReply(StatsRecord),
+
+    ListRoutesRequest,
+    ListRoutesReply(Vec<RouteRecord>),
+
+    /// request for the named network, others go to the first network of daemon
+    Network(String, Box<AdminMsg>),
+    NoSuchNetwork(String),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let msg = Msg {
+            inner: ControlMsg::HwAddrReply([2, 0, 0, 0, 0, 1]),
+        };
+        let data = bincode::serialize(&msg).unwrap();
+
+        assert!(matches!(
+            decode::<Msg>(&data),
+            Ok(Msg {
+                inner: ControlMsg::HwAddrReply(_)
+            })
+        ));
+
+        // truncated, trailing garbage, unknown variant
+        assert!(decode::<Msg>(&data[..data.len() - 1]).is_err());
+        assert!(decode::<Msg>(&[&data[..], &[0]].concat()).is_err());
+        assert!(decode::<Msg>(&[0xff; 4]).is_err());
+
+        // length prefix far beyond limit
+        let mut data = 6u32.to_le_bytes().to_vec(); // HandshakeInit
+        data.extend_from_slice(&u64::MAX.to_le_bytes());
+        assert!(decode::<Msg>(&data).is_err());
+    }
+
+    #[test]
+    fn test_chunks() {
+        let items: Vec<Vec<u8>> = (0..40).map(|it| vec![0; it * 5]).collect();
+        let packed = chunks(&items);
+
+        assert!(packed.len() > 1);
+        assert_eq!(packed.iter().map(|it| it.len()).sum::<usize>(), items.len());
+        assert!(packed.iter().all(|it| encoded_len(it) <= MAX_ITEMS_LEN + 8));
+
+        // largest items of names, keys and addresses in a full chunk, sealed and
+        // forwarded, still decode
+        let to = SocketAddr::from(([0xfe80, 0, 0, 0, 0, 0, 0, 1], 9909));
+        let msg = ControlMsg::Forward {
+            to,
+            from: to,
+            ttl: 16,
+            msg: Box::new(ControlMsg::Sealed(vec![0; 4 + 8 + MAX_ITEMS_LEN + 40])),
+        };
+        let data = bincode::serialize(&Msg { inner: msg }).unwrap();
+        assert!(decode::<Msg>(&data).is_ok());
+
+        let too_large = vec![vec![0u8; MAX_ITEMS_LEN]];
+        assert!(chunks(&too_large).is_empty());
+    }
 }
@@ -0,0 +1,151 @@
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::net::Ipv4Addr;
+
+use libc::{
+    c_void, sockaddr, sockaddr_nl, AF_INET, AF_NETLINK, IFA_ADDRESS, IFA_LOCAL, NETLINK_ROUTE,
+    NLMSG_ERROR, NLM_F_ACK, NLM_F_CREATE, NLM_F_REPLACE, NLM_F_REQUEST, RTM_DELADDR, RTM_NEWADDR,
+    SOCK_CLOEXEC, SOCK_RAW,
+};
+
+const NLMSG_HDRLEN: usize = 16;
+
+/// minimal rtnetlink client, one request per socket
+struct NetlinkSocket(i32);
+
+impl NetlinkSocket {
+    fn open() -> io::Result<NetlinkSocket> {
+        let fd = unsafe { libc::socket(AF_NETLINK, SOCK_RAW | SOCK_CLOEXEC, NETLINK_ROUTE) };
+
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(NetlinkSocket(fd))
+    }
+
+    /// send request and wait for ack
+    fn request(&self, msg_type: u16, flags: u16, payload: &[u8]) -> io::Result<()> {
+        let mut msg = Vec::with_capacity(NLMSG_HDRLEN + payload.len());
+        msg.extend_from_slice(&((NLMSG_HDRLEN + payload.len()) as u32).to_ne_bytes());
+        msg.extend_from_slice(&msg_type.to_ne_bytes());
+        msg.extend_from_slice(&(flags | NLM_F_REQUEST as u16 | NLM_F_ACK as u16).to_ne_bytes());
+        msg.extend_from_slice(&1u32.to_ne_bytes()); // seq
+        msg.extend_from_slice(&0u32.to_ne_bytes()); // pid, kernel fills
+        msg.extend_from_slice(payload);
+
+        let mut addr: sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = AF_NETLINK as u16;
+
+        let rc = unsafe {
+            libc::sendto(
+                self.0,
+                msg.as_ptr() as *const c_void,
+                msg.len(),
+                0,
+                &addr as *const sockaddr_nl as *const sockaddr,
+                mem::size_of::<sockaddr_nl>() as u32,
+            )
+        };
+
+        if rc < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut buff = [0u8; 1024];
+        let size = unsafe { libc::recv(self.0, buff.as_mut_ptr() as *mut c_void, buff.len(), 0) };
+
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        if (size as usize) < NLMSG_HDRLEN + 4 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "short netlink reply",
+            ));
+        }
+
+        let mut reply_type = [0; 2];
+        reply_type.copy_from_slice(&buff[4..6]);
+
+        if u16::from_ne_bytes(reply_type) != NLMSG_ERROR as u16 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unexpected netlink reply",
+            ));
+        }
+
+        let mut errno = [0; 4];
+        errno.copy_from_slice(&buff[NLMSG_HDRLEN..NLMSG_HDRLEN + 4]);
+
+        match i32::from_ne_bytes(errno) {
+            0 => Ok(()),
+            errno => Err(io::Error::from_raw_os_error(-errno)),
+        }
+    }
+}
+
+impl Drop for NetlinkSocket {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.0);
+        }
+    }
+}
+
+fn push_attr(buff: &mut Vec<u8>, attr_type: u16, data: &[u8]) {
+    let len = 4 + data.len();
+
+    buff.extend_from_slice(&(len as u16).to_ne_bytes());
+    buff.extend_from_slice(&attr_type.to_ne_bytes());
+    buff.extend_from_slice(data);
+
+    // attributes are aligned to 4 bytes
+    buff.resize(buff.len() + ((4 - len % 4) % 4), 0);
+}
+
+fn ifaddr_msg(if_index: u32, addr: Ipv4Addr, prefix: u8) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(24);
+
+    // struct ifaddrmsg
+    payload.push(AF_INET as u8);
+    payload.push(prefix);
+    payload.push(0); // flags
+    payload.push(0); // scope, universe
+    payload.extend_from_slice(&if_index.to_ne_bytes());
+
+    push_attr(&mut payload, IFA_LOCAL, &addr.octets());
+    push_attr(&mut payload, IFA_ADDRESS, &addr.octets());
+
+    payload
+}
+
+pub(crate) fn if_index(if_name: &str) -> io::Result<u32> {
+    let name = CString::new(if_name)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "bad interface name"))?;
+
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(io::Error::last_os_error()),
+        index => Ok(index),
+    }
+}
+
+/// add or replace ipv4 address on interface, like `ip addr replace`
+pub(crate) fn add_ipv4_addr(if_index: u32, addr: Ipv4Addr, prefix: u8) -> io::Result<()> {
+    let sock = NetlinkSocket::open()?;
+
+    sock.request(
+        RTM_NEWADDR,
+        (NLM_F_CREATE | NLM_F_REPLACE) as u16,
+        &ifaddr_msg(if_index, addr, prefix),
+    )
+}
+
+/// remove ipv4 address from interface, like `ip addr del`
+pub(crate) fn del_ipv4_addr(if_index: u32, addr: Ipv4Addr, prefix: u8) -> io::Result<()> {
+    let sock = NetlinkSocket::open()?;
+
+    sock.request(RTM_DELADDR, 0, &ifaddr_msg(if_index, addr, prefix))
+}
@@ -0,0 +1,134 @@
+//! versioned header in front of every datagram on data port, so datagrams of another
+//! protocol, version or network are told apart before anything else is done with them
+
+use crate::crypto::OpenError;
+
+/// magic (2 bytes) + version + type + flags (2 bytes) + reserved (2 bytes) + network id
+/// (4 bytes)
+pub(crate) const PACKET_HEADER_LEN: usize = 12;
+
+const MAGIC: [u8; 2] = *b"td";
+
+/// raised on incompatible change of header or of what follows it
+pub(crate) const VERSION: u8 = 1;
+
+/// what follows the header, types after these are for later versions, eg, fragments,
+/// and are rejected
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum PacketType {
+    /// frame sealed with session of receiver
+    Data = 1,
+    /// data packet passed on through peers, behind relay header
+    Relay = 2,
+    /// nothing follows, keeps nat mapping towards peer open
+    Keepalive = 3,
+}
+
+impl PacketType {
+    fn from_u8(value: u8) -> Option<PacketType> {
+        match value {
+            1 => Some(PacketType::Data),
+            2 => Some(PacketType::Relay),
+            3 => Some(PacketType::Keepalive),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct PacketHeader {
+    pub(crate) kind: PacketType,
+    /// none defined by this version, sent as 0 and ignored, so later versions may add
+    /// some without breaking older nodes
+    pub(crate) flags: u16,
+    pub(crate) network_id: u32,
+}
+
+impl PacketHeader {
+    pub(crate) fn new(kind: PacketType, network_id: u32) -> PacketHeader {
+        PacketHeader {
+            kind,
+            flags: 0,
+            network_id,
+        }
+    }
+
+    pub(crate) fn encode(&self) -> [u8; PACKET_HEADER_LEN] {
+        let mut header = [0; PACKET_HEADER_LEN];
+        header[..2].copy_from_slice(&MAGIC);
+        header[2] = VERSION;
+        header[3] = self.kind as u8;
+        header[4..6].copy_from_slice(&self.flags.to_be_bytes());
+        header[8..].copy_from_slice(&self.network_id.to_be_bytes());
+
+        header
+    }
+
+    /// header of datagram received, checked in order of magic, version, type and
+    /// network, reserved bytes are ignored
+    pub(crate) fn parse(data: &[u8], network_id: u32) -> Result<PacketHeader, OpenError> {
+        if data.len() < PACKET_HEADER_LEN {
+            return Err(OpenError::Malformed);
+        }
+
+        if data[..2] != MAGIC {
+            return Err(OpenError::BadMagic);
+        }
+
+        if data[2] != VERSION {
+            return Err(OpenError::WrongVersion);
+        }
+
+        let kind = PacketType::from_u8(data[3]).ok_or(OpenError::UnknownType)?;
+        let header = PacketHeader {
+            kind,
+            flags: u16::from_be_bytes([data[4], data[5]]),
+            network_id: u32::from_be_bytes([data[8], data[9], data[10], data[11]]),
+        };
+
+        if header.network_id != network_id {
+            return Err(OpenError::WrongNetwork);
+        }
+
+        Ok(header)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_packet_header() {
+        let header = PacketHeader::new(PacketType::Relay, 7);
+        let data = header.encode();
+        assert_eq!(&data, &[b't', b'd', 1, 2, 0, 0, 0, 0, 0, 0, 0, 7]);
+        assert_eq!(PacketHeader::parse(&data, 7).unwrap(), header);
+
+        assert!(matches!(
+            PacketHeader::parse(&data, 8),
+            Err(OpenError::WrongNetwork)
+        ));
+        assert!(matches!(
+            PacketHeader::parse(&data[..11], 7),
+            Err(OpenError::Malformed)
+        ));
+
+        // flags of later versions and reserved bytes are ignored
+        let mut later = data;
+        later[4..8].copy_from_slice(&[0xff; 4]);
+        assert_eq!(PacketHeader::parse(&later, 7).unwrap().flags, 0xffff);
+
+        for (i, byte, expected) in &[
+            (0, b'x', "BadMagic"),
+            (2, 2, "WrongVersion"),
+            (3, 0, "UnknownType"),
+            (3, 4, "UnknownType"),
+        ] {
+            let mut bad = data;
+            bad[*i] = *byte;
+            let e = PacketHeader::parse(&bad, 7).unwrap_err();
+            assert_eq!(&format!("{:?}", e), expected);
+        }
+    }
+}
@@ -1,9 +1,31 @@
-use std::net::{SocketAddr, ToSocketAddrs};
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddr, ToSocketAddrs};
 use std::str::FromStr;
 
 use serde::{Deserialize, Serialize};
 
 use crate::error::TapDemoError;
+use crate::underlay::prefer_ipv4;
+
+/// liveness of peer, as seen by failure detector
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PeerState {
+    Alive,
+    /// not answering probes, still sent to
+    Suspect,
+    /// suspect for too long, not sent to until answering again
+    Dead,
+}
+
+impl fmt::Display for PeerState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PeerState::Alive => write!(f, "alive"),
+            PeerState::Suspect => write!(f, "suspect"),
+            PeerState::Dead => write!(f, "dead"),
+        }
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub(crate) struct Peer {
@@ -11,6 +33,13 @@ pub(crate) struct Peer {
     pub(crate) ctl_addr: SocketAddr,
     pub(crate) data_addr: SocketAddr,
     pub(crate) hw_addr: [u8; 6],
+    /// static public key, learned by handshake
+    pub(crate) public_key: [u8; 32],
+    /// overlay address assigned by ipam
+    pub(crate) ip_addr: Option<Ipv4Addr>,
+    pub(crate) state: PeerState,
+    /// ctl addr of peer relaying to it, when not reached directly
+    pub(crate) via: Option<SocketAddr>,
 }
 
 impl FromStr for Peer {
@@ -25,19 +54,26 @@ impl FromStr for Peer {
 
         let socket_addrs = pairs[1].to_socket_addrs()?;
 
-        let ctl_addr = socket_addrs
-            .into_iter()
-            .find(|it| it.is_ipv4())
-            .ok_or(TapDemoError::PeerParseError)?;
+        let ctl_addr = prefer_ipv4(socket_addrs).ok_or(TapDemoError::PeerParseError)?;
 
-        let mut data_addr = ctl_addr.clone();
-        data_addr.set_port(data_addr.port() - 1);
+        // data port of peer is learned in handshake, guess the default one until then
+        let mut data_addr = ctl_addr;
+        data_addr.set_port(
+            ctl_addr
+                .port()
+                .checked_sub(1)
+                .ok_or(TapDemoError::PeerParseError)?,
+        );
 
         Ok(Peer {
             name: pairs[0].to_owned(),
             ctl_addr,
             data_addr,
             hw_addr: [0; 6],
+            public_key: [0; 32],
+            ip_addr: None,
+            state: PeerState::Alive,
+            via: None,
         })
     }
 }
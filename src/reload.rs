@@ -0,0 +1,208 @@
+use std::io;
+use std::mem;
+use std::net::SocketAddr;
+use std::ptr;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use clap::ArgMatches;
+use libc::{c_int, sigset_t, SIGHUP, SIGINT, SIGTERM, SIG_BLOCK};
+use log::{debug, error, info, warn};
+
+use crate::app::AppState;
+use crate::config::Config;
+use crate::discovery::init_peers_hw_addr;
+use crate::error::AppResult;
+use crate::membership::leave;
+use crate::peer::Peer;
+
+fn signal_set() -> sigset_t {
+    unsafe {
+        let mut set: sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, SIGHUP);
+        libc::sigaddset(&mut set, SIGINT);
+        libc::sigaddset(&mut set, SIGTERM);
+
+        set
+    }
+}
+
+/// block SIGHUP, SIGINT and SIGTERM in calling thread, threads spawned later inherit
+/// the mask, so they are only taken by `sigwait` in signal thread
+pub(crate) fn block_signals() -> io::Result<()> {
+    let set = signal_set();
+
+    match unsafe { libc::pthread_sigmask(SIG_BLOCK, &set, ptr::null_mut()) } {
+        0 => Ok(()),
+        rc => Err(io::Error::from_raw_os_error(rc)),
+    }
+}
+
+fn wait_signal() -> io::Result<c_int> {
+    let set = signal_set();
+    let mut sig = 0;
+
+    match unsafe { libc::sigwait(&set, &mut sig) } {
+        0 => Ok(sig),
+        rc => Err(io::Error::from_raw_os_error(rc)),
+    }
+}
+
+/// re-read config file on SIGHUP, `args` of `start` still override file values,
+/// tell peers of every network this node leaves and exit on SIGINT or SIGTERM
+pub(crate) fn signal_thread(
+    states: Vec<Arc<AppState>>,
+    args: ArgMatches<'static>,
+) -> JoinHandle<()> {
+    debug!("signal_thread start");
+
+    std::thread::spawn(move || loop {
+        let sig = match wait_signal() {
+            Ok(sig) => sig,
+            Err(e) => {
+                error!("error wait signal, {}", e);
+                return;
+            }
+        };
+
+        if sig != SIGHUP {
+            info!("signal {} received, leave", sig);
+            for state in &states {
+                leave(state);
+            }
+            std::process::exit(0);
+        }
+
+        info!("SIGHUP received, reload config");
+
+        if let Err(e) = reload_all(&states, &args) {
+            error!("reload config failed, {}", e);
+        }
+    })
+}
+
+fn reload_all(states: &[Arc<AppState>], args: &ArgMatches) -> AppResult<()> {
+    if args.value_of("config").is_none() {
+        warn!("no config file given, nothing to reload");
+        return Ok(());
+    }
+
+    let networks = Config::load(args)?.networks();
+
+    for state in states {
+        let network = state.config().network.clone();
+
+        match networks.iter().find(|it| it.network == network) {
+            Some(new) => reload(state, new.clone())?,
+            None => warn!("network {} removed from config, restart to apply", network),
+        }
+    }
+
+    if networks.len() > states.len() {
+        warn!("networks added to config, restart to apply");
+    }
+
+    Ok(())
+}
+
+fn reload(state: &Arc<AppState>, new: Config) -> AppResult<()> {
+    let old = state.config();
+
+    // others are used to create tap and sockets at start
+    let applied = Config {
+        heartbeat_interval: new.heartbeat_interval,
+        probe_timeout: new.probe_timeout,
+        suspect_timeout: new.suspect_timeout,
+        discovery_interval: new.discovery_interval,
+        broadcast_limit: new.broadcast_limit,
+        peers: new.peers.clone(),
+        ..(*old).clone()
+    };
+
+    if applied != new {
+        warn!("only peers, heartbeat_interval, probe_timeout, suspect_timeout, discovery_interval and broadcast_limit are reloaded, restart to apply others");
+    }
+
+    let peers = applied.static_peers()?;
+    let old_peers = old.static_peers()?;
+    state.set_config(applied);
+
+    // by address, name of peer is the one it gives in handshake
+    for removed in old_peers
+        .iter()
+        .filter(|old| !peers.iter().any(|it| it.ctl_addr == old.ctl_addr))
+    {
+        info!("remove peer {}({})", removed.name, removed.ctl_addr);
+
+        let removed = state.remove_peers_where(|it| it.ctl_addr == removed.ctl_addr);
+        state.membership.ignore(&removed);
+    }
+
+    let known: Vec<SocketAddr> = {
+        let peers = state.peers.read().unwrap();
+        peers.iter().map(|it| it.ctl_addr).collect()
+    };
+
+    let added: Vec<Peer> = peers
+        .into_iter()
+        .filter(|it| !known.contains(&it.ctl_addr))
+        .collect();
+
+    if !added.is_empty() {
+        let state = state.clone();
+        std::thread::spawn(move || init_peers_hw_addr(state, added));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::PeerConfig;
+    use crate::test_util::{connect, fake_node};
+
+    #[test]
+    fn test_reload_peers() {
+        let (a, _a_wire) = fake_node("a", [2, 0, 0, 0, 0, 1]);
+        let (b, _b_wire) = fake_node("b", [2, 0, 0, 0, 0, 2]);
+        connect(&a, &b, 1, 2);
+
+        // configured under another name than it gives in handshake
+        let b_addr = a.peers.read().unwrap()[0].ctl_addr;
+        let configured = PeerConfig {
+            name: "node-b".to_owned(),
+            addr: b_addr.to_string(),
+        };
+        a.set_config(Config {
+            peers: vec![configured.clone()],
+            ..(*a.config()).clone()
+        });
+
+        // renamed only, still the same peer
+        let renamed = PeerConfig {
+            name: "b".to_owned(),
+            ..configured
+        };
+        reload(
+            &a,
+            Config {
+                peers: vec![renamed],
+                ..(*a.config()).clone()
+            },
+        )
+        .unwrap();
+        assert_eq!(a.peers.read().unwrap().len(), 1);
+
+        reload(
+            &a,
+            Config {
+                peers: Vec::new(),
+                ..(*a.config()).clone()
+            },
+        )
+        .unwrap();
+        assert!(a.peers.read().unwrap().is_empty());
+    }
+}
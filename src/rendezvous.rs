@@ -0,0 +1,701 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bincode::serialize;
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+
+use crate::app::AppState;
+use crate::config::MAX_NAME_LEN;
+use crate::control::REPLY_TIMEOUT;
+use crate::detector::ping;
+use crate::discovery::init_peer_hw_addr;
+use crate::error::{AppResult, TapDemoError};
+use crate::msg::*;
+use crate::packet::{PacketHeader, PacketType};
+use crate::peer::{Peer, PeerState};
+use crate::underlay::{bind_socket, canonical, prefer_ipv4};
+
+pub(crate) const DEFAULT_RENDEZVOUS_PORT: u16 = 9910;
+
+/// registrations not refreshed for this long are dropped, nodes refresh every
+/// `heartbeat_interval`
+const REGISTRATION_TIMEOUT: Duration = Duration::from_secs(60);
+
+const MAX_RELAYS: usize = 64;
+
+/// relays without traffic for this long are closed
+const RELAY_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// nodes tried to connect through rendezvous are not tried again for this long
+const CONNECT_RETRY: Duration = Duration::from_secs(60);
+
+/// pings sent while waiting for a path to open, each waits `PUNCH_INTERVAL`
+const PUNCH_ATTEMPTS: u32 = 10;
+const PUNCH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// largest udp payload over ipv4
+const MAX_DATAGRAM_LEN: usize = 65507;
+
+/// node registered with rendezvous, at endpoints seen by rendezvous, ie, outside nat
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct NodeInfo {
+    pub(crate) name: String,
+    pub(crate) public_key: [u8; 32],
+    pub(crate) ctl_addr: SocketAddr,
+    pub(crate) data_addr: SocketAddr,
+}
+
+struct Registration {
+    name: String,
+    ctl_addr: SocketAddr,
+    /// known once registered from data socket too
+    data_addr: Option<SocketAddr>,
+    seen: Instant,
+}
+
+type RelayKey = ([u8; 32], [u8; 32]);
+
+/// `tap-demo rendezvous`, records public endpoints of nodes, tells pairs of nodes to
+/// punch towards each other, and relays between them when punching fails
+///
+/// nodes are not authenticated here, a node registering a key it doesn't own only
+/// gets handshakes which fail, frames are end to end encrypted through relays.
+pub(crate) struct RendezvousServer {
+    sock: UdpSocket,
+    nodes: HashMap<[u8; 32], Registration>,
+    /// ctl and data address of relay, by keys of the two nodes, lower first
+    relays: Arc<Mutex<HashMap<RelayKey, (SocketAddr, SocketAddr)>>>,
+}
+
+impl RendezvousServer {
+    pub(crate) fn new(sock: UdpSocket) -> RendezvousServer {
+        RendezvousServer {
+            sock,
+            nodes: HashMap::new(),
+            relays: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub(crate) fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.sock.local_addr()
+    }
+
+    pub(crate) fn run(mut self) {
+        let mut buff = vec![0; MAX_MSG_LEN];
+
+        loop {
+            let (size, src) = match self.sock.recv_from(&mut buff) {
+                Ok((size, src)) => (size, canonical(src)),
+                Err(_) => continue,
+            };
+
+            match decode::<Msg>(&buff[..size]) {
+                Ok(msg) => self.handle(src, msg.inner),
+                Err(e) => debug!("drop msg from {}, {}", src, e),
+            }
+        }
+    }
+
+    fn send(&self, msg: ControlMsg, addr: SocketAddr) {
+        if let Ok(data) = serialize(&Msg { inner: msg }) {
+            let _ = self.sock.send_to(&data, addr);
+        }
+    }
+
+    fn handle(&mut self, src: SocketAddr, msg: ControlMsg) {
+        self.nodes
+            .retain(|_, it| it.seen.elapsed() < REGISTRATION_TIMEOUT);
+
+        match msg {
+            // could not be listed to other nodes
+            ControlMsg::Register { name, .. } if name.len() > MAX_NAME_LEN => {}
+            ControlMsg::Register { name, public_key } => {
+                let node = self.nodes.entry(public_key).or_insert(Registration {
+                    name: name.clone(),
+                    ctl_addr: src,
+                    data_addr: None,
+                    seen: Instant::now(),
+                });
+
+                if node.ctl_addr != src {
+                    info!("node {} moved to {}", name, src);
+                    node.data_addr = None;
+                }
+
+                node.name = name;
+                node.ctl_addr = src;
+                node.seen = Instant::now();
+            }
+            ControlMsg::RegisterData(public_key) => {
+                let node = match self.nodes.get_mut(&public_key) {
+                    // from the host registered, with data socket
+                    Some(node) if node.ctl_addr.ip() == src.ip() => node,
+                    _ => return,
+                };
+
+                let new = node.data_addr.is_none();
+                node.data_addr = Some(src);
+
+                // others learn new node at once, not by next list
+                if let Some(info) = self.info(&public_key).filter(|_| new) {
+                    for (key, node) in &self.nodes {
+                        if *key != public_key {
+                            self.send(ControlMsg::Nodes(vec![info.clone()]), node.ctl_addr);
+                        }
+                    }
+                }
+            }
+            ControlMsg::ListNodes => {
+                let key = match self.key_of(src) {
+                    Some(key) => key,
+                    None => return,
+                };
+
+                let nodes: Vec<NodeInfo> = self
+                    .nodes
+                    .keys()
+                    .filter(|it| **it != key)
+                    .filter_map(|it| self.info(it))
+                    .collect();
+
+                for chunk in chunks(&nodes) {
+                    self.send(ControlMsg::Nodes(chunk), src);
+                }
+            }
+            ControlMsg::PunchRequest(target) => {
+                if let Some((a, b)) = self.pair(src, &target) {
+                    debug!("punch between {} and {}", a.name, b.name);
+
+                    self.send(ControlMsg::Punch(b.clone()), a.ctl_addr);
+                    self.send(ControlMsg::Punch(a), b.ctl_addr);
+                }
+            }
+            ControlMsg::RelayRequest(target) => {
+                let (a, b) = match self.pair(src, &target) {
+                    Some(pair) => pair,
+                    None => return,
+                };
+
+                let (ctl_addr, data_addr) = match self.open_relay(&a, &b) {
+                    Ok(addrs) => addrs,
+                    Err(e) => {
+                        info!("relay between {} and {} failed, {}", a.name, b.name, e);
+                        return;
+                    }
+                };
+
+                // each sees the other at relay
+                let (a_ctl_addr, b_ctl_addr) = (a.ctl_addr, b.ctl_addr);
+                let relayed = |node: NodeInfo| NodeInfo {
+                    ctl_addr,
+                    data_addr,
+                    ..node
+                };
+
+                self.send(ControlMsg::RelayReady(relayed(b)), a_ctl_addr);
+                self.send(ControlMsg::RelayReady(relayed(a)), b_ctl_addr);
+            }
+            msg => debug!("unexpected msg from {}, {:?}", src, msg),
+        }
+    }
+
+    fn key_of(&self, ctl_addr: SocketAddr) -> Option<[u8; 32]> {
+        self.nodes
+            .iter()
+            .find(|(_, it)| it.ctl_addr == ctl_addr)
+            .map(|(key, _)| *key)
+    }
+
+    fn info(&self, key: &[u8; 32]) -> Option<NodeInfo> {
+        let node = self.nodes.get(key)?;
+
+        Some(NodeInfo {
+            name: node.name.clone(),
+            public_key: *key,
+            ctl_addr: node.ctl_addr,
+            data_addr: node.data_addr?,
+        })
+    }
+
+    /// node registered at `src` and node of `target`
+    fn pair(&self, src: SocketAddr, target: &[u8; 32]) -> Option<(NodeInfo, NodeInfo)> {
+        let key = self.key_of(src)?;
+
+        if key == *target {
+            return None;
+        }
+
+        Some((self.info(&key)?, self.info(target)?))
+    }
+
+    /// relay between `a` and `b`, one socket for control and one for data, each passing
+    /// datagrams from one node to the other
+    fn open_relay(&self, a: &NodeInfo, b: &NodeInfo) -> io::Result<(SocketAddr, SocketAddr)> {
+        let key = if a.public_key < b.public_key {
+            (a.public_key, b.public_key)
+        } else {
+            (b.public_key, a.public_key)
+        };
+
+        let mut relays = self.relays.lock().unwrap();
+
+        if let Some(addrs) = relays.get(&key) {
+            return Ok(*addrs);
+        }
+
+        if relays.len() >= MAX_RELAYS {
+            return Err(io::Error::other("too many relays"));
+        }
+
+        let ctl_sock = bind_socket(0)?.into_udp_socket();
+        let data_sock = bind_socket(0)?.into_udp_socket();
+        let addrs = (ctl_sock.local_addr()?, data_sock.local_addr()?);
+        relays.insert(key, addrs);
+
+        let active = Arc::new(Mutex::new(Instant::now()));
+
+        {
+            let active = Arc::clone(&active);
+            let (a, b) = (a.data_addr, b.data_addr);
+            std::thread::spawn(move || forward(data_sock, a, b, &active));
+        }
+
+        let relays = Arc::clone(&self.relays);
+        let (a, b) = (a.ctl_addr, b.ctl_addr);
+        std::thread::spawn(move || {
+            forward(ctl_sock, a, b, &active);
+            relays.lock().unwrap().remove(&key);
+        });
+
+        Ok(addrs)
+    }
+}
+
+/// pass datagrams between `a` and `b` until neither socket of relay had traffic for
+/// `RELAY_IDLE_TIMEOUT`
+fn forward(sock: UdpSocket, a: SocketAddr, b: SocketAddr, active: &Mutex<Instant>) {
+    let mut buff = vec![0; MAX_DATAGRAM_LEN];
+
+    if sock.set_read_timeout(Some(Duration::from_secs(1))).is_err() {
+        return;
+    }
+
+    while active.lock().unwrap().elapsed() < RELAY_IDLE_TIMEOUT {
+        let (size, src) = match sock.recv_from(&mut buff) {
+            Ok((size, src)) => (size, canonical(src)),
+            Err(_) => continue,
+        };
+
+        let dst = match src {
+            src if src == a => b,
+            src if src == b => a,
+            _ => continue,
+        };
+
+        *active.lock().unwrap() = Instant::now();
+        let _ = sock.send_to(&buff[..size], dst);
+    }
+}
+
+/// run rendezvous on `port` of all interfaces
+pub(crate) fn serve(port: u16) -> AppResult<()> {
+    let server = RendezvousServer::new(bind_socket(port)?.into_udp_socket());
+    info!("rendezvous listen on {}", server.local_addr()?);
+
+    server.run();
+
+    Ok(())
+}
+
+struct ClientInner {
+    /// rendezvous of config, resolved every round
+    server: Option<SocketAddr>,
+    /// data endpoints of nodes told by rendezvous, by their ctl endpoint
+    endpoints: HashMap<SocketAddr, SocketAddr>,
+    /// when connecting a node was last tried, by public key
+    tried: HashMap<[u8; 32], Instant>,
+    last_list: Option<Instant>,
+}
+
+/// node side of rendezvous
+///
+/// nodes register from control and data socket every `heartbeat_interval`, which also
+/// keeps their nat mappings open, and list other nodes every `discovery_interval`. of
+/// each pair of nodes not connected, the one with lower key asks rendezvous to have
+/// both punch towards each other, and for a relay if punching fails.
+pub(crate) struct Rendezvous {
+    inner: Mutex<ClientInner>,
+}
+
+impl Rendezvous {
+    pub(crate) fn new() -> Rendezvous {
+        Rendezvous {
+            inner: Mutex::new(ClientInner {
+                server: None,
+                endpoints: HashMap::new(),
+                tried: HashMap::new(),
+                last_list: None,
+            }),
+        }
+    }
+
+    /// data endpoint of node at `ctl_addr`, if told by rendezvous
+    pub(crate) fn data_addr(&self, ctl_addr: &SocketAddr) -> Option<SocketAddr> {
+        self.inner.lock().unwrap().endpoints.get(ctl_addr).copied()
+    }
+
+    fn learned(&self, node: &NodeInfo) {
+        self.inner
+            .lock()
+            .unwrap()
+            .endpoints
+            .insert(node.ctl_addr, node.data_addr);
+    }
+
+    /// whether node should be tried now, it's marked as tried if so
+    fn should_try(&self, public_key: &[u8; 32]) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tried.retain(|_, it| it.elapsed() < CONNECT_RETRY);
+
+        inner.tried.insert(*public_key, Instant::now()).is_none()
+    }
+}
+
+/// rendezvous resolved by last round, `None` if not configured
+pub(crate) fn rendezvous_addr(state: &AppState) -> Option<SocketAddr> {
+    state.rendezvous.inner.lock().unwrap().server
+}
+
+fn resolve(addr: &str) -> Option<SocketAddr> {
+    prefer_ipv4(addr.to_socket_addrs().ok()?)
+}
+
+/// register with rendezvous, keep nat mappings of data socket open, and list nodes
+/// every `discovery_interval`, run by heartbeats thread
+pub(crate) fn rendezvous_round(state: &AppState) {
+    let config = state.config();
+
+    let server = match config.rendezvous {
+        Some(ref addr) => match resolve(addr) {
+            Some(server) => server,
+            None => {
+                debug!("error resolve rendezvous {}", addr);
+                return;
+            }
+        },
+        None => return,
+    };
+
+    let list = {
+        let mut inner = state.rendezvous.inner.lock().unwrap();
+        inner.server = Some(server);
+
+        let interval = Duration::from_secs(config.discovery_interval);
+        let due = inner.last_list.is_none_or(|it| it.elapsed() >= interval);
+        if due {
+            inner.last_list = Some(Instant::now());
+        }
+
+        due
+    };
+
+    let public_key = state.handshake.identity.public_key;
+    let msg = ControlMsg::Register {
+        name: config.name.clone(),
+        public_key,
+    };
+    let _ = state.ctl.send(msg, server);
+
+    if let Ok(data) = serialize(&Msg {
+        inner: ControlMsg::RegisterData(public_key),
+    }) {
+        let _ = state.data_sock.send_to(&data, server);
+    }
+
+    // control ones are kept open by heartbeats
+    let data_addrs: Vec<SocketAddr> = {
+        let peers = state.peers.read().unwrap();
+        peers
+            .iter()
+            .filter(|it| it.state != PeerState::Dead)
+            .map(|it| it.data_addr)
+            .collect()
+    };
+
+    for data_addr in data_addrs {
+        let _ = state.data_sock.send_to(&[], data_addr);
+    }
+
+    if list {
+        let _ = state.ctl.send(ControlMsg::ListNodes, server);
+    }
+}
+
+/// connect nodes listed by rendezvous which are not peers yet
+pub(crate) fn on_nodes(state: &Arc<AppState>, nodes: Vec<NodeInfo>) {
+    let own_key = state.handshake.identity.public_key;
+
+    for node in nodes {
+        // the other one connects us, so both don't punch twice
+        if node.public_key <= own_key {
+            continue;
+        }
+
+        let known = {
+            let peers = state.peers.read().unwrap();
+            peers.iter().any(|it| it.public_key == node.public_key)
+        };
+
+        if known
+            || state.membership.is_ignored(&node.public_key)
+            || !state.rendezvous.should_try(&node.public_key)
+        {
+            continue;
+        }
+
+        let state = Arc::clone(state);
+        std::thread::spawn(move || connect(&state, node));
+    }
+}
+
+/// punch towards `node` as asked by rendezvous
+pub(crate) fn on_punch(state: &AppState, node: NodeInfo) {
+    debug!("punch towards {} at {}", node.name, node.ctl_addr);
+
+    open(state, &node);
+}
+
+/// relay to `node` opened by rendezvous at `server`, return node at addresses of relay
+pub(crate) fn on_relay_ready(state: &AppState, server: SocketAddr, mut node: NodeInfo) -> NodeInfo {
+    // relay is bound on all addresses of rendezvous
+    for addr in [&mut node.ctl_addr, &mut node.data_addr] {
+        if addr.ip().is_unspecified() {
+            addr.set_ip(server.ip());
+        }
+    }
+
+    info!("relay to {} at {}", node.name, node.ctl_addr);
+    open(state, &node);
+
+    node
+}
+
+/// send keepalive from data socket to `node`
+fn keepalive(state: &AppState, node: &NodeInfo) {
+    let header = PacketHeader::new(PacketType::Keepalive, state.config().network_id);
+    let _ = state.data_sock.send_to(&header.encode(), node.data_addr);
+}
+
+/// send to `node` from control and data socket, so our nat lets its packets in
+fn open(state: &AppState, node: &NodeInfo) {
+    state.rendezvous.learned(node);
+
+    let _ = state.ctl.send(ControlMsg::Ping, node.ctl_addr);
+    keepalive(state, node);
+}
+
+/// ping `node` until it answers, keep sending from data socket meanwhile
+fn reachable(state: &AppState, node: &NodeInfo) -> bool {
+    (0..PUNCH_ATTEMPTS).any(|_| {
+        keepalive(state, node);
+
+        ping(state, node.ctl_addr, PUNCH_INTERVAL).is_ok()
+    })
+}
+
+/// have `node` punch towards us, and see whether it's reached directly
+fn punch(state: &AppState, server: SocketAddr, node: &NodeInfo) -> bool {
+    state.rendezvous.learned(node);
+
+    let _ = state
+        .ctl
+        .send(ControlMsg::PunchRequest(node.public_key), server);
+
+    reachable(state, node)
+}
+
+fn is_relay_ready(msg: &ControlMsg) -> bool {
+    matches!(msg, ControlMsg::RelayReady(_))
+}
+
+/// ask rendezvous at `server` to relay between `node` and us
+fn relay(state: &AppState, server: SocketAddr, node: &NodeInfo) -> AppResult<NodeInfo> {
+    let msg = ControlMsg::RelayRequest(node.public_key);
+
+    match state
+        .ctl
+        .request(msg, &[server], is_relay_ready, REPLY_TIMEOUT)?
+    {
+        ControlMsg::RelayReady(relayed) if relayed.public_key == node.public_key => {
+            Ok(on_relay_ready(state, server, relayed))
+        }
+        _ => Err(TapDemoError::UnexpectedMsg),
+    }
+}
+
+/// connect `node` directly if punching succeeds, or through relay
+fn connect(state: &AppState, node: NodeInfo) {
+    let server = match rendezvous_addr(state) {
+        Some(server) => server,
+        None => return,
+    };
+
+    let node = if punch(state, server, &node) {
+        info!("punched through to {} at {}", node.name, node.ctl_addr);
+        node
+    } else {
+        match relay(state, server, &node) {
+            Ok(relayed) if reachable(state, &relayed) => relayed,
+            Ok(_) => {
+                debug!("relay to {} not reachable", node.name);
+                return;
+            }
+            Err(e) => {
+                debug!("relay to {} failed, {}", node.name, e);
+                return;
+            }
+        }
+    };
+
+    handshake_node(state, &node);
+}
+
+/// handshake with `node` and add it as peer
+fn handshake_node(state: &AppState, node: &NodeInfo) {
+    let mut peer = Peer {
+        name: node.name.clone(),
+        ctl_addr: node.ctl_addr,
+        data_addr: node.data_addr,
+        hw_addr: [0; 6],
+        public_key: [0; 32],
+        ip_addr: None,
+        state: PeerState::Alive,
+        via: None,
+    };
+
+    match init_peer_hw_addr(state, &mut peer) {
+        // rendezvous is trusted for addresses only
+        Ok(_) if peer.public_key != node.public_key => {
+            info!("{} at {} is not the node listed", peer.name, peer.ctl_addr);
+            state.cipher.remove(&peer.data_addr);
+        }
+        Ok(_) => {
+            state.add_peer(peer);
+        }
+        Err(e) => debug!("connect {} failed, {}", node.name, e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::config::Config;
+    use crate::test_util::control_node;
+
+    fn start_server() -> SocketAddr {
+        let server = RendezvousServer::new(UdpSocket::bind("127.0.0.1:0").unwrap());
+        let addr = server.local_addr().unwrap();
+        std::thread::spawn(move || server.run());
+
+        addr
+    }
+
+    fn peer_of(state: &AppState, other: &AppState) -> Option<Peer> {
+        let peers = state.peers.read().unwrap();
+        let key = other.handshake.identity.public_key;
+
+        peers.iter().find(|it| it.public_key == key).cloned()
+    }
+
+    fn wait_for<F: Fn() -> bool>(f: F) -> bool {
+        let start = Instant::now();
+
+        while start.elapsed() < Duration::from_secs(10) {
+            if f() {
+                return true;
+            }
+
+            std::thread::sleep(Duration::from_millis(50));
+        }
+
+        false
+    }
+
+    fn use_rendezvous(state: &AppState, server: SocketAddr) {
+        state.set_config(Config {
+            rendezvous: Some(server.to_string()),
+            ..(*state.config()).clone()
+        });
+
+        rendezvous_round(state);
+    }
+
+    /// a and b, the one with lower key first
+    fn nodes() -> (Arc<AppState>, Arc<AppState>) {
+        let (a, _) = control_node("a", [2, 0, 0, 0, 0, 1]);
+        let (b, _) = control_node("b", [2, 0, 0, 0, 0, 2]);
+
+        if a.handshake.identity.public_key < b.handshake.identity.public_key {
+            (a, b)
+        } else {
+            (b, a)
+        }
+    }
+
+    #[test]
+    fn test_rendezvous() {
+        let server = start_server();
+        let (a, b) = nodes();
+
+        // b registers first, a finds it in list and connects
+        use_rendezvous(&b, server);
+        std::thread::sleep(Duration::from_millis(100));
+        use_rendezvous(&a, server);
+
+        assert!(wait_for(
+            || peer_of(&a, &b).is_some() && peer_of(&b, &a).is_some()
+        ));
+
+        let peer = peer_of(&a, &b).unwrap();
+        assert_eq!(peer.ctl_addr.port(), b.config().ctl_port);
+        assert_eq!(peer.data_addr.port(), b.config().data_port);
+    }
+
+    #[test]
+    fn test_relay() {
+        let server = start_server();
+        let (a, b) = nodes();
+
+        // registered without listing, so a doesn't punch through on its own
+        for state in [&b, &a].iter() {
+            state.rendezvous.inner.lock().unwrap().last_list = Some(Instant::now());
+            use_rendezvous(state, server);
+        }
+        std::thread::sleep(Duration::from_millis(100));
+
+        let node = NodeInfo {
+            name: "b".to_owned(),
+            public_key: b.handshake.identity.public_key,
+            ctl_addr: SocketAddr::new(server.ip(), b.config().ctl_port),
+            data_addr: SocketAddr::new(server.ip(), b.config().data_port),
+        };
+        let relayed = relay(&a, server, &node).unwrap();
+        assert_eq!(relayed.ctl_addr.ip(), server.ip());
+        assert_ne!(relayed.ctl_addr.port(), b.config().ctl_port);
+
+        assert!(reachable(&a, &relayed));
+        handshake_node(&a, &relayed);
+        assert!(wait_for(|| peer_of(&b, &a).is_some()));
+
+        // both see each other at relay
+        assert_eq!(peer_of(&a, &b).unwrap().ctl_addr, relayed.ctl_addr);
+        assert_eq!(peer_of(&b, &a).unwrap().ctl_addr, relayed.ctl_addr);
+        assert_eq!(peer_of(&b, &a).unwrap().data_addr, relayed.data_addr);
+        assert!(ping(&b, relayed.ctl_addr, Duration::from_secs(1)).is_ok());
+    }
+}
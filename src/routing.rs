@@ -0,0 +1,410 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::app::AppState;
+use crate::control::send_sealed;
+use crate::msg::*;
+use crate::peer::{Peer, PeerState};
+
+/// longest path, also ttl of frames and control messages sent through relays
+pub(crate) const MAX_HOPS: u8 = 16;
+
+/// metric of a route withdrawn, eg, by the peer it goes through
+const UNREACHABLE: u32 = u32::MAX;
+
+/// learned routes not advertised again for this many heartbeat rounds are dropped
+const ROUTE_TIMEOUT_ROUNDS: u32 = 3;
+
+/// destination reachable by the peer sent it, metric is latency in microseconds,
+/// its ctl addr is the one in our peers, not told by the peer
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct RouteAdvert {
+    pub(crate) hw_addr: [u8; 6],
+    pub(crate) metric: u32,
+    pub(crate) hops: u8,
+}
+
+/// route as shown by `routes`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct RouteRecord {
+    pub(crate) name: Option<String>,
+    pub(crate) hw_addr: [u8; 6],
+    /// none if reached directly
+    pub(crate) next_hop: Option<String>,
+    pub(crate) metric: u32,
+    pub(crate) hops: u8,
+}
+
+/// peer answering ping directly
+struct Link {
+    ctl_addr: SocketAddr,
+    /// smoothed round trip time
+    rtt: Duration,
+}
+
+/// route advertised by a peer, metric excludes the link to it
+struct Learned {
+    metric: u32,
+    hops: u8,
+    updated: Instant,
+}
+
+/// best path to a destination, by hw addr of next hop
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Best {
+    next_hop: [u8; 6],
+    metric: u32,
+    hops: u8,
+}
+
+struct Inner {
+    /// by hw addr of peer
+    links: HashMap<[u8; 6], Link>,
+    /// by hw addr of destination and of peer advertised it
+    learned: HashMap<([u8; 6], [u8; 6]), Learned>,
+}
+
+/// distance vector routing over peers, for nodes not reached directly
+///
+/// links to peers are measured by ping of failure detector, and every heartbeat round
+/// each peer is sent the best routes, except those through itself. a node reached
+/// directly is sent to directly, otherwise the route with lowest sum of latencies wins.
+pub(crate) struct Routing {
+    inner: Mutex<Inner>,
+}
+
+fn micros(rtt: Duration) -> u32 {
+    rtt.as_micros().min(UNREACHABLE as u128 - 1) as u32
+}
+
+impl Inner {
+    fn best(&self, dst: &[u8; 6]) -> Option<Best> {
+        let direct = self.links.get(dst).map(|it| Best {
+            next_hop: *dst,
+            metric: micros(it.rtt),
+            hops: 1,
+        });
+
+        let learned = self
+            .learned
+            .iter()
+            .filter(|((it, _), _)| it == dst)
+            .filter_map(|((_, next_hop), route)| {
+                let link = self.links.get(next_hop)?;
+
+                Some(Best {
+                    next_hop: *next_hop,
+                    metric: route.metric.saturating_add(micros(link.rtt)),
+                    hops: route.hops + 1,
+                })
+            })
+            .filter(|it| it.metric < UNREACHABLE && it.hops <= MAX_HOPS)
+            .min_by_key(|it| it.metric);
+
+        direct.or(learned)
+    }
+
+    fn destinations(&self) -> Vec<[u8; 6]> {
+        let mut dsts: Vec<[u8; 6]> = self
+            .links
+            .keys()
+            .chain(self.learned.keys().map(|(dst, _)| dst))
+            .copied()
+            .collect();
+        dsts.sort_unstable();
+        dsts.dedup();
+
+        dsts
+    }
+}
+
+impl Routing {
+    pub(crate) fn new() -> Routing {
+        Routing {
+            inner: Mutex::new(Inner {
+                links: HashMap::new(),
+                learned: HashMap::new(),
+            }),
+        }
+    }
+
+    /// peer answered ping directly in `rtt`
+    pub(crate) fn link_up(&self, hw_addr: [u8; 6], ctl_addr: SocketAddr, rtt: Duration) {
+        let mut inner = self.inner.lock().unwrap();
+
+        match inner.links.get_mut(&hw_addr) {
+            // smoothed as tcp does
+            Some(link) if link.ctl_addr == ctl_addr => link.rtt = (link.rtt * 7 + rtt) / 8,
+            _ => {
+                inner.links.insert(hw_addr, Link { ctl_addr, rtt });
+            }
+        }
+    }
+
+    /// peer not answering ping directly, or gone
+    pub(crate) fn link_down(&self, hw_addr: &[u8; 6]) {
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.links.remove(hw_addr);
+        inner.learned.retain(|(_, next_hop), _| next_hop != hw_addr);
+    }
+
+    /// ctl addr of peer to send to for node `dst`, `dst` itself if reached directly
+    pub(crate) fn next_hop(&self, dst: &[u8; 6]) -> Option<SocketAddr> {
+        let inner = self.inner.lock().unwrap();
+        let best = inner.best(dst)?;
+
+        inner.links.get(&best.next_hop).map(|it| it.ctl_addr)
+    }
+
+    /// as `next_hop`, for peer at `ctl_addr`, known by its hw addr in `peers`
+    pub(crate) fn next_hop_to(&self, peers: &[Peer], ctl_addr: SocketAddr) -> Option<SocketAddr> {
+        let dst = peers.iter().find(|it| it.ctl_addr == ctl_addr)?.hw_addr;
+
+        self.next_hop(&dst)
+    }
+
+    /// routes to send to peer `to`, routes through it are withdrawn, as is `to` itself
+    fn adverts(&self, to: &[u8; 6]) -> Vec<RouteAdvert> {
+        let inner = self.inner.lock().unwrap();
+
+        inner
+            .destinations()
+            .into_iter()
+            .filter(|it| it != to)
+            .map(|dst| {
+                let best = inner.best(&dst);
+
+                RouteAdvert {
+                    hw_addr: dst,
+                    metric: match best {
+                        Some(best) if best.next_hop != *to => best.metric,
+                        _ => UNREACHABLE,
+                    },
+                    hops: best.map_or(MAX_HOPS, |it| it.hops),
+                }
+            })
+            .collect()
+    }
+
+    /// routes advertised by peer `from`, this node is skipped
+    fn learn(&self, local: &[u8; 6], from: [u8; 6], adverts: Vec<RouteAdvert>) {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+
+        for advert in adverts {
+            let key = (advert.hw_addr, from);
+
+            if advert.hw_addr == *local || advert.hw_addr == from {
+                continue;
+            }
+
+            if advert.metric == UNREACHABLE || advert.hops >= MAX_HOPS {
+                inner.learned.remove(&key);
+                continue;
+            }
+
+            inner.learned.insert(
+                key,
+                Learned {
+                    metric: advert.metric,
+                    hops: advert.hops,
+                    updated: now,
+                },
+            );
+        }
+    }
+
+    fn expire(&self, timeout: Duration) {
+        self.inner
+            .lock()
+            .unwrap()
+            .learned
+            .retain(|_, it| it.updated.elapsed() < timeout);
+    }
+
+    pub(crate) fn records(&self, peers: &[Peer]) -> Vec<RouteRecord> {
+        let inner = self.inner.lock().unwrap();
+        let name_of = |hw_addr: &[u8; 6]| {
+            peers
+                .iter()
+                .find(|it| it.hw_addr == *hw_addr)
+                .map(|it| it.name.clone())
+        };
+
+        inner
+            .destinations()
+            .into_iter()
+            .filter_map(|dst| {
+                let best = inner.best(&dst)?;
+
+                Some(RouteRecord {
+                    name: name_of(&dst),
+                    hw_addr: dst,
+                    next_hop: if best.next_hop == dst {
+                        None
+                    } else {
+                        name_of(&best.next_hop)
+                    },
+                    metric: best.metric,
+                    hops: best.hops,
+                })
+            })
+            .collect()
+    }
+}
+
+/// peer to send to for node `dst`, reached directly, falls back to `dst` itself if a
+/// peer reached directly and not measured yet
+pub(crate) fn next_hop<'a>(state: &AppState, peers: &'a [Peer], dst: &[u8; 6]) -> Option<&'a Peer> {
+    let direct = |it: &&Peer| it.via.is_none() && it.state != PeerState::Dead;
+
+    match state.routing.next_hop(dst) {
+        Some(ctl_addr) => peers
+            .iter()
+            .filter(direct)
+            .find(|it| it.ctl_addr == ctl_addr),
+        None => peers.iter().filter(direct).find(|it| it.hw_addr == *dst),
+    }
+}
+
+/// as `next_hop`, for node at `ctl_addr`
+pub(crate) fn next_hop_to(
+    state: &AppState,
+    peers: &[Peer],
+    ctl_addr: SocketAddr,
+) -> Option<SocketAddr> {
+    let direct = |it: &&Peer| it.via.is_none() && it.state != PeerState::Dead;
+    let next_hop = state
+        .routing
+        .next_hop_to(peers, ctl_addr)
+        .unwrap_or(ctl_addr);
+
+    peers
+        .iter()
+        .filter(direct)
+        .find(|it| it.ctl_addr == next_hop)
+        .map(|it| it.ctl_addr)
+}
+
+/// handle routes advertised by `peer`, false if not reached directly
+pub(crate) fn on_routes(state: &AppState, peer: &Peer, adverts: Vec<RouteAdvert>) -> bool {
+    if peer.via.is_some() {
+        return false;
+    }
+
+    state.routing.learn(&state.hw_addr, peer.hw_addr, adverts);
+
+    true
+}
+
+/// send routes to peers reached directly, run by heartbeats thread
+pub(crate) fn routing_round(state: &AppState) {
+    let interval = Duration::from_secs(state.config().heartbeat_interval);
+    state.routing.expire(interval * ROUTE_TIMEOUT_ROUNDS);
+
+    let peers: Vec<Peer> = {
+        let peers = state.peers.read().unwrap();
+        peers
+            .iter()
+            .filter(|it| it.via.is_none() && it.state != PeerState::Dead)
+            .cloned()
+            .collect()
+    };
+
+    for peer in peers {
+        let adverts = state.routing.adverts(&peer.hw_addr);
+
+        for chunk in chunks(&adverts) {
+            if let Err(e) = send_sealed(state, &ControlMsg::Routes(chunk), &peer) {
+                debug!("send routes to {} failed, {}", peer.name, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn hw(n: u8) -> [u8; 6] {
+        [2, 0, 0, 0, 0, n]
+    }
+
+    fn advert(n: u8, metric: u32, hops: u8) -> RouteAdvert {
+        RouteAdvert {
+            hw_addr: hw(n),
+            metric,
+            hops,
+        }
+    }
+
+    #[test]
+    fn test_routing() {
+        let local = hw(1);
+        let routing = Routing::new();
+
+        // b and c are peers, d is behind both, e only behind c
+        routing.link_up(hw(2), addr(2), Duration::from_millis(10));
+        routing.link_up(hw(3), addr(3), Duration::from_millis(2));
+        routing.learn(&local, hw(2), vec![advert(4, 5_000, 1), advert(1, 0, 1)]);
+        routing.learn(
+            &local,
+            hw(3),
+            vec![advert(4, 20_000, 2), advert(5, 1_000, 1)],
+        );
+
+        // lowest sum of latencies, not fewest hops
+        assert_eq!(routing.next_hop(&hw(4)), Some(addr(2)));
+        assert_eq!(routing.next_hop(&hw(5)), Some(addr(3)));
+        assert_eq!(routing.next_hop(&hw(3)), Some(addr(3)));
+
+        // node at ctl addr known by our peers only
+        let mut e: Peer = format!("e={}", addr(9)).parse().unwrap();
+        e.hw_addr = hw(5);
+        assert_eq!(routing.next_hop_to(&[e], addr(9)), Some(addr(3)));
+        assert_eq!(routing.next_hop_to(&[], addr(9)), None);
+        assert_eq!(routing.next_hop(&local), None);
+
+        // reached directly once answering, even if slower
+        routing.link_up(hw(5), addr(5), Duration::from_millis(50));
+        assert_eq!(routing.next_hop(&hw(5)), Some(addr(5)));
+        routing.link_down(&hw(5));
+        routing.link_up(hw(3), addr(3), Duration::from_millis(2));
+        routing.learn(
+            &local,
+            hw(3),
+            vec![advert(4, 20_000, 2), advert(5, 1_000, 1)],
+        );
+
+        // split horizon, routes through the peer are withdrawn to it
+        let adverts = routing.adverts(&hw(3));
+        let to_e = adverts.iter().find(|it| it.hw_addr == hw(5)).unwrap();
+        assert_eq!(to_e.metric, UNREACHABLE);
+        assert!(adverts.iter().all(|it| it.hw_addr != hw(3)));
+
+        let to_d = adverts.iter().find(|it| it.hw_addr == hw(4)).unwrap();
+        assert_eq!((to_d.metric, to_d.hops), (15_000, 2));
+
+        // withdrawn, then lost link to b
+        routing.learn(&local, hw(2), vec![advert(4, UNREACHABLE, 1)]);
+        assert_eq!(routing.next_hop(&hw(4)), Some(addr(3)));
+
+        routing.link_down(&hw(3));
+        assert_eq!(routing.next_hop(&hw(4)), None);
+        assert_eq!(routing.next_hop(&hw(5)), None);
+
+        // too long to be a route
+        routing.learn(&local, hw(2), vec![advert(6, 1, MAX_HOPS)]);
+        assert_eq!(routing.next_hop(&hw(6)), None);
+    }
+}
@@ -0,0 +1,88 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::{Deserialize, Serialize};
+
+/// counters of rejected packets, and of frames relayed
+#[derive(Debug, Default)]
+pub(crate) struct Stats {
+    pub(crate) auth_failed: AtomicU64,
+    pub(crate) replayed: AtomicU64,
+    pub(crate) malformed: AtomicU64,
+    pub(crate) unknown_session: AtomicU64,
+    /// frames larger than tap mtu
+    pub(crate) oversized: AtomicU64,
+    /// control messages failed to decode
+    pub(crate) ctl_malformed: AtomicU64,
+    /// control messages decoded but not expected here
+    pub(crate) ctl_unexpected: AtomicU64,
+    /// frames passed on between other peers
+    pub(crate) relayed: AtomicU64,
+    /// frames dropped by relay after too many hops
+    pub(crate) ttl_expired: AtomicU64,
+    /// copies of broadcast frames come back through a loop
+    pub(crate) looped: AtomicU64,
+    /// broadcast frames from tap over `broadcast_limit`
+    pub(crate) storm_dropped: AtomicU64,
+    /// frames of another network
+    pub(crate) wrong_network: AtomicU64,
+    /// geneve packets with critical options not understood
+    pub(crate) unknown_option: AtomicU64,
+    /// geneve packets whose sender option names another peer than the one sent them
+    pub(crate) wrong_sender: AtomicU64,
+    /// datagrams without our packet header, eg, of another protocol
+    pub(crate) bad_magic: AtomicU64,
+    /// packets of another version
+    pub(crate) wrong_version: AtomicU64,
+    /// packets of type unknown to this version
+    pub(crate) unknown_type: AtomicU64,
+}
+
+/// snapshot of `Stats`, as shown by `stats`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct StatsRecord {
+    pub(crate) auth_failed: u64,
+    pub(crate) replayed: u64,
+    pub(crate) malformed: u64,
+    pub(crate) unknown_session: u64,
+    pub(crate) oversized: u64,
+    pub(crate) ctl_malformed: u64,
+    pub(crate) ctl_unexpected: u64,
+    pub(crate) relayed: u64,
+    pub(crate) ttl_expired: u64,
+    pub(crate) looped: u64,
+    pub(crate) storm_dropped: u64,
+    pub(crate) wrong_network: u64,
+    pub(crate) unknown_option: u64,
+    pub(crate) wrong_sender: u64,
+    pub(crate) bad_magic: u64,
+    pub(crate) wrong_version: u64,
+    pub(crate) unknown_type: u64,
+}
+
+impl Stats {
+    pub(crate) fn incr(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> StatsRecord {
+        StatsRecord {
+            auth_failed: self.auth_failed.load(Ordering::Relaxed),
+            replayed: self.replayed.load(Ordering::Relaxed),
+            malformed: self.malformed.load(Ordering::Relaxed),
+            unknown_session: self.unknown_session.load(Ordering::Relaxed),
+            oversized: self.oversized.load(Ordering::Relaxed),
+            ctl_malformed: self.ctl_malformed.load(Ordering::Relaxed),
+            ctl_unexpected: self.ctl_unexpected.load(Ordering::Relaxed),
+            relayed: self.relayed.load(Ordering::Relaxed),
+            ttl_expired: self.ttl_expired.load(Ordering::Relaxed),
+            looped: self.looped.load(Ordering::Relaxed),
+            storm_dropped: self.storm_dropped.load(Ordering::Relaxed),
+            wrong_network: self.wrong_network.load(Ordering::Relaxed),
+            unknown_option: self.unknown_option.load(Ordering::Relaxed),
+            wrong_sender: self.wrong_sender.load(Ordering::Relaxed),
+            bad_magic: self.bad_magic.load(Ordering::Relaxed),
+            wrong_version: self.wrong_version.load(Ordering::Relaxed),
+            unknown_type: self.unknown_type.load(Ordering::Relaxed),
+        }
+    }
+}
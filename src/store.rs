@@ -0,0 +1,214 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{AppResult, TapDemoError};
+use crate::peer::{Peer, PeerState};
+
+pub(crate) const DEFAULT_STATE_FILE: &str = "/var/lib/tap-demo/peers";
+
+/// peers not seen for this long are dropped from state file
+pub(crate) const DEFAULT_PEER_EXPIRE: u64 = 7 * 24 * 3600;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct PeerRecord {
+    name: String,
+    /// control address
+    addr: SocketAddr,
+    /// unix time the peer was last in peer list
+    last_seen: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct StateFile {
+    peers: Vec<PeerRecord>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|it| it.as_secs())
+        .unwrap_or(0)
+}
+
+/// peers known across restarts, kept in a toml file
+///
+/// dead peers are kept until expired, so they are tried again after restart,
+/// peers removed by admin or config are dropped at once.
+pub(crate) struct PeerStore {
+    path: PathBuf,
+    expire: Duration,
+    records: Mutex<Vec<PeerRecord>>,
+}
+
+impl PeerStore {
+    /// store at `path`, empty path keeps records in memory only
+    pub(crate) fn new(path: &Path, expire: Duration) -> PeerStore {
+        PeerStore {
+            path: path.to_owned(),
+            expire,
+            records: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// read state file if exists, expired entries are skipped
+    pub(crate) fn load(path: &Path, expire: Duration) -> AppResult<PeerStore> {
+        let store = PeerStore::new(path, expire);
+
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(store),
+            Err(e) => return Err(e.into()),
+        };
+
+        let file: StateFile = toml::from_str(&content).map_err(|e| {
+            TapDemoError::ConfigError(format!("state file {}, {}", path.display(), e))
+        })?;
+
+        let mut records = file.peers;
+        records.retain(|it| store.is_fresh(it));
+
+        info!("load {} peers from {}", records.len(), path.display());
+        *store.records.lock().unwrap() = records;
+
+        Ok(store)
+    }
+
+    fn is_fresh(&self, record: &PeerRecord) -> bool {
+        now().saturating_sub(record.last_seen) < self.expire.as_secs()
+    }
+
+    /// peers in state file, to be connected at start
+    pub(crate) fn peers(&self) -> Vec<Peer> {
+        let records = self.records.lock().unwrap();
+
+        records
+            .iter()
+            .filter_map(|it| format!("{}={}", it.name, it.addr).parse().ok())
+            .collect()
+    }
+
+    /// whether peer at `ctl_addr` is still wanted, ie, in state file and not expired
+    pub(crate) fn contains(&self, ctl_addr: &SocketAddr) -> bool {
+        let records = self.records.lock().unwrap();
+
+        records
+            .iter()
+            .any(|it| it.addr == *ctl_addr && self.is_fresh(it))
+    }
+
+    /// record current `peers` as seen now, except dead ones, drop `removed` and expired
+    /// ones, then write state file
+    pub(crate) fn update(&self, peers: &[Peer], removed: &[SocketAddr]) {
+        let mut records = self.records.lock().unwrap();
+        let now = now();
+        let peers: Vec<&Peer> = peers
+            .iter()
+            .filter(|it| it.state != PeerState::Dead)
+            .collect();
+
+        records.retain(|it| {
+            !removed.contains(&it.addr)
+                && !peers.iter().any(|peer| peer.ctl_addr == it.addr)
+                && self.is_fresh(it)
+        });
+
+        records.extend(peers.iter().map(|it| PeerRecord {
+            name: it.name.clone(),
+            addr: it.ctl_addr,
+            last_seen: now,
+        }));
+
+        if self.path.as_os_str().is_empty() {
+            return;
+        }
+
+        if let Err(e) = self.write(&records) {
+            warn!("error write {}, {}", self.path.display(), e);
+        }
+    }
+
+    /// write to temp file then rename, so the file is never seen half written. temp file
+    /// is named after the whole file name and hidden, so it is neither the temp file nor
+    /// the state file of another network
+    fn write(&self, records: &[PeerRecord]) -> io::Result<()> {
+        let file = StateFile {
+            peers: records.to_vec(),
+        };
+        let content =
+            toml::to_string(&file).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let tmp = match self.path.file_name() {
+            Some(name) => self
+                .path
+                .with_file_name(format!(".{}.tmp", name.to_string_lossy())),
+            None => return Err(io::Error::from(io::ErrorKind::InvalidInput)),
+        };
+        let mut tmp_file = File::create(&tmp)?;
+        tmp_file.write_all(content.as_bytes())?;
+        tmp_file.sync_all()?;
+
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_store() {
+        let path = std::env::temp_dir().join(format!("tap-demo-peers-{}", std::process::id()));
+        let expire = Duration::from_secs(60);
+
+        let peer: Peer = "n2=127.0.0.1:9909".parse().unwrap();
+        let other: Peer = "n3=127.0.0.2:9909".parse().unwrap();
+
+        let store = PeerStore::new(&path, expire);
+        store.update(&[peer.clone(), other.clone()], &[]);
+        store.update(&[], &[other.ctl_addr]);
+
+        let store = PeerStore::load(&path, expire).unwrap();
+        let peers = store.peers();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].ctl_addr, peer.ctl_addr);
+
+        // expired
+        let store = PeerStore::load(&path, Duration::from_secs(0)).unwrap();
+        assert!(store.peers().is_empty());
+        assert!(!store.contains(&peer.ctl_addr));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_store_networks() {
+        let dir = std::env::temp_dir().join(format!("tap-demo-networks-{}", std::process::id()));
+        let expire = Duration::from_secs(60);
+
+        // state files of network tmp, red and of default network, saved in turn
+        let paths = ["peers.tmp", "peers.red", "peers"].map(|it| dir.join(it));
+        for (i, path) in paths.iter().enumerate() {
+            let peer: Peer = format!("n{}=127.0.0.{}:9909", i, i + 1).parse().unwrap();
+            PeerStore::new(path, expire).update(&[peer], &[]);
+        }
+
+        for (i, path) in paths.iter().enumerate() {
+            let peers = PeerStore::load(path, expire).unwrap().peers();
+            assert_eq!(peers.len(), 1);
+            assert_eq!(peers[0].name, format!("n{}", i));
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
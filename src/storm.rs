@@ -0,0 +1,136 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+/// a flood frame seen again within this time is a copy gone round a loop, loops take
+/// milliseconds while retries of arp and the like are a second apart
+const LOOP_WINDOW: Duration = Duration::from_millis(500);
+
+/// frames remembered at most, older ones are dropped first past this
+const MAX_SEEN: usize = 4096;
+
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+struct Inner {
+    /// hash of flood frame, to when it was seen
+    seen: HashMap<u64, Instant>,
+    /// flood frames from tap in current second
+    window_start: Instant,
+    count: u64,
+}
+
+/// loop prevention and storm detection for broadcast and multicast frames
+///
+/// frames received from peers are never flooded again, only written to tap. when taps
+/// of several nodes are bridged to the same lan, a frame still comes back through
+/// another node, so each flood frame is remembered for a moment and copies of it are
+/// dropped, whether read from tap or received from peers. flood frames read from tap
+/// over `broadcast_limit` a second are dropped as a storm, and reported once a second.
+pub(crate) struct StormGuard {
+    inner: Mutex<Inner>,
+}
+
+fn hash_frame(frame: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    frame.hash(&mut hasher);
+
+    hasher.finish()
+}
+
+impl StormGuard {
+    pub(crate) fn new() -> StormGuard {
+        StormGuard {
+            inner: Mutex::new(Inner {
+                seen: HashMap::new(),
+                window_start: Instant::now(),
+                count: 0,
+            }),
+        }
+    }
+
+    /// whether flood frame is not a copy of one seen within `LOOP_WINDOW`, and remember it
+    pub(crate) fn first_seen(&self, frame: &[u8]) -> bool {
+        let hash = hash_frame(frame);
+        let now = Instant::now();
+        let mut inner = self.inner.lock().unwrap();
+
+        if let Some(seen) = inner.seen.get(&hash) {
+            if now - *seen < LOOP_WINDOW {
+                return false;
+            }
+        }
+
+        if inner.seen.len() >= MAX_SEEN {
+            inner.seen.retain(|_, it| now - *it < LOOP_WINDOW);
+        }
+
+        // still full, in a storm, forget the lot rather than scan on every frame
+        if inner.seen.len() >= MAX_SEEN {
+            inner.seen.clear();
+        }
+
+        inner.seen.insert(hash, now);
+
+        true
+    }
+
+    /// whether another flood frame from tap is within `limit` a second, 0 for no limit
+    pub(crate) fn admit(&self, limit: u64) -> bool {
+        let now = Instant::now();
+        let mut inner = self.inner.lock().unwrap();
+
+        if now - inner.window_start >= RATE_WINDOW {
+            inner.window_start = now;
+            inner.count = 0;
+        }
+
+        inner.count += 1;
+
+        if limit == 0 || inner.count <= limit {
+            return true;
+        }
+
+        if inner.count == limit + 1 {
+            warn!(
+                "broadcast storm, over {} frames a second from tap, dropping",
+                limit
+            );
+        }
+
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_storm_guard() {
+        let guard = StormGuard::new();
+        let arp = [0xffu8; 60];
+        let mut other = [0xffu8; 60];
+        other[59] = 0;
+
+        // a copy coming back is dropped, another frame is not
+        assert!(guard.first_seen(&arp));
+        assert!(!guard.first_seen(&arp));
+        assert!(guard.first_seen(&other));
+
+        // the same frame sent again later, eg, arp retry
+        std::thread::sleep(LOOP_WINDOW);
+        assert!(guard.first_seen(&arp));
+
+        // over limit in a second, then admitted again next second
+        assert!((0..10).all(|_| guard.admit(10)));
+        assert!(!guard.admit(10));
+        assert!((0..100).all(|_| guard.admit(0)));
+
+        std::thread::sleep(RATE_WINDOW);
+        assert!(guard.admit(10));
+    }
+}
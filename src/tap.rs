@@ -5,9 +5,8 @@ use std::os::unix::io::{FromRawFd, IntoRawFd};
 use std::time::Duration;
 
 use libc::ioctl;
-use std::net::Ipv4Addr;
 
-static TUN_DEV: &'static str = "/dev/net/tun";
+static TUN_DEV: &str = "/dev/net/tun";
 static IFF_TAP: c_short = 0x0002;
 static IFF_NO_PI: c_short = 0x1000;
 static IFF_UP: c_short = 0x0001;
@@ -16,7 +15,7 @@ static TUNSETIFF: u64 = 1074025674;
 static SIOCGIFHWADDR: u64 = 0x8927;
 static SIOCSIFFLAGS: u64 = 0x8914;
 static SIOCGIFFLAGS: u64 = 0x8913;
-static SIOCSIFADDR: u64 = 0x8916;
+static SIOCSIFMTU: u64 = 0x8922;
 
 #[derive(Debug)]
 #[repr(C)]
@@ -37,7 +36,7 @@ impl IfReq {
         let mut if_name = [0; 16];
 
         for (idx, data) in name.as_bytes().iter().enumerate() {
-            if_name[idx] = data.clone().try_into().unwrap();
+            if_name[idx] = (*data).try_into().unwrap();
         }
 
         IfReq {
@@ -46,11 +45,12 @@ impl IfReq {
         }
     }
 
+    #[allow(dead_code)]
     pub fn if_name(&mut self, name: &str) {
         self.if_name = [0; 16];
 
         for (idx, data) in name.as_bytes().iter().enumerate() {
-            self.if_name[idx] = data.clone().try_into().unwrap();
+            self.if_name[idx] = (*data).try_into().unwrap();
         }
     }
 
@@ -59,6 +59,10 @@ impl IfReq {
         self.ifr_ifru[1] |= (flags >> 8) as u8;
     }
 
+    pub fn if_mtu(&mut self, mtu: i32) {
+        self.ifr_ifru[..4].copy_from_slice(&mtu.to_ne_bytes());
+    }
+
     pub fn if_hwaddr(&self) -> [u8; 6] {
         let mut hwaddr = [0; 6];
 
@@ -66,21 +70,6 @@ impl IfReq {
 
         hwaddr
     }
-
-    pub fn if_add_ipv4(&mut self, ipv4: Ipv4Addr) {
-        use libc::AF_INET;
-
-        // sin_family
-        self.ifr_ifru[0] = AF_INET as u8;
-        self.ifr_ifru[1] = 0; // since we known AF_INET is 1
-
-        // sin_port
-        self.ifr_ifru[2] = 0;
-        self.ifr_ifru[3] = 0;
-
-        // sin_addr
-        self.ifr_ifru[4..8].copy_from_slice(&ipv4.octets());
-    }
 }
 
 pub fn create_tap(name: &str) -> Result<TapInfo, crate::error::TapDemoError> {
@@ -121,10 +110,6 @@ pub fn create_tap(name: &str) -> Result<TapInfo, crate::error::TapDemoError> {
             return Err(crate::error::TapDemoError::TapSetupError);
         }
 
-        // todo: ip prefix, should use rtnetlink
-        //        ifreq.if_add_ipv4(Ipv4Addr::new(10, 0, 0, 1));
-        //        rc = ioctl(skfd, SIOCSIFADDR, &ifreq);
-
         libc::close(skfd);
 
         Ok(TapInfo {
@@ -134,6 +119,30 @@ pub fn create_tap(name: &str) -> Result<TapInfo, crate::error::TapDemoError> {
     }
 }
 
+/// set mtu of interface `name`, like `ip link set mtu`
+pub fn set_mtu(name: &str, mtu: usize) -> Result<(), crate::error::TapDemoError> {
+    use libc::{socket, AF_INET, SOCK_DGRAM};
+
+    let mut ifreq = IfReq::with_name(name);
+    ifreq.if_mtu(mtu as i32);
+
+    unsafe {
+        let skfd = socket(AF_INET, SOCK_DGRAM, 0);
+        if skfd < 0 {
+            return Err(crate::error::TapDemoError::TapSetupError);
+        }
+
+        let rc = ioctl(skfd, SIOCSIFMTU, &ifreq);
+        libc::close(skfd);
+
+        if rc != 0 {
+            return Err(crate::error::TapDemoError::TapSetupError);
+        }
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod test {
     #[test]
@@ -0,0 +1,101 @@
+//! nodes without tap device or privileges, shared by tests of data plane and heartbeats
+
+use std::fs::File;
+use std::net::UdpSocket;
+use std::os::unix::io::{FromRawFd, IntoRawFd};
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::app::AppState;
+use crate::config::Config;
+use crate::control::{control_thread, ControlSocket};
+use crate::crypto::SessionKeys;
+use crate::handshake::HandshakeConfig;
+use crate::identity::Identity;
+use crate::peer::{Peer, PeerState};
+use crate::store::PeerStore;
+use crate::tap::TapInfo;
+
+/// node with a datagram socket pair as tap, which keeps frame boundaries like a
+/// real tap, the other end is returned to inject and capture frames
+pub(crate) fn fake_node(name: &str, hw_addr: [u8; 6]) -> (Arc<AppState>, UnixDatagram) {
+    let (tap, wire) = UnixDatagram::pair().unwrap();
+    wire.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+    let data_sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let mut config = Config {
+        name: name.to_owned(),
+        data_port: data_sock.local_addr().unwrap().port(),
+        ctl_port: 0,
+        ..Config::default()
+    };
+    let ctl = ControlSocket::bind(&config).unwrap();
+    config.ctl_port = ctl.local_addr().unwrap().port();
+    let tap_info = TapInfo {
+        tap_dev: unsafe { File::from_raw_fd(tap.into_raw_fd()) },
+        hw_addr,
+    };
+    let handshake = HandshakeConfig {
+        identity: Identity::generate().unwrap(),
+        psk: None,
+        authorized_keys: None,
+    };
+
+    let state = AppState::new(
+        config,
+        tap_info,
+        data_sock,
+        ctl,
+        handshake,
+        None,
+        PeerStore::new(Path::new(""), Duration::from_secs(0)),
+    );
+
+    (Arc::new(state), wire)
+}
+
+/// fake node answering control messages on `ctl_port` of its config
+pub(crate) fn control_node(name: &str, hw_addr: [u8; 6]) -> (Arc<AppState>, UnixDatagram) {
+    let (state, wire) = fake_node(name, hw_addr);
+    control_thread(Arc::clone(&state));
+
+    (state, wire)
+}
+
+/// make `b` a peer of `a`, with a session as if handshake done
+pub(crate) fn connect(a: &AppState, b: &AppState, a_index: u32, b_index: u32) {
+    let data_addr = b.data_sock.local_addr().unwrap();
+
+    a.cipher.install(
+        data_addr,
+        SessionKeys {
+            local_index: a_index,
+            remote_index: b_index,
+            tx: [a_index as u8; 32],
+            rx: [b_index as u8; 32],
+        },
+    );
+
+    a.peers.write().unwrap().push(Peer {
+        name: b.config().name.clone(),
+        ctl_addr: data_addr,
+        data_addr,
+        hw_addr: b.hw_addr,
+        public_key: b.handshake.identity.public_key,
+        ip_addr: None,
+        state: PeerState::Alive,
+        via: None,
+    });
+}
+
+pub(crate) fn frame(dst_mac: [u8; 6], src_mac: [u8; 6], len: usize) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(len);
+    frame.extend_from_slice(&dst_mac);
+    frame.extend_from_slice(&src_mac);
+    frame.extend_from_slice(&0x0800u16.to_be_bytes());
+    frame.extend((frame.len()..len).map(|it| it as u8));
+
+    frame
+}
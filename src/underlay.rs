@@ -0,0 +1,84 @@
+//! udp sockets of the underlay, ipv4 and ipv6 alike
+
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+
+/// socket bound to `port` on all addresses, dual stack so peers are reached over ipv4
+/// and ipv6 from one socket, ipv4 only on hosts without ipv6
+pub(crate) fn bind_socket(port: u16) -> io::Result<Socket> {
+    let v6 = Socket::new(Domain::ipv6(), Type::dgram(), Some(Protocol::udp()));
+
+    let (sock, addr) = match v6 {
+        Ok(sock) => {
+            sock.set_only_v6(false)?;
+            (sock, SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port))
+        }
+        Err(_) => (
+            Socket::new(Domain::ipv4(), Type::dgram(), Some(Protocol::udp()))?,
+            SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port),
+        ),
+    };
+
+    sock.bind(&SockAddr::from(addr))?;
+
+    Ok(sock)
+}
+
+/// ipv4 address as peers are known by, dual stack sockets see them mapped into ipv6
+pub(crate) fn canonical(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(ip.into(), v6.port()),
+            None => addr,
+        },
+        addr => addr,
+    }
+}
+
+/// one of addresses a name resolves to, ipv4 preferred as it's reached by ipv4 only
+/// hosts too
+pub(crate) fn prefer_ipv4<I: IntoIterator<Item = SocketAddr>>(addrs: I) -> Option<SocketAddr> {
+    addrs.into_iter().min_by_key(|it| it.is_ipv6())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::UdpSocket;
+
+    use crate::peer::Peer;
+
+    #[test]
+    fn test_dual_stack() {
+        let sock = bind_socket(0).unwrap().into_udp_socket();
+        let port = sock.local_addr().unwrap().port();
+
+        for client in &["127.0.0.1:0", "[::1]:0"] {
+            let client = UdpSocket::bind(client).unwrap();
+            let to: SocketAddr = match client.local_addr().unwrap() {
+                SocketAddr::V4(_) => SocketAddr::from(([127, 0, 0, 1], port)),
+                SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::LOCALHOST, port)),
+            };
+            client.send_to(b"ping", to).unwrap();
+
+            let mut buff = [0; 16];
+            let (_, src) = sock.recv_from(&mut buff).unwrap();
+            assert_eq!(canonical(src), client.local_addr().unwrap());
+
+            // and back to the address as given
+            sock.send_to(b"pong", canonical(src)).unwrap();
+            assert!(client.recv(&mut buff).is_ok());
+        }
+
+        let v4: SocketAddr = "10.0.0.1:9909".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:9909".parse().unwrap();
+        assert_eq!(prefer_ipv4(vec![v6, v4]), Some(v4));
+        assert_eq!(prefer_ipv4(vec![v6]), Some(v6));
+
+        let peer: Peer = "b=[::1]:9909".parse().unwrap();
+        assert_eq!(peer.ctl_addr, "[::1]:9909".parse().unwrap());
+        assert_eq!(peer.data_addr, "[::1]:9908".parse().unwrap());
+    }
+}
@@ -0,0 +1,69 @@
+//! vxlan encapsulation of rfc 7348, frames in plain udp, so a node interworks with
+//! linux `vxlan` interfaces and other vteps
+
+use crate::crypto::OpenError;
+
+/// iana assigned port
+pub(crate) const VXLAN_PORT: u16 = 4789;
+
+/// flags, reserved, vni and reserved
+pub(crate) const VXLAN_HEADER_LEN: usize = 8;
+
+/// vni is 24 bits
+pub(crate) const MAX_VNI: u32 = (1 << 24) - 1;
+
+/// `I` flag, vni is valid
+const FLAG_VNI: u8 = 0x08;
+
+/// put `frame` behind vxlan header of `vni` into `out`, return packet length
+pub(crate) fn encap(vni: u32, frame: &[u8], out: &mut Vec<u8>) -> usize {
+    out.clear();
+    out.extend_from_slice(&[FLAG_VNI, 0, 0, 0]);
+    out.extend_from_slice(&(vni << 8).to_be_bytes());
+    out.extend_from_slice(frame);
+
+    out.len()
+}
+
+/// frame of vxlan packet, packets without `I` flag are malformed and those of another
+/// vni are of another network, reserved bits are ignored as rfc says
+pub(crate) fn decap(data: &[u8], vni: u32) -> Result<&[u8], OpenError> {
+    if data.len() < VXLAN_HEADER_LEN || data[0] & FLAG_VNI == 0 {
+        return Err(OpenError::Malformed);
+    }
+
+    if u32::from_be_bytes([0, data[4], data[5], data[6]]) != vni {
+        return Err(OpenError::WrongNetwork);
+    }
+
+    Ok(&data[VXLAN_HEADER_LEN..])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_vxlan() {
+        let frame = [0xffu8; 60];
+        let mut packet = Vec::new();
+
+        assert_eq!(encap(42, &frame, &mut packet), VXLAN_HEADER_LEN + 60);
+        assert_eq!(&packet[..8], &[0x08, 0, 0, 0, 0, 0, 42, 0]);
+        assert_eq!(decap(&packet, 42).unwrap(), &frame[..]);
+
+        // reserved bits set by another vtep
+        packet[1] = 0xff;
+        packet[7] = 0xff;
+        assert!(decap(&packet, 42).is_ok());
+
+        assert!(matches!(decap(&packet, 43), Err(OpenError::WrongNetwork)));
+        assert!(matches!(decap(&packet[..7], 42), Err(OpenError::Malformed)));
+
+        packet[0] = 0;
+        assert!(matches!(decap(&packet, 42), Err(OpenError::Malformed)));
+
+        encap(MAX_VNI, &frame, &mut packet);
+        assert_eq!(&packet[4..8], &[0xff, 0xff, 0xff, 0]);
+    }
+}